@@ -0,0 +1,7 @@
+-- Versions of the session signing and encryption keys, see src/keyring
+CREATE TABLE IF NOT EXISTS session_keys (
+    version INTEGER PRIMARY KEY,
+    signing TEXT NOT NULL,
+    encryption TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
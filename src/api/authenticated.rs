@@ -1,20 +1,112 @@
-use crate::axum_introspector::introspection::IntrospectedUser;
-use crate::AppState;
+use crate::api::router::AppState;
+use crate::api::security_headers::Proxied;
+use crate::api::upstream::Upstream;
+use crate::api::websocket::{self, is_websocket_upgrade};
+use crate::axum_introspector::introspection::{IntrospectedUser, IntrospectionState};
 use axum::extract::{Request, State};
 use axum::response::IntoResponse;
-use tower::Layer;
-use tower_service::Service;
+use serde_json::to_string;
+use tower_cookies::Cookies;
+use tower_sessions::session::Id;
+use tower_sessions::SessionStore;
 use worker::*;
 
 pub struct AuthenticatedApi;
 
+/// Whether a proxied connection may stay open: the session it was opened with, if any, is still
+/// in the store, and its token is still active. A store that cannot tell, like the cookie store
+/// outside of a request, leaves it to the token.
+async fn still_signed_in<S: SessionStore>(
+    session_store: S,
+    session_id: Option<Id>,
+    introspection_state: IntrospectionState,
+    token: String,
+) -> bool {
+    if let Some(session_id) = session_id {
+        if let Ok(None) = session_store.load(&session_id).await {
+            return false;
+        }
+    }
+    IntrospectedUser::revalidate(introspection_state, token).await
+}
+
 impl AuthenticatedApi {
     #[worker::send]
-    pub async fn proxy(session: tower_sessions::Session, State(state): State<AppState>, user: IntrospectedUser, mut request: Request) -> impl IntoResponse {
-        let worker_request = worker::Request::try_from(request).unwrap();
-        let http_request = http::Request::try_from(worker_request).unwrap();
+    pub async fn proxy<S, U>(session: tower_sessions::Session, State(state): State<AppState<S, U>>, cookies: Cookies, user: IntrospectedUser, mut request: Request) -> impl IntoResponse
+    where
+        S: SessionStore + Clone,
+        U: Upstream + Clone + 'static,
+    {
+        // browsers attach the session cookie to cross-site requests as well
+        let authenticated_by_session = matches!(session.get::<String>("token").await, Ok(Some(_)));
+        if authenticated_by_session
+            && !state
+                .csrf_policy
+                .protect(
+                    &request,
+                    &session,
+                    &cookies,
+                    &state.config.app_url,
+                    !state.config.dev_mode,
+                )
+                .await
+        {
+            return (http::StatusCode::FORBIDDEN, "CSRF check failed.").into_response();
+        }
+        // upgrades are GETs, which the CSRF check lets through, but a cross-site page opening one
+        // would get a connection carrying the user's session
+        let upgrade = is_websocket_upgrade(&request);
+        if authenticated_by_session
+            && upgrade
+            && !state
+                .csrf_policy
+                .allows_upgrade(&request, &state.config.app_url)
+        {
+            return (http::StatusCode::FORBIDDEN, "CSRF check failed.").into_response();
+        }
+
+        if !upgrade {
+            let mut response = state
+                .response_transforms
+                .apply(state.upstream.forward(request).await);
+            response.extensions_mut().insert(Proxied);
+            return response;
+        }
+
+        // the relay re-checks the session and token the connection was opened with, so a
+        // connection without a token to re-check is not opened
+        let (mut parts, body) = request.into_parts();
+        let Some(token) = IntrospectedUser::token_from_parts(&mut parts, &state)
+            .await
+            .ok()
+            .flatten()
+        else {
+            return http::StatusCode::UNAUTHORIZED.into_response();
+        };
+        let session_id = session.id().filter(|_| authenticated_by_session);
+        let response = state.upstream.forward(Request::from_parts(parts, body)).await;
 
-        let proxy_target = state.env.service("PROXY_TARGET").unwrap();
-        <http::Response<worker::Body> as Into<HttpResponse>>::into(proxy_target.fetch_request(http_request).await.expect("failed to proxy request"))
+        let introspection_state = state.introspection_state.clone();
+        let session_store = state.session_store.clone();
+        let mut response = websocket::relay(response, state.websocket_policy, move || {
+            still_signed_in(
+                session_store.clone(),
+                session_id,
+                introspection_state.clone(),
+                token.clone(),
+            )
+        })
+        .await;
+        response.extensions_mut().insert(Proxied);
+        response
+    }
+
+    #[worker::send]
+    pub async fn whoami(
+        session: tower_sessions::Session,
+        introspected_user: IntrospectedUser,
+    ) -> impl IntoResponse {
+        console_log!("calling whoami");
+        to_string(&introspected_user).unwrap()
     }
-}
\ No newline at end of file
+}
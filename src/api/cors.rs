@@ -0,0 +1,211 @@
+use custom_error::custom_error;
+use http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+
+custom_error! {
+    pub CorsError
+        InvalidConfig{source: serde_json::Error} = "the CORS policy is not valid: {source}",
+        InvalidOrigin{origin: String} = "{origin} is not a valid origin",
+        InvalidMethod{method: String} = "{method} is not a valid method",
+        InvalidHeader{name: String} = "{name} is not a valid header",
+        WildcardWithCredentials = "credentials can not be allowed together with a \"*\" wildcard",
+}
+
+/// Which cross-origin requests browsers may make, as configured in `CORS_POLICY`, e.g.
+/// `{"allowed_origins": ["https://app.example.com"], "allowed_methods": ["GET", "POST"],
+/// "allowed_headers": ["content-type"], "allow_credentials": true, "max_age": 600}`.
+///
+/// The default allows no origin at all. Preflights are answered at the edge, before
+/// authentication, as browsers never send credentials with them.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct CorsPolicy {
+    /// Exact origins, or `"*"` for any.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Methods, or `"*"` for any.
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// Request headers, or `"*"` for any.
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// Lets the allowed origins make requests with the user's session cookie.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache a preflight.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+fn is_wildcard(values: &[String]) -> bool {
+    values.iter().any(|value| value == "*")
+}
+
+impl CorsPolicy {
+    pub fn from_json(json: &str) -> Result<Self, CorsError> {
+        let policy: CorsPolicy = serde_json::from_str(json)?;
+        policy.layer()?;
+        Ok(policy)
+    }
+
+    /// The origins that may make requests with the user's session, none without `allow_credentials`.
+    pub fn credentialed_origins(&self) -> Vec<String> {
+        if !self.allow_credentials {
+            return Vec::new();
+        }
+        self.allowed_origins
+            .iter()
+            .map(|origin| origin.trim_end_matches('/').to_string())
+            .collect()
+    }
+
+    /// Builds the layer enforcing the policy.
+    pub fn layer(&self) -> Result<CorsLayer, CorsError> {
+        let wildcard = is_wildcard(&self.allowed_origins)
+            || is_wildcard(&self.allowed_methods)
+            || is_wildcard(&self.allowed_headers);
+        if self.allow_credentials && wildcard {
+            return Err(CorsError::WildcardWithCredentials);
+        }
+
+        let origins: AllowOrigin = if is_wildcard(&self.allowed_origins) {
+            Any.into()
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::try_from(origin.trim_end_matches('/')).map_err(|_| {
+                        CorsError::InvalidOrigin {
+                            origin: origin.clone(),
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowOrigin::list(origins)
+        };
+
+        let methods: AllowMethods = if is_wildcard(&self.allowed_methods) {
+            Any.into()
+        } else {
+            self.allowed_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|_| {
+                        CorsError::InvalidMethod {
+                            method: method.clone(),
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into()
+        };
+
+        let headers: AllowHeaders = if is_wildcard(&self.allowed_headers) {
+            Any.into()
+        } else {
+            self.allowed_headers
+                .iter()
+                .map(|name| {
+                    HeaderName::try_from(name.as_str())
+                        .map_err(|_| CorsError::InvalidHeader { name: name.clone() })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into()
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(Duration::from_secs(max_age));
+        }
+        Ok(layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use super::*;
+
+    #[test]
+    fn defaults_to_no_origins() {
+        let policy = CorsPolicy::from_json("{}").unwrap();
+
+        assert_eq!(policy, CorsPolicy::default());
+        assert!(policy.allowed_origins.is_empty());
+        assert!(!policy.allow_credentials);
+    }
+
+    #[test]
+    fn parses_an_allow_list() {
+        let policy = CorsPolicy::from_json(
+            r#"{
+                "allowed_origins": ["https://app.example.com/"],
+                "allowed_methods": ["get", "POST"],
+                "allowed_headers": ["content-type", "x-requested-with"],
+                "allow_credentials": true,
+                "max_age": 600
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(policy.allowed_origins, vec!["https://app.example.com/"]);
+        assert_eq!(policy.max_age, Some(600));
+    }
+
+    #[test]
+    fn only_credentialed_origins_are_handed_on() {
+        let policy = CorsPolicy::from_json(
+            r#"{"allowed_origins": ["https://app.example.com/"], "allow_credentials": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy.credentialed_origins(),
+            vec!["https://app.example.com"]
+        );
+
+        let policy =
+            CorsPolicy::from_json(r#"{"allowed_origins": ["https://app.example.com"]}"#).unwrap();
+        assert!(policy.credentialed_origins().is_empty());
+    }
+
+    #[test]
+    fn wildcards_are_allowed_without_credentials() {
+        assert!(CorsPolicy::from_json(
+            r#"{"allowed_origins": ["*"], "allowed_methods": ["*"], "allowed_headers": ["*"]}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(matches!(
+            CorsPolicy::from_json(r#"{"allowed_origins": ["*"], "allow_credentials": true}"#),
+            Err(CorsError::WildcardWithCredentials)
+        ));
+        assert!(matches!(
+            CorsPolicy::from_json(
+                r#"{"allowed_origins": ["https://app.example.com"], "allowed_headers": ["*"], "allow_credentials": true}"#
+            ),
+            Err(CorsError::WildcardWithCredentials)
+        ));
+        assert!(matches!(
+            CorsPolicy::from_json(r#"{"allowed_methods": ["NOT A METHOD"]}"#),
+            Err(CorsError::InvalidMethod { .. })
+        ));
+        assert!(matches!(
+            CorsPolicy::from_json(r#"{"allowed_headers": ["not a header"]}"#),
+            Err(CorsError::InvalidHeader { .. })
+        ));
+        assert!(matches!(
+            CorsPolicy::from_json(r#"{"allowed_origins": "https://app.example.com"}"#),
+            Err(CorsError::InvalidConfig { .. })
+        ));
+    }
+}
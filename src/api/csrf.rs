@@ -0,0 +1,434 @@
+use axum::extract::Request;
+use base64::Engine;
+use custom_error::custom_error;
+use http::Method;
+use ring::rand::SecureRandom;
+use serde::Deserialize;
+use tower_cookies::cookie::SameSite;
+use tower_cookies::{Cookie, Cookies};
+use tower_sessions::Session;
+
+custom_error! {
+    pub CsrfError
+        InvalidConfig{source: serde_json::Error} = "the CSRF policy is not valid: {source}",
+        InvalidPath{path: String} = "the exempt path {path} does not start with a slash",
+}
+
+/// Key of the CSRF token on the session.
+const SESSION_CSRF_TOKEN: &str = "csrf_token";
+
+/// How requests that change state are protected when they are authenticated by the session
+/// cookie, as configured in `CSRF_POLICY`, e.g. `{"trusted_origins": ["https://admin.example.com"],
+/// "exempt_paths": ["/webhooks"]}`.
+///
+/// Such a request passes if it comes from the app's own origin, going by `Sec-Fetch-Site` or
+/// `Origin`, or if it carries the CSRF token of its session in a header. The token is handed to
+/// the page in a cookie scripts can read (double submit). Requests with a bearer token instead of
+/// a session are not affected, browsers do not attach those on their own.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CsrfPolicy {
+    pub enabled: bool,
+    /// Accept requests from the app's origin and `trusted_origins` without a token.
+    pub verify_origin: bool,
+    /// Accept requests carrying the session's token in `header_name`.
+    pub verify_token: bool,
+    pub trusted_origins: Vec<String>,
+    /// Path prefixes that are never checked, e.g. for webhooks.
+    pub exempt_paths: Vec<String>,
+    pub header_name: String,
+    pub cookie_name: String,
+}
+
+impl Default for CsrfPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            verify_origin: true,
+            verify_token: true,
+            trusted_origins: Vec::new(),
+            exempt_paths: Vec::new(),
+            header_name: "x-csrf-token".to_string(),
+            cookie_name: "csrf_token".to_string(),
+        }
+    }
+}
+
+/// What the `Sec-Fetch-Site` and `Origin` headers say about where a request comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Provenance {
+    Trusted,
+    CrossSite,
+    Unknown,
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn origin_of(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()
+        .map(|url| url.origin().ascii_serialization())
+}
+
+impl CsrfPolicy {
+    pub fn from_json(json: &str) -> Result<Self, CsrfError> {
+        let policy: CsrfPolicy = serde_json::from_str(json)?;
+        if let Some(path) = policy
+            .exempt_paths
+            .iter()
+            .find(|path| !path.starts_with('/'))
+        {
+            return Err(CsrfError::InvalidPath { path: path.clone() });
+        }
+        Ok(policy)
+    }
+
+    /// Trusts `origins` as well, e.g. the ones CORS lets make requests with the user's session.
+    pub fn with_trusted_origins(mut self, origins: impl IntoIterator<Item = String>) -> Self {
+        self.trusted_origins.extend(origins);
+        self
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    fn is_trusted_origin(&self, origin: &str, app_url: &str) -> bool {
+        origin_of(app_url).is_some_and(|app_origin| app_origin == origin)
+            || self
+                .trusted_origins
+                .iter()
+                .any(|trusted| trusted.trim_end_matches('/') == origin)
+    }
+
+    fn provenance(&self, request: &Request, app_url: &str) -> Provenance {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        if let Some(origin) = header("origin").filter(|origin| *origin != "null") {
+            return if self.is_trusted_origin(origin, app_url) {
+                Provenance::Trusted
+            } else {
+                Provenance::CrossSite
+            };
+        }
+
+        match header("sec-fetch-site") {
+            // typed into the address bar or opened from a bookmark
+            Some("same-origin") | Some("none") => Provenance::Trusted,
+            Some(_) => Provenance::CrossSite,
+            None => Provenance::Unknown,
+        }
+    }
+
+    fn has_valid_token(&self, request: &Request, session_token: Option<&str>) -> bool {
+        let Some(session_token) = session_token else {
+            return false;
+        };
+        request
+            .headers()
+            .get(self.header_name.as_str())
+            .is_some_and(|token| {
+                ring::constant_time::verify_slices_are_equal(
+                    token.as_bytes(),
+                    session_token.as_bytes(),
+                )
+                .is_ok()
+            })
+    }
+
+    /// Whether a request authenticated by its session cookie may be forwarded.
+    pub(crate) fn allows(
+        &self,
+        request: &Request,
+        app_url: &str,
+        session_token: Option<&str>,
+    ) -> bool {
+        if !self.enabled || is_safe(request.method()) || self.is_exempt(request.uri().path()) {
+            return true;
+        }
+
+        (self.verify_origin && self.provenance(request, app_url) == Provenance::Trusted)
+            || (self.verify_token && self.has_valid_token(request, session_token))
+    }
+
+    /// Whether a WebSocket upgrade authenticated by its session cookie may be forwarded. Upgrades
+    /// are `GET`s any page can open and cannot carry the token header, so only their origin counts.
+    pub(crate) fn allows_upgrade(&self, request: &Request, app_url: &str) -> bool {
+        !self.enabled
+            || self.is_exempt(request.uri().path())
+            || self.provenance(request, app_url) == Provenance::Trusted
+    }
+
+    /// Checks a request authenticated by `session`, and makes sure the session has a token the
+    /// page can read from the CSRF cookie.
+    pub(crate) async fn protect(
+        &self,
+        request: &Request,
+        session: &Session,
+        cookies: &Cookies,
+        app_url: &str,
+        secure: bool,
+    ) -> bool {
+        if !self.enabled {
+            return true;
+        }
+
+        let session_token = session
+            .get::<String>(SESSION_CSRF_TOKEN)
+            .await
+            .ok()
+            .flatten();
+        if !self.allows(request, app_url, session_token.as_deref()) {
+            return false;
+        }
+
+        let token = match session_token {
+            Some(token) => token,
+            None => {
+                let token = generate_token();
+                if session.insert(SESSION_CSRF_TOKEN, &token).await.is_err() {
+                    return true;
+                }
+                token
+            }
+        };
+        if !matches!(cookies.get(&self.cookie_name), Some(cookie) if cookie.value() == token) {
+            cookies.add(
+                Cookie::build((self.cookie_name.clone(), token))
+                    .path("/")
+                    .same_site(SameSite::Strict)
+                    .secure(secure)
+                    .http_only(false)
+                    .build(),
+            );
+        }
+        true
+    }
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system random number generator is available");
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::body::Body;
+
+    use super::*;
+
+    const APP_URL: &str = "https://app.example.com";
+    const TOKEN: &str = "session-token";
+
+    fn request(method: Method, path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn allows(policy: &CsrfPolicy, request: &Request) -> bool {
+        policy.allows(request, APP_URL, Some(TOKEN))
+    }
+
+    #[test]
+    fn safe_methods_are_not_checked() {
+        let policy = CsrfPolicy::default();
+
+        assert!(allows(
+            &policy,
+            &request(Method::GET, "/", &[("sec-fetch-site", "cross-site")])
+        ));
+        assert!(allows(&policy, &request(Method::HEAD, "/", &[])));
+    }
+
+    #[test]
+    fn same_origin_requests_pass() {
+        let policy = CsrfPolicy::default();
+
+        assert!(allows(
+            &policy,
+            &request(Method::POST, "/items", &[("origin", APP_URL)])
+        ));
+        assert!(allows(
+            &policy,
+            &request(Method::POST, "/items", &[("sec-fetch-site", "same-origin")])
+        ));
+    }
+
+    #[test]
+    fn cross_site_requests_are_rejected() {
+        let policy = CsrfPolicy::default();
+
+        assert!(!allows(
+            &policy,
+            &request(
+                Method::POST,
+                "/items",
+                &[("origin", "https://evil.example")]
+            )
+        ));
+        assert!(!allows(
+            &policy,
+            &request(Method::DELETE, "/items", &[("sec-fetch-site", "same-site")])
+        ));
+        // nothing to go by
+        assert!(!allows(&policy, &request(Method::POST, "/items", &[])));
+    }
+
+    #[test]
+    fn the_origin_takes_precedence_over_sec_fetch_site() {
+        let policy = CsrfPolicy::default();
+
+        assert!(!allows(
+            &policy,
+            &request(
+                Method::POST,
+                "/items",
+                &[
+                    ("origin", "https://evil.example"),
+                    ("sec-fetch-site", "same-origin")
+                ]
+            )
+        ));
+    }
+
+    #[test]
+    fn trusted_origins_pass() {
+        let policy = CsrfPolicy {
+            trusted_origins: vec!["https://admin.example.com/".to_string()],
+            ..CsrfPolicy::default()
+        };
+
+        assert!(allows(
+            &policy,
+            &request(
+                Method::POST,
+                "/items",
+                &[("origin", "https://admin.example.com")]
+            )
+        ));
+    }
+
+    #[test]
+    fn added_origins_are_trusted() {
+        let policy = CsrfPolicy::default()
+            .with_trusted_origins(vec!["https://admin.example.com".to_string()]);
+
+        assert!(allows(
+            &policy,
+            &request(
+                Method::POST,
+                "/items",
+                &[("origin", "https://admin.example.com")]
+            )
+        ));
+    }
+
+    #[test]
+    fn the_session_token_lets_requests_pass() {
+        let policy = CsrfPolicy::default();
+
+        assert!(allows(
+            &policy,
+            &request(
+                Method::POST,
+                "/items",
+                &[
+                    ("origin", "https://other.example.com"),
+                    ("x-csrf-token", TOKEN)
+                ]
+            )
+        ));
+        assert!(!allows(
+            &policy,
+            &request(Method::POST, "/items", &[("x-csrf-token", "guessed")])
+        ));
+        assert!(!policy.allows(
+            &request(Method::POST, "/items", &[("x-csrf-token", TOKEN)]),
+            APP_URL,
+            None
+        ));
+    }
+
+    #[test]
+    fn only_enabled_checks_count() {
+        let token_only = CsrfPolicy {
+            verify_origin: false,
+            ..CsrfPolicy::default()
+        };
+
+        assert!(!allows(
+            &token_only,
+            &request(Method::POST, "/items", &[("origin", APP_URL)])
+        ));
+        assert!(allows(
+            &token_only,
+            &request(Method::POST, "/items", &[("x-csrf-token", TOKEN)])
+        ));
+    }
+
+    #[test]
+    fn upgrades_need_a_trusted_origin() {
+        let policy = CsrfPolicy {
+            trusted_origins: vec!["https://admin.example.com".to_string()],
+            ..CsrfPolicy::default()
+        };
+        let upgrade = |headers: &[(&str, &str)]| request(Method::GET, "/socket", headers);
+
+        assert!(policy.allows_upgrade(&upgrade(&[("origin", APP_URL)]), APP_URL));
+        assert!(policy.allows_upgrade(
+            &upgrade(&[("origin", "https://admin.example.com")]),
+            APP_URL
+        ));
+        assert!(!policy.allows_upgrade(
+            &upgrade(&[("origin", "https://evil.app.example.com")]),
+            APP_URL
+        ));
+        // the token header does not help, browsers cannot set it on upgrades
+        assert!(!policy.allows_upgrade(&upgrade(&[("x-csrf-token", TOKEN)]), APP_URL));
+    }
+
+    #[test]
+    fn exempt_paths_are_not_checked() {
+        let policy = CsrfPolicy::from_json(r#"{"exempt_paths": ["/webhooks"]}"#).unwrap();
+
+        assert!(allows(
+            &policy,
+            &request(Method::POST, "/webhooks/github", &[])
+        ));
+        assert!(!allows(&policy, &request(Method::POST, "/webhooksx", &[])));
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        assert!(matches!(
+            CsrfPolicy::from_json(r#"{"exempt_paths": ["webhooks"]}"#),
+            Err(CsrfError::InvalidPath { .. })
+        ));
+        assert!(matches!(
+            CsrfPolicy::from_json(r#"{"enabled": "yes"}"#),
+            Err(CsrfError::InvalidConfig { .. })
+        ));
+    }
+}
@@ -1,3 +1,12 @@
 pub mod public;
+pub mod response_transforms;
 pub mod authenticated;
+pub mod cors;
+pub mod csrf;
 pub mod router;
+pub mod routing;
+pub mod security_headers;
+pub mod upstream;
+pub mod websocket;
+#[cfg(test)]
+mod tests;
@@ -1,5 +1,8 @@
+use crate::api::router::AppState;
+use crate::api::upstream::Upstream;
+use crate::axum_introspector::introspection::IntrospectedUser;
 use crate::utilities::Utilities;
-use crate::{AppState, Callback};
+use crate::Callback;
 use axum::extract::{Query, Request, State};
 use axum::response::IntoResponse;
 use oauth2::basic::BasicClient;
@@ -10,12 +13,33 @@ use oauth2::{
 use std::str::FromStr;
 use std::sync::Arc;
 use tower::Layer;
+use tower_cookies::Cookies;
 use tower_service::Service;
-use tower_sessions_core::Session;
+use tower_sessions::SessionStore;
+use tower_sessions_core::session::Id;
+use tower_sessions_core::{Expiry, Session};
 use worker::*;
 
 pub struct PublicApi;
 
+/// Key of the started session's id on a pending login.
+const AUTH_SESSION_ID: &str = "auth_session_id";
+
+/// Submits the login form right away. The Content-Security-Policy of the edge allows it by hash.
+pub(crate) const LOGIN_SCRIPT: &str = r#"
+        document.addEventListener('DOMContentLoaded', function() {
+            const form = document.querySelector('form[action="/login/authorize"]');
+            if (form) {
+                form.submit();
+            } else {
+                console.error("Login form not found.");
+            }
+        });
+    "#;
+
+/// How long a started login may take before its callback is rejected.
+const PENDING_LOGIN_TTL: time::Duration = time::Duration::minutes(10);
+
 impl PublicApi {
     #[worker::send]
     pub async fn fallback() -> impl IntoResponse {
@@ -34,7 +58,7 @@ impl PublicApi {
 
         session.save().await.unwrap();
 
-        axum::response::Html(
+        axum::response::Html(format!(
             r#"
 <!DOCTYPE html>
 <html lang="en">
@@ -42,58 +66,97 @@ impl PublicApi {
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
     <title>Redirecting...</title>
-    <script>
-        document.addEventListener('DOMContentLoaded', function() {
-            const form = document.querySelector('form[action="/login/authorize"]');
-            if (form) {
-                form.submit();
-            } else {
-                console.error("Login form not found.");
-            }
-        });
-    </script>
+    <script>{}</script>
 </head>
 <body>
     <p>Redirecting to login...</p>
-    <form action="/login/authorize" method="GET" style="display:none;">
+    <form action="/login/authorize" method="GET" hidden>
         <button type="submit">Login with ZITADEL</button>
     </form>
 </body>
 </html>
 "#,
-        )
+            LOGIN_SCRIPT
+        ))
         .into_response()
     }
 
+    /// Ends the session and evicts its token from the introspection caches, so a token handed
+    /// out elsewhere is introspected afresh instead of riding on this session's cached result.
+    /// Logging out changes state, so it is a `POST` held to the CSRF policy like proxied ones.
+    #[worker::send]
+    pub async fn logout<S, U>(
+        session: tower_sessions::Session,
+        State(state): State<AppState<S, U>>,
+        cookies: Cookies,
+        request: Request,
+    ) -> impl IntoResponse
+    where
+        S: SessionStore + Clone,
+        U: Upstream + Clone + 'static,
+    {
+        if !state
+            .csrf_policy
+            .protect(
+                &request,
+                &session,
+                &cookies,
+                &state.config.app_url,
+                !state.config.dev_mode,
+            )
+            .await
+        {
+            return (http::StatusCode::FORBIDDEN, "CSRF check failed.").into_response();
+        }
+
+        if let Ok(Some(token)) = session.get::<String>("token").await {
+            IntrospectedUser::forget(&state.introspection_state, &token).await;
+        }
+
+        if let Err(error) = session.flush().await {
+            console_error!("Could not end the session: {:?}", error);
+            return axum::response::Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+
+        axum::response::Redirect::to("/login").into_response()
+    }
+
     #[worker::send]
-    pub async fn authorize(
+    pub async fn authorize<S, U>(
         session: tower_sessions::Session,
-        State(state): State<AppState>,
-    ) -> impl IntoResponse {
-        let oauth_base_url = state.env.secret("AUTH_SERVER_URL").unwrap().to_string();
-        let app_host = state.env.secret("APP_URL").unwrap().to_string();
+        State(state): State<AppState<S, U>>,
+    ) -> impl IntoResponse
+    where
+        S: SessionStore + Clone,
+        U: Upstream + Clone + 'static,
+    {
+        let oauth_base_url = state.config.auth_server_url.clone();
+        let app_host = state.config.app_url.clone();
 
         let redirect_uri = format!("{}{}", app_host, "/login/callback");
 
-        let client = BasicClient::new(ClientId::new(
-            state.env.secret("CLIENT_ID").unwrap().to_string(),
-        ))
-        .set_client_secret(ClientSecret::new(
-            state.env.secret("CLIENT_SECRET").unwrap().to_string(),
-        ))
-        .set_auth_uri(AuthUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/authorize")).unwrap())
-        .set_token_uri(TokenUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/token")).unwrap())
-        .set_redirect_uri(RedirectUrl::new(redirect_uri).unwrap());
+        let client = BasicClient::new(ClientId::new(state.config.client_id.clone()))
+            .set_client_secret(ClientSecret::new(state.config.client_secret.clone()))
+            .set_auth_uri(
+                AuthUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/authorize")).unwrap(),
+            )
+            .set_token_uri(
+                TokenUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/token")).unwrap(),
+            )
+            .set_redirect_uri(RedirectUrl::new(redirect_uri).unwrap());
 
         // Generate a PKCE challenge.
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-        let org_scope: String = if let Ok(org_id) = state.env.secret("ZITADEL_ORG_ID") {
-            format!("urn:zitadel:iam:org:id:{}", org_id.to_string())
+        let org_scope: String = if let Some(org_id) = &state.config.org_id {
+            format!("urn:zitadel:iam:org:id:{}", org_id)
         } else {
             String::new()
         };
-        let project_scope: String = if let Ok(project_id) = state.env.secret("ZITADEL_PROJECT_ID") {
+        let project_scope: String = if let Some(project_id) = &state.config.project_id {
             format!(
                 "urn:zitadel:iam:org:project:id:{}:aud",
                 project_id.to_string()
@@ -116,8 +179,18 @@ impl PublicApi {
             scopes.push(Scope::new(project_scope));
         }
 
+        // The OAuth state is the id of a short-lived pending login in the session store,
+        // which leads the callback back to the session that started the login.
+        let pending_login = Session::new(
+            None,
+            Arc::new(state.session_store.clone()),
+            Some(Expiry::OnInactivity(PENDING_LOGIN_TTL)),
+        );
+        pending_login.save().await.unwrap();
+        let pending_login_id = pending_login.id().unwrap().to_string();
+
         let (auth_url, csrf_token) = client
-            .authorize_url(CsrfToken::new_random)
+            .authorize_url(|| CsrfToken::new(pending_login_id))
             .add_scopes(scopes)
             .set_pkce_challenge(pkce_challenge)
             .url();
@@ -147,16 +220,11 @@ impl PublicApi {
                 .unwrap();
             session.save().await.unwrap();
         }
-        let csrf_store = state.env.kv("KV_STORAGE").unwrap();
-
-        let session_csrf_key = Utilities::get_auth_session_key(csrf_string.as_str());
-
-        csrf_store
-            .put(session_csrf_key.as_str(), session.id().unwrap().to_string())
-            .unwrap()
-            .execute()
+        pending_login
+            .insert(AUTH_SESSION_ID, session.id().unwrap().to_string())
             .await
             .unwrap();
+        pending_login.save().await.unwrap();
 
         let final_auth_url = auth_url.as_str();
 
@@ -170,12 +238,16 @@ impl PublicApi {
     }
 
     #[worker::send]
-    pub async fn callback(
-        State(state): State<AppState>,
+    pub async fn callback<S, U>(
+        State(state): State<AppState<S, U>>,
         mut session: tower_sessions::Session,
         callback: Query<Callback>,
         request: Request,
-    ) -> impl IntoResponse {
+    ) -> impl IntoResponse
+    where
+        S: SessionStore + Clone,
+        U: Upstream + Clone + 'static,
+    {
         let code = &callback.code;
         let state_param = &callback.state;
 
@@ -188,24 +260,38 @@ impl PublicApi {
 
         let verifier_storage_key = Utilities::get_pkce_verifier_storage_key(state_param);
 
-        let csrf_store = state.env.kv("KV_STORAGE").unwrap();
+        let session_store = Arc::new(state.session_store.clone());
 
-        let csrf_key = Utilities::get_auth_session_key(state_param);
+        let pending_login = match Id::from_str(state_param) {
+            Ok(pending_login_id) => {
+                Session::new(Some(pending_login_id), session_store.clone(), None)
+            }
+            Err(_) => {
+                return axum::response::Response::builder()
+                    .status(http::StatusCode::BAD_REQUEST)
+                    .body(axum::body::Body::from("Session state mismatch or expired."))
+                    .unwrap();
+            }
+        };
 
-        let get_auth_session_id = csrf_store
-            .get(csrf_key.as_str())
-            .text()
+        let auth_session_id = pending_login
+            .get::<String>(AUTH_SESSION_ID)
             .await
-            .expect("failed to get auth session id");
+            .ok()
+            .flatten()
+            .and_then(|id| Id::from_str(id.as_str()).ok());
 
-        csrf_store.delete(csrf_key.as_str()).await.unwrap();
+        pending_login.delete().await.unwrap();
 
-        let asi = get_auth_session_id.map(|data| data).unwrap();
-
-        let auth_session_id = tower_sessions_core::session::Id::from_str(asi.as_str()).unwrap();
+        let Some(auth_session_id) = auth_session_id else {
+            console_error!("No pending login found for state: {:?}", state_param);
+            return axum::response::Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(axum::body::Body::from("Session state mismatch or expired."))
+                .unwrap();
+        };
 
-        let mut auth_session =
-            Session::new(Some(auth_session_id), Arc::new(state.session_store), None);
+        let mut auth_session = Session::new(Some(auth_session_id), session_store, None);
 
         let verifier_string: String = match auth_session.get(verifier_storage_key.as_str()).await {
             Ok(Some(v)) => v,
@@ -272,34 +358,29 @@ impl PublicApi {
             .await
             .unwrap();
 
-        let oauth_base_url = state.env.secret("AUTH_SERVER_URL").unwrap().to_string();
-        let app_host = state.env.secret("HOST").unwrap().to_string();
+        let oauth_base_url = state.config.auth_server_url.clone();
+        // must match the redirect URI of the authorization request
+        let app_host = state.config.app_url.clone();
         let redirect_uri = format!("{}{}", app_host, "/login/callback");
 
         let redirect_url = RedirectUrl::new(redirect_uri).unwrap();
 
-        let client = BasicClient::new(ClientId::new(
-            state.env.secret("CLIENT_ID").unwrap().to_string(),
-        ))
-        .set_client_secret(ClientSecret::new(
-            state
-                .env
-                .secret("CLIENT_SECRET")
-                .unwrap()
-                .to_string(),
-        ))
-        .set_auth_uri(AuthUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/authorize")).unwrap())
-        .set_token_uri(TokenUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/token")).unwrap())
-        .set_redirect_uri(redirect_url);
+        let client = BasicClient::new(ClientId::new(state.config.client_id.clone()))
+            .set_client_secret(ClientSecret::new(state.config.client_secret.clone()))
+            .set_auth_uri(
+                AuthUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/authorize")).unwrap(),
+            )
+            .set_token_uri(
+                TokenUrl::new(format!("{}{}", oauth_base_url, "/oauth/v2/token")).unwrap(),
+            )
+            .set_redirect_uri(redirect_url);
 
-        let http_client = oauth2::reqwest::ClientBuilder::new()
-            .build()
-            .expect("Client should build");
+        let http_client = state.http_client.clone();
 
         match client
             .exchange_code(AuthorizationCode::new(code.to_string()))
             .set_pkce_verifier(pkce_verifier)
-            .request_async(&http_client)
+            .request_async(&|request| http_client.execute_request(request))
             .await
         {
             Ok(token_result) => {
@@ -0,0 +1,301 @@
+use axum::response::{IntoResponse, Redirect, Response};
+use custom_error::custom_error;
+use http::{HeaderName, HeaderValue, StatusCode};
+use serde::Deserialize;
+use std::sync::Arc;
+
+custom_error! {
+    pub TransformError
+        InvalidConfig{source: serde_json::Error} = "the response transforms are not valid: {source}",
+        InvalidHeader{name: String} = "{name} is not a valid header",
+        InvalidStatus{status: u16} = "{status} is not a valid status code",
+}
+
+/// A change made to every response coming back from the upstream.
+#[derive(Clone, Debug)]
+pub enum ResponseTransform {
+    /// Sets a header, replacing any value the upstream sent.
+    SetHeader(HeaderName, HeaderValue),
+    RemoveHeader(HeaderName),
+    /// Replaces the prefix `from` of a `Location` header with `to`, e.g. to turn redirects to
+    /// the internal service into redirects to the edge.
+    RewriteLocation {
+        from: String,
+        to: String,
+    },
+    /// Answers with another status, keeping headers and body.
+    MapStatus {
+        from: StatusCode,
+        to: StatusCode,
+    },
+    /// Sends the user back to the login instead, e.g. when the upstream answers `401`.
+    LoginOnStatus(StatusCode),
+}
+
+/// One entry of `PROXY_RESPONSE_TRANSFORMS`, e.g. `{"set_header": {"name": "x-frame-options",
+/// "value": "DENY"}}`, `{"remove_header": "server"}`, `{"rewrite_location": {"from":
+/// "http://backend.internal", "to": ""}}`, `{"map_status": {"from": 502, "to": 503}}` or
+/// `{"login_on_status": 401}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformConfig {
+    SetHeader { name: String, value: String },
+    RemoveHeader(String),
+    RewriteLocation { from: String, to: String },
+    MapStatus { from: u16, to: u16 },
+    LoginOnStatus(u16),
+}
+
+fn header_name(name: &str) -> Result<HeaderName, TransformError> {
+    HeaderName::try_from(name).map_err(|_| TransformError::InvalidHeader {
+        name: name.to_string(),
+    })
+}
+
+fn status(status: u16) -> Result<StatusCode, TransformError> {
+    StatusCode::from_u16(status).map_err(|_| TransformError::InvalidStatus { status })
+}
+
+impl TryFrom<TransformConfig> for ResponseTransform {
+    type Error = TransformError;
+
+    fn try_from(config: TransformConfig) -> Result<Self, Self::Error> {
+        Ok(match config {
+            TransformConfig::SetHeader { name, value } => ResponseTransform::SetHeader(
+                header_name(&name)?,
+                HeaderValue::try_from(value).map_err(|_| TransformError::InvalidHeader { name })?,
+            ),
+            TransformConfig::RemoveHeader(name) => {
+                ResponseTransform::RemoveHeader(header_name(&name)?)
+            }
+            TransformConfig::RewriteLocation { from, to } => {
+                ResponseTransform::RewriteLocation { from, to }
+            }
+            TransformConfig::MapStatus { from, to } => ResponseTransform::MapStatus {
+                from: status(from)?,
+                to: status(to)?,
+            },
+            TransformConfig::LoginOnStatus(code) => ResponseTransform::LoginOnStatus(status(code)?),
+        })
+    }
+}
+
+impl ResponseTransform {
+    fn apply(&self, mut response: Response) -> Response {
+        match self {
+            ResponseTransform::SetHeader(name, value) => {
+                response.headers_mut().insert(name.clone(), value.clone());
+            }
+            ResponseTransform::RemoveHeader(name) => {
+                response.headers_mut().remove(name);
+            }
+            ResponseTransform::RewriteLocation { from, to } => {
+                let rewritten = response
+                    .headers()
+                    .get(http::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| location.strip_prefix(from.as_str()))
+                    // "http://backend.internal" is not a prefix of "http://backend.internal.evil.com"
+                    .filter(|rest| {
+                        from.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?', '#'])
+                    })
+                    .map(|rest| format!("{}{}", to, rest))
+                    .map(|location| {
+                        if location.is_empty() {
+                            "/".to_string()
+                        } else {
+                            location
+                        }
+                    })
+                    .and_then(|location| HeaderValue::try_from(location).ok());
+                if let Some(location) = rewritten {
+                    response
+                        .headers_mut()
+                        .insert(http::header::LOCATION, location);
+                }
+            }
+            ResponseTransform::MapStatus { from, to } => {
+                if response.status() == *from {
+                    *response.status_mut() = *to;
+                }
+            }
+            ResponseTransform::LoginOnStatus(status) => {
+                if response.status() == *status {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+        }
+        response
+    }
+}
+
+/// The transforms applied, in order, to proxied responses. Empty by default.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseTransforms {
+    transforms: Arc<Vec<ResponseTransform>>,
+}
+
+impl ResponseTransforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the transforms from their JSON configuration, a list of [`TransformConfig`].
+    pub fn from_json(json: &str) -> Result<Self, TransformError> {
+        let configs: Vec<TransformConfig> = serde_json::from_str(json)?;
+
+        let transforms = configs
+            .into_iter()
+            .map(ResponseTransform::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            transforms: Arc::new(transforms),
+        })
+    }
+
+    pub fn with(mut self, transform: ResponseTransform) -> Self {
+        Arc::make_mut(&mut self.transforms).push(transform);
+        self
+    }
+
+    pub fn apply(&self, response: Response) -> Response {
+        self.transforms
+            .iter()
+            .fold(response, |response, transform| transform.apply(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> Response {
+        let mut builder = http::Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::from("upstream body")).unwrap()
+    }
+
+    #[test]
+    fn sets_and_removes_headers() {
+        let transforms = ResponseTransforms::from_json(
+            r#"[
+                {"set_header": {"name": "x-frame-options", "value": "DENY"}},
+                {"remove_header": "server"}
+            ]"#,
+        )
+        .unwrap();
+
+        let response = transforms.apply(response(
+            200,
+            &[("server", "backend/1.0"), ("x-frame-options", "ALLOWALL")],
+        ));
+
+        assert_eq!(response.headers().get("x-frame-options").unwrap(), "DENY");
+        assert!(response.headers().get("server").is_none());
+    }
+
+    #[test]
+    fn rewrites_internal_locations() {
+        let transforms = ResponseTransforms::new().with(ResponseTransform::RewriteLocation {
+            from: "http://backend.internal".to_string(),
+            to: "https://app.example.com".to_string(),
+        });
+
+        let internal = transforms.apply(response(
+            302,
+            &[("location", "http://backend.internal/next?page=2")],
+        ));
+        let external =
+            transforms.apply(response(302, &[("location", "https://other.example.com/")]));
+        let lookalike = transforms.apply(response(
+            302,
+            &[("location", "http://backend.internal.evil.com/x")],
+        ));
+
+        assert_eq!(
+            internal.headers().get("location").unwrap(),
+            "https://app.example.com/next?page=2"
+        );
+        assert_eq!(
+            external.headers().get("location").unwrap(),
+            "https://other.example.com/"
+        );
+        assert_eq!(
+            lookalike.headers().get("location").unwrap(),
+            "http://backend.internal.evil.com/x"
+        );
+    }
+
+    #[test]
+    fn rewriting_to_a_relative_location_keeps_a_path() {
+        let transforms = ResponseTransforms::new().with(ResponseTransform::RewriteLocation {
+            from: "http://backend.internal".to_string(),
+            to: "".to_string(),
+        });
+
+        let response = transforms.apply(response(302, &[("location", "http://backend.internal")]));
+
+        assert_eq!(response.headers().get("location").unwrap(), "/");
+    }
+
+    #[tokio::test]
+    async fn maps_statuses() {
+        let transforms =
+            ResponseTransforms::from_json(r#"[{"map_status": {"from": 502, "to": 503}}]"#).unwrap();
+
+        let mapped = transforms.apply(response(502, &[]));
+        let untouched = transforms.apply(response(500, &[]));
+
+        assert_eq!(mapped.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(untouched.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = mapped.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "upstream body");
+    }
+
+    #[test]
+    fn sends_unauthorized_users_to_the_login() {
+        let transforms = ResponseTransforms::from_json(r#"[{"login_on_status": 401}]"#).unwrap();
+
+        let response = transforms.apply(response(401, &[]));
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers().get("location").unwrap(), "/login");
+    }
+
+    #[test]
+    fn transforms_apply_in_order() {
+        let transforms = ResponseTransforms::new()
+            .with(ResponseTransform::MapStatus {
+                from: StatusCode::FORBIDDEN,
+                to: StatusCode::UNAUTHORIZED,
+            })
+            .with(ResponseTransform::LoginOnStatus(StatusCode::UNAUTHORIZED));
+
+        let response = transforms.apply(response(403, &[]));
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        assert!(matches!(
+            ResponseTransforms::from_json(r#"[{"remove_header": "not a header"}]"#),
+            Err(TransformError::InvalidHeader { .. })
+        ));
+        assert!(matches!(
+            ResponseTransforms::from_json(r#"[{"login_on_status": 1000}]"#),
+            Err(TransformError::InvalidStatus { .. })
+        ));
+        assert!(matches!(
+            ResponseTransforms::from_json(r#"[{"drop_body": true}]"#),
+            Err(TransformError::InvalidConfig { .. })
+        ));
+    }
+}
@@ -1,188 +1,350 @@
 use crate::api::authenticated::AuthenticatedApi;
+use crate::api::csrf::CsrfPolicy;
 use crate::api::public::PublicApi;
-use crate::axum_introspector::introspection::{IntrospectionState, IntrospectionStateBuilder};
-use crate::oidc::introspection::cache::in_memory::InMemoryIntrospectionCache;
-use crate::session_storage::in_memory::MemoryStore;
+use crate::api::response_transforms::ResponseTransforms;
+use crate::api::security_headers::SecurityHeaders;
+use crate::api::upstream::Upstream;
+use crate::api::websocket::WebSocketPolicy;
+use crate::axum_introspector::introspection::{DegradedSignal, IntrospectionState};
+use crate::http_client::HttpClient;
+use crate::keyring::Keyring;
+use crate::session_storage::cookie::{carry_session_data, SessionDataCookies};
+use axum::extract::{FromRef, State};
 use axum::response::{IntoResponse, Redirect};
-use axum::routing::{any, get};
-use axum::{Router, ServiceExt};
+use axum::routing::{any, get, post};
+use axum::Router;
 use http::HeaderName;
 use std::iter::once;
 use std::sync::Arc;
+use tower_cookies::cookie::{Cookie, SameSite};
 use tower_cookies::CookieManagerLayer;
 use tower_http::cors::CorsLayer;
 use tower_http::propagate_header::PropagateHeaderLayer;
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
-use tower_sessions::cookie::{Key, SameSite};
-use tower_sessions::SessionManagerLayer;
+use tower_sessions::{Session, SessionManagerLayer, SessionStore};
 use tower_sessions_core::Expiry;
+use worker::Env;
 
-// Test configuration struct
-#[derive(Clone)]
-pub struct TestConfig {
+pub(crate) const SESSION_COOKIE: &str = "session";
+
+/// Deployment settings of the edge, read from Worker vars and secrets in production.
+#[derive(Clone, Debug)]
+pub struct AppConfig {
     pub auth_server_url: String,
     pub client_id: String,
     pub client_secret: String,
+    /// Public URL of the app. The OAuth redirect and the session cookie domain derive from it.
     pub app_url: String,
+    pub org_id: Option<String>,
+    pub project_id: Option<String>,
     pub dev_mode: bool,
 }
 
-impl Default for TestConfig {
-    fn default() -> Self {
+impl AppConfig {
+    pub fn from_env(env: &Env) -> Self {
         Self {
-            auth_server_url: "https://test-auth-server.example.com".to_string(),
-            client_id: "test-client-id".to_string(),
-            client_secret: "test-client-secret".to_string(),
-            app_url: "http://localhost:3000".to_string(),
-            dev_mode: true,
+            auth_server_url: env.secret("AUTH_SERVER_URL").unwrap().to_string(),
+            client_id: env.secret("CLIENT_ID").unwrap().to_string(),
+            client_secret: env.secret("CLIENT_SECRET").unwrap().to_string(),
+            app_url: env.secret("APP_URL").unwrap().to_string(),
+            org_id: env.secret("ZITADEL_ORG_ID").ok().map(|v| v.to_string()),
+            project_id: env.secret("ZITADEL_PROJECT_ID").ok().map(|v| v.to_string()),
+            dev_mode: env.var("DEV_MODE").unwrap().to_string() == "true",
         }
     }
 }
 
-// App state for testing
 #[derive(Clone)]
-pub struct TestAppState {
+pub struct AppState<S, U> {
+    pub config: Arc<AppConfig>,
     pub introspection_state: IntrospectionState,
-    pub session_store: MemoryStore,
+    pub session_store: S,
+    pub upstream: U,
+    /// Used for the token exchange with the IdP.
+    pub http_client: HttpClient,
+    pub websocket_policy: WebSocketPolicy,
+    /// Applied to responses from the upstream before they are returned.
+    pub response_transforms: ResponseTransforms,
+    pub security_headers: SecurityHeaders,
+    /// Built from the `CorsPolicy`; cross-origin requests are denied unless allowed here.
+    pub cors: CorsLayer,
+    /// Guards state-changing requests authenticated by the session cookie.
+    pub csrf_policy: CsrfPolicy,
 }
 
-impl From<TestAppState> for IntrospectionState {
-    fn from(state: TestAppState) -> Self {
-        state.introspection_state
+impl<S, U> AppState<S, U> {
+    pub fn new(
+        config: AppConfig,
+        introspection_state: IntrospectionState,
+        session_store: S,
+        upstream: U,
+    ) -> Self {
+        Self {
+            security_headers: SecurityHeaders::new(&config),
+            config: Arc::new(config),
+            introspection_state,
+            session_store,
+            upstream,
+            http_client: HttpClient::default(),
+            websocket_policy: WebSocketPolicy::default(),
+            response_transforms: ResponseTransforms::default(),
+            cors: CorsLayer::new(),
+            csrf_policy: CsrfPolicy::default(),
+        }
+    }
+
+    pub fn with_http_client(mut self, http_client: HttpClient) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    pub fn with_websocket_policy(mut self, websocket_policy: WebSocketPolicy) -> Self {
+        self.websocket_policy = websocket_policy;
+        self
+    }
+
+    pub fn with_security_headers(mut self, security_headers: SecurityHeaders) -> Self {
+        self.security_headers = security_headers;
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsLayer) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_csrf_policy(mut self, csrf_policy: CsrfPolicy) -> Self {
+        self.csrf_policy = csrf_policy;
+        self
+    }
+
+    pub fn with_response_transforms(mut self, response_transforms: ResponseTransforms) -> Self {
+        self.response_transforms = response_transforms;
+        self
     }
 }
 
-// Create a router for testing
-pub async fn create_router(config: TestConfig) -> Router<TestAppState> {
-    // Create a memory-based introspection cache for testing
-    let cache = InMemoryIntrospectionCache::new();
-
-    // Create introspection state
-    let introspection_state = IntrospectionStateBuilder::new(&config.auth_server_url)
-        .with_basic_auth(&config.client_id, &config.client_secret)
-        .with_introspection_cache(cache)
-        .build()
-        .await
-        .unwrap();
-
-    // Create a memory-based session store for testing
-    let session_store = MemoryStore::default();
-
-    // Create app state
-    let state = TestAppState {
-        introspection_state,
-        session_store: session_store.clone(),
-    };
+impl<S, U> FromRef<AppState<S, U>> for IntrospectionState {
+    fn from_ref(input: &AppState<S, U>) -> Self {
+        input.introspection_state.clone()
+    }
+}
 
-    // Generate keys for session encryption and signing
-    let signing_key = Key::generate();
-    let encryption_key = Key::generate();
+/// Builds the edge router. The session store, the introspection cache (configured on the
+/// [`IntrospectionState`]) and the upstream are pluggable, so the production routes can run
+/// against Cloudflare bindings as well as in-memory implementations.
+///
+/// Session cookies are sealed with the active key of `keyring`; cookies sealed with one of its
+/// previous keys are still accepted and re-issued under the active key.
+pub fn create_router<S, U>(state: AppState<S, U>, keyring: Keyring) -> Router
+where
+    S: SessionStore + Clone,
+    U: Upstream + Clone + 'static,
+{
+    let cookie_host_uri = state.config.app_url.parse::<http::Uri>().unwrap();
 
-    // Parse the app URL to get the host for cookies
-    let cookie_host_uri = config.app_url.parse::<http::Uri>().unwrap();
     let mut cookie_host = cookie_host_uri.authority().unwrap().to_string();
 
     if cookie_host.starts_with("localhost:") {
         cookie_host = "localhost".to_string();
     }
 
-    // Create session layer
-    let session_layer = SessionManagerLayer::new(session_store)
-        .with_name("session")
+    let session_data_cookies = SessionDataCookies {
+        domain: cookie_host.clone(),
+        secure: !state.config.dev_mode,
+    };
+
+    let session_layer = SessionManagerLayer::new(state.session_store.clone())
+        .with_name(SESSION_COOKIE)
         .with_expiry(Expiry::OnSessionEnd)
         .with_domain(cookie_host)
         .with_same_site(SameSite::Lax)
-        .with_signed(signing_key)
-        .with_private(encryption_key)
+        .with_signed(keyring.active().signing.clone())
+        .with_private(keyring.active().encryption.clone())
         .with_path("/")
-        .with_secure(!config.dev_mode)
+        .with_secure(!state.config.dev_mode)
         .with_always_save(false);
 
-    // Error handling middleware
-    async fn handle_introspection_errors(
-        mut response: axum_core::response::Response,
-    ) -> axum_core::response::Response {
-        let x_error_header_value = response
-            .headers()
-            .get("x-introspection-error")
-            .and_then(|header_value| header_value.to_str().ok());
-
-        match response.status() {
-            http::StatusCode::UNAUTHORIZED => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "unauthorized" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::BAD_REQUEST => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "invalid schema"
-                        || x_error == "invalid header"
-                        || x_error == "introspection error"
-                    {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::FORBIDDEN => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "user is inactive" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::NOT_FOUND => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "user was not found" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::INTERNAL_SERVER_ERROR => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "missing config" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            _ => response,
-        }
-    }
+    let security_headers = state.security_headers.clone();
+    let cors = state.cors.clone();
 
-    // Create the router with test-specific routes
     Router::new()
-        .route("/api/whoami", get(whoami))
-        .route("/public", get(public_test_route))
-        .route("/protected", get(protected_test_route))
+        .route("/", any(AuthenticatedApi::proxy::<S, U>))
+        .route("/login", get(PublicApi::login_page)) // Add the login page route
+        .route("/login/callback", get(PublicApi::callback::<S, U>))
+        .route("/login/authorize", get(PublicApi::authorize::<S, U>))
+        .route("/logout", post(PublicApi::logout::<S, U>))
+        .route("/api/whoami", get(AuthenticatedApi::whoami))
+        .route("/*path", any(AuthenticatedApi::proxy::<S, U>))
         .layer(PropagateHeaderLayer::new(HeaderName::from_static(
             "x-request-id",
         )))
         .layer(axum::middleware::map_response(handle_introspection_errors))
+        .layer(axum::middleware::from_fn(signal_degraded_introspection))
+        .layer(axum::middleware::map_response_with_state(
+            security_headers,
+            set_security_headers,
+        ))
         .with_state(state)
+        .layer(axum::middleware::from_fn(refresh_resealed_session))
         .layer(session_layer)
+        .layer(axum::middleware::from_fn_with_state(
+            session_data_cookies,
+            carry_session_data,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            keyring,
+            reseal_session_cookie,
+        ))
         .layer(CookieManagerLayer::new())
-        .layer(CorsLayer::very_permissive())
+        .layer(cors)
         .layer(SetSensitiveRequestHeadersLayer::new(once(
             http::header::AUTHORIZATION,
         )))
 }
 
-// Test routes
-async fn whoami() -> impl IntoResponse {
-    "test user"
+async fn handle_introspection_errors(
+    mut response: axum_core::response::Response,
+) -> axum_core::response::Response {
+    let x_error_header_value = response
+        .headers()
+        .get("x-introspection-error")
+        .and_then(|header_value| header_value.to_str().ok());
+
+    // not used but is available
+    let x_session_header_value = response
+        .headers()
+        .get("x-session")
+        .and_then(|header_value| header_value.to_str().ok());
+
+    match response.status() {
+        http::StatusCode::UNAUTHORIZED => {
+            if let Some(x_error) = x_error_header_value {
+                if x_error == "unauthorized" {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+            response
+        }
+        http::StatusCode::BAD_REQUEST => {
+            if let Some(x_error) = x_error_header_value {
+                if x_error == "invalid schema"
+                    || x_error == "invalid header"
+                    || x_error == "introspection error"
+                {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+            response
+        }
+        http::StatusCode::FORBIDDEN => {
+            if let Some(x_error) = x_error_header_value {
+                if x_error == "user is inactive" {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+            response
+        }
+        http::StatusCode::NOT_FOUND => {
+            if let Some(x_error) = x_error_header_value {
+                if x_error == "user was not found" {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+            response
+        }
+        http::StatusCode::INTERNAL_SERVER_ERROR => {
+            if let Some(x_error) = x_error_header_value {
+                if x_error == "missing config" {
+                    return Redirect::to("/login").into_response();
+                }
+            }
+            response
+        }
+        _ => response,
+    }
+}
+
+async fn set_security_headers(
+    State(security_headers): State<SecurityHeaders>,
+    mut response: axum_core::response::Response,
+) -> axum_core::response::Response {
+    security_headers.apply(&mut response);
+    response
 }
 
-async fn public_test_route() -> impl IntoResponse {
-    "public route"
+async fn signal_degraded_introspection(
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum_core::response::Response {
+    let degraded = DegradedSignal::default();
+    request.extensions_mut().insert(degraded.clone());
+
+    let mut response = next.run(request).await;
+    if degraded.is_degraded() {
+        response.headers_mut().insert(
+            "x-introspection-degraded",
+            http::HeaderValue::from_static("stale"),
+        );
+    }
+    response
 }
 
-async fn protected_test_route() -> impl IntoResponse {
-    "protected route"
+/// Marks requests whose session cookie was sealed with a previous key.
+#[derive(Clone, Copy, Debug)]
+struct ResealedSession;
+
+/// Swaps a session cookie sealed with a previous key for one sealed with the active key, so the
+/// session layer can open it.
+async fn reseal_session_cookie(
+    State(keyring): State<Keyring>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum_core::response::Response {
+    if !keyring.previous().is_empty() {
+        let mut resealed = false;
+        let pairs: Vec<String> = request
+            .headers()
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                match Cookie::parse_encoded(pair.to_string())
+                    .ok()
+                    .filter(|cookie| cookie.name() == SESSION_COOKIE)
+                    .and_then(|cookie| keyring.reseal(cookie))
+                {
+                    Some(cookie) => {
+                        resealed = true;
+                        format!("{}={}", cookie.name(), cookie.value())
+                    }
+                    None => pair.to_string(),
+                }
+            })
+            .collect();
+
+        if resealed {
+            if let Ok(value) = http::HeaderValue::from_str(&pairs.join("; ")) {
+                request.headers_mut().insert(http::header::COOKIE, value);
+                request.extensions_mut().insert(ResealedSession);
+            }
+        }
+    }
+    next.run(request).await
 }
 
+/// Has the session layer issue the cookie again, now sealed with the active key.
+async fn refresh_resealed_session(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum_core::response::Response {
+    if request.extensions().get::<ResealedSession>().is_some() {
+        if let Some(session) = request.extensions().get::<Session>() {
+            session.set_expiry(session.expiry());
+        }
+    }
+    next.run(request).await
+}
@@ -0,0 +1,374 @@
+use async_trait::async_trait;
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use custom_error::custom_error;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::api::upstream::{OriginUpstream, Upstream};
+
+custom_error! {
+    pub RoutingError
+        InvalidConfig{source: serde_json::Error} = "the routing table is not valid: {source}",
+        UnknownService{name: String} = "no service binding named {name}",
+        InvalidOrigin{origin: String} = "the origin {origin} is not an absolute URL",
+        InvalidPrefix{prefix: String} = "the path prefix {prefix} does not start with a slash",
+}
+
+/// One entry of the routing table as configured in `PROXY_ROUTES`, e.g.
+/// `{"host": "app.example.com", "path_prefix": "/api", "strip_prefix": true, "service": "API"}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteConfig {
+    /// Only requests for this host match. Matches any host if unset.
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_prefix")]
+    pub path_prefix: String,
+    /// Removes the matched prefix before forwarding.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replaces the matched prefix before forwarding, takes precedence over `strip_prefix`.
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    /// Passes the client's `Authorization` header on to an origin, which does not get it otherwise.
+    #[serde(default)]
+    pub forward_authorization: bool,
+    #[serde(flatten)]
+    pub target: Target,
+}
+
+fn default_prefix() -> String {
+    "/".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// Name of a service binding.
+    Service(String),
+    /// Absolute URL of an HTTP origin.
+    Origin(String),
+}
+
+#[derive(Debug)]
+struct Route {
+    host: Option<String>,
+    path_prefix: String,
+    rewrite: Option<String>,
+    upstream: Arc<dyn Upstream>,
+}
+
+impl Route {
+    /// Length of the matched prefix, if the route matches.
+    fn matches(&self, host: Option<&str>, path: &str) -> Option<usize> {
+        if let Some(route_host) = &self.host {
+            if !host.is_some_and(|host| host.eq_ignore_ascii_case(route_host)) {
+                return None;
+            }
+        }
+
+        let prefix = self.path_prefix.trim_end_matches('/');
+        let rest = path.strip_prefix(prefix)?;
+        // "/api" matches "/api" and "/api/users", but not "/apis"
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(prefix.len())
+        } else {
+            None
+        }
+    }
+
+    fn rewrite_path(&self, path: &str) -> String {
+        let Some(replacement) = &self.rewrite else {
+            return path.to_string();
+        };
+        let rest = &path[self.path_prefix.trim_end_matches('/').len()..];
+        let path = format!("{}{}", replacement.trim_end_matches('/'), rest);
+        if path.starts_with('/') {
+            path
+        } else {
+            format!("/{}", path)
+        }
+    }
+}
+
+/// Proxies to one of several upstreams by host and path prefix, so one edge worker can
+/// protect several apps sharing one session.
+///
+/// The route with the longest matching prefix wins; a route bound to the request host wins
+/// over a host-independent one with the same prefix. Requests no route matches are a 404.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the table from its JSON configuration, a list of [`RouteConfig`].
+    /// Service bindings are looked up by name with `service`.
+    pub fn from_json(
+        json: &str,
+        service: impl Fn(&str) -> Option<Arc<dyn Upstream>>,
+    ) -> Result<Self, RoutingError> {
+        let configs: Vec<RouteConfig> = serde_json::from_str(json)?;
+
+        let mut table = Self::new();
+        for config in configs {
+            let upstream: Arc<dyn Upstream> = match &config.target {
+                Target::Service(name) => service(name)
+                    .ok_or_else(|| RoutingError::UnknownService { name: name.clone() })?,
+                Target::Origin(origin) => {
+                    let uri = origin
+                        .parse::<http::Uri>()
+                        .ok()
+                        .filter(|uri| uri.scheme().is_some() && uri.authority().is_some())
+                        .ok_or_else(|| RoutingError::InvalidOrigin {
+                            origin: origin.clone(),
+                        })?;
+                    Arc::new(
+                        OriginUpstream::new(uri)
+                            .with_forward_authorization(config.forward_authorization),
+                    )
+                }
+            };
+            table.add_route(config, upstream)?;
+        }
+        Ok(table)
+    }
+
+    pub fn add_route(
+        &mut self,
+        config: RouteConfig,
+        upstream: Arc<dyn Upstream>,
+    ) -> Result<&mut Self, RoutingError> {
+        if !config.path_prefix.starts_with('/') {
+            return Err(RoutingError::InvalidPrefix {
+                prefix: config.path_prefix,
+            });
+        }
+
+        let rewrite = match (config.rewrite, config.strip_prefix) {
+            (Some(rewrite), _) => Some(rewrite),
+            (None, true) => Some("/".to_string()),
+            (None, false) => None,
+        };
+
+        self.routes.push(Route {
+            host: config.host,
+            path_prefix: config.path_prefix,
+            rewrite,
+            upstream,
+        });
+        Ok(self)
+    }
+
+    fn route(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter_map(|route| {
+                route
+                    .matches(host, path)
+                    .map(|length| ((length, route.host.is_some()), route))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, route)| route)
+    }
+}
+
+fn request_host(request: &Request) -> Option<String> {
+    if let Some(host) = request.uri().host() {
+        return Some(host.to_string());
+    }
+    let host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+    // the Host header may carry a port
+    Some(
+        host.rsplit_once(':')
+            .map_or(host, |(host, _)| host)
+            .to_string(),
+    )
+}
+
+#[async_trait]
+impl Upstream for RoutingTable {
+    async fn forward(&self, mut request: Request) -> Response {
+        let host = request_host(&request);
+        let Some(route) = self.route(host.as_deref(), request.uri().path()) else {
+            return http::StatusCode::NOT_FOUND.into_response();
+        };
+
+        if route.rewrite.is_some() {
+            let path = route.rewrite_path(request.uri().path());
+            let path_and_query = match request.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+
+            let mut parts = request.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse().ok();
+            match http::Uri::from_parts(parts) {
+                Ok(uri) => *request.uri_mut() = uri,
+                Err(_) => return http::StatusCode::BAD_REQUEST.into_response(),
+            }
+        }
+
+        route.upstream.forward(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::body::Body;
+    use axum::http::Uri;
+    use axum::Router;
+    use http_body_util::BodyExt;
+
+    use crate::api::upstream::RouterUpstream;
+
+    use super::*;
+
+    /// An upstream answering with its name and the URI it got.
+    fn named(name: &'static str) -> Arc<dyn Upstream> {
+        Arc::new(RouterUpstream::new(Router::new().fallback(
+            move |uri: Uri| async move { format!("{} {}", name, uri) },
+        )))
+    }
+
+    fn services(name: &str) -> Option<Arc<dyn Upstream>> {
+        match name {
+            "APP" => Some(named("app")),
+            "API" => Some(named("api")),
+            "ADMIN" => Some(named("admin")),
+            _ => None,
+        }
+    }
+
+    async fn forward(table: &RoutingTable, uri: &str) -> (http::StatusCode, String) {
+        let response = table
+            .forward(Request::get(uri).body(Body::empty()).unwrap())
+            .await;
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    const ROUTES: &str = r#"[
+        {"service": "APP"},
+        {"path_prefix": "/api", "strip_prefix": true, "service": "API"},
+        {"path_prefix": "/api/v1", "rewrite": "/legacy", "service": "API"},
+        {"host": "admin.example.com", "service": "ADMIN"}
+    ]"#;
+
+    #[tokio::test]
+    async fn routes_by_longest_prefix() {
+        let table = RoutingTable::from_json(ROUTES, services).unwrap();
+
+        assert_eq!(
+            forward(&table, "https://example.com/home").await.1,
+            "app https://example.com/home"
+        );
+        assert_eq!(
+            forward(&table, "https://example.com/api/users?page=2")
+                .await
+                .1,
+            "api https://example.com/users?page=2"
+        );
+        assert_eq!(
+            forward(&table, "https://example.com/api/v1/users").await.1,
+            "api https://example.com/legacy/users"
+        );
+    }
+
+    #[tokio::test]
+    async fn prefixes_match_whole_segments() {
+        let table = RoutingTable::from_json(ROUTES, services).unwrap();
+
+        assert_eq!(
+            forward(&table, "https://example.com/apis").await.1,
+            "app https://example.com/apis"
+        );
+        assert_eq!(
+            forward(&table, "https://example.com/api").await.1,
+            "api https://example.com/"
+        );
+    }
+
+    #[tokio::test]
+    async fn host_routes_win_over_catch_all() {
+        let table = RoutingTable::from_json(ROUTES, services).unwrap();
+
+        assert_eq!(
+            forward(&table, "https://admin.example.com/users").await.1,
+            "admin https://admin.example.com/users"
+        );
+        // the longer prefix still wins
+        assert_eq!(
+            forward(&table, "https://admin.example.com/api/x").await.1,
+            "api https://admin.example.com/x"
+        );
+    }
+
+    #[tokio::test]
+    async fn host_header_is_used_for_relative_uris() {
+        let table = RoutingTable::from_json(ROUTES, services).unwrap();
+
+        let response = table
+            .forward(
+                Request::get("/users")
+                    .header(http::header::HOST, "admin.example.com:8787")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(body, "admin /users");
+    }
+
+    #[tokio::test]
+    async fn unmatched_requests_are_not_found() {
+        let table = RoutingTable::from_json(
+            r#"[{"host": "app.example.com", "service": "APP"}]"#,
+            services,
+        )
+        .unwrap();
+
+        let (status, _) = forward(&table, "https://other.example.com/").await;
+
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        assert!(matches!(
+            RoutingTable::from_json(r#"[{"service": "MISSING"}]"#, services),
+            Err(RoutingError::UnknownService { .. })
+        ));
+        assert!(matches!(
+            RoutingTable::from_json(r#"[{"origin": "/relative"}]"#, services),
+            Err(RoutingError::InvalidOrigin { .. })
+        ));
+        assert!(matches!(
+            RoutingTable::from_json(r#"[{"path_prefix": "api", "service": "APP"}]"#, services),
+            Err(RoutingError::InvalidPrefix { .. })
+        ));
+        assert!(matches!(
+            RoutingTable::from_json(r#"[{"path_prefix": "/api"}]"#, services),
+            Err(RoutingError::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn origins_are_parsed() {
+        let table = RoutingTable::from_json(
+            r#"[{"path_prefix": "/docs", "origin": "https://docs.example.com"}]"#,
+            services,
+        )
+        .unwrap();
+
+        assert!(table.route(None, "/docs/intro").is_some());
+    }
+}
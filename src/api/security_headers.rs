@@ -0,0 +1,313 @@
+use axum::response::Response;
+use base64::Engine;
+use custom_error::custom_error;
+use http::header::{
+    CACHE_CONTROL, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::api::public::LOGIN_SCRIPT;
+use crate::api::router::AppConfig;
+
+custom_error! {
+    pub SecurityHeadersError
+        InvalidConfig{source: serde_json::Error} = "the security headers are not valid: {source}",
+        InvalidHeader{name: String} = "{name} is not a valid header",
+}
+
+/// Marks responses that come from the upstream, as opposed to those the edge serves itself.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Proxied;
+
+const HSTS: &str = "max-age=31536000; includeSubDomains";
+
+/// Security headers added to responses.
+///
+/// Responses the edge serves itself (the login page, the callback, redirects to the login, ...)
+/// always get strict headers, including `Cache-Control: no-store`. Proxied responses are left to
+/// the upstream unless headers are configured for them; those only fill in what the upstream
+/// did not send.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    edge: HeaderMap,
+    proxied: HeaderMap,
+    hsts: bool,
+}
+
+/// The `SECURITY_HEADERS` configuration, e.g. `{"proxied_defaults": true, "edge":
+/// {"referrer-policy": "same-origin"}, "proxied": {"x-frame-options": null}}`.
+/// A `null` value removes a header that would be set otherwise.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SecurityHeadersConfig {
+    /// Opts proxied responses into the defaults of [`SecurityHeaders::with_proxied_defaults`].
+    #[serde(default)]
+    pub proxied_defaults: bool,
+    #[serde(default)]
+    pub edge: BTreeMap<String, Option<String>>,
+    #[serde(default)]
+    pub proxied: BTreeMap<String, Option<String>>,
+}
+
+impl SecurityHeaders {
+    /// Strict headers for the edge, nothing for proxied responses. HSTS is only sent outside
+    /// of `dev_mode`, as it would stick to `localhost` otherwise.
+    pub fn new(config: &AppConfig) -> Self {
+        let hsts = !config.dev_mode;
+
+        let mut edge = HeaderMap::new();
+        edge.insert(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::try_from(edge_content_security_policy(&config.auth_server_url))
+                .expect("the policy is a valid header"),
+        );
+        edge.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        // the callback URL carries the authorization code
+        edge.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+        edge.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        edge.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        if hsts {
+            edge.insert(STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS));
+        }
+
+        Self {
+            edge,
+            proxied: HeaderMap::new(),
+            hsts,
+        }
+    }
+
+    /// Adds headers to proxied responses that are safe for most apps. No Content-Security-Policy,
+    /// that depends too much on the app.
+    pub fn with_proxied_defaults(mut self) -> Self {
+        self.proxied
+            .insert(X_FRAME_OPTIONS, HeaderValue::from_static("SAMEORIGIN"));
+        self.proxied.insert(
+            REFERRER_POLICY,
+            HeaderValue::from_static("strict-origin-when-cross-origin"),
+        );
+        self.proxied
+            .insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        if self.hsts {
+            self.proxied
+                .insert(STRICT_TRANSPORT_SECURITY, HeaderValue::from_static(HSTS));
+        }
+        self
+    }
+
+    pub fn with_edge_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.edge.insert(name, value);
+        self
+    }
+
+    pub fn without_edge_header(mut self, name: HeaderName) -> Self {
+        self.edge.remove(name);
+        self
+    }
+
+    pub fn with_proxied_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.proxied.insert(name, value);
+        self
+    }
+
+    pub fn without_proxied_header(mut self, name: HeaderName) -> Self {
+        self.proxied.remove(name);
+        self
+    }
+
+    /// Applies a [`SecurityHeadersConfig`] given as JSON on top of these headers.
+    pub fn configure(mut self, json: &str) -> Result<Self, SecurityHeadersError> {
+        let config: SecurityHeadersConfig = serde_json::from_str(json)?;
+
+        if config.proxied_defaults {
+            self = self.with_proxied_defaults();
+        }
+        apply_overrides(&mut self.edge, config.edge)?;
+        apply_overrides(&mut self.proxied, config.proxied)?;
+        Ok(self)
+    }
+
+    pub(crate) fn apply(&self, response: &mut Response) {
+        if response.extensions().get::<Proxied>().is_some() {
+            for (name, value) in &self.proxied {
+                if !response.headers().contains_key(name) {
+                    response.headers_mut().insert(name.clone(), value.clone());
+                }
+            }
+        } else {
+            for (name, value) in &self.edge {
+                response.headers_mut().insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn apply_overrides(
+    headers: &mut HeaderMap,
+    overrides: BTreeMap<String, Option<String>>,
+) -> Result<(), SecurityHeadersError> {
+    for (name, value) in overrides {
+        let invalid = || SecurityHeadersError::InvalidHeader { name: name.clone() };
+        let header_name = HeaderName::try_from(name.as_str()).map_err(|_| invalid())?;
+        match value {
+            Some(value) => {
+                headers.insert(
+                    header_name,
+                    HeaderValue::try_from(value).map_err(|_| invalid())?,
+                );
+            }
+            None => {
+                headers.remove(header_name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Only the inline script of the login page may run, and forms may only lead to the edge and
+/// the IdP the login redirects to.
+fn edge_content_security_policy(auth_server_url: &str) -> String {
+    let script_hash = base64::engine::general_purpose::STANDARD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        LOGIN_SCRIPT.as_bytes(),
+    ));
+    let auth_server_origin = url::Url::parse(auth_server_url)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default();
+
+    format!(
+        "default-src 'none'; script-src 'sha256-{}'; form-action 'self' {}; frame-ancestors 'none'; base-uri 'none'",
+        script_hash, auth_server_origin
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::body::Body;
+
+    use super::*;
+
+    fn config(dev_mode: bool) -> AppConfig {
+        AppConfig {
+            auth_server_url: "https://idp.example.com/".to_string(),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            app_url: "https://app.example.com".to_string(),
+            org_id: None,
+            project_id: None,
+            dev_mode,
+        }
+    }
+
+    fn edge_response() -> Response {
+        Response::new(Body::empty())
+    }
+
+    fn proxied_response(headers: &[(HeaderName, &'static str)]) -> Response {
+        let mut response = Response::new(Body::empty());
+        for (name, value) in headers {
+            response
+                .headers_mut()
+                .insert(name.clone(), HeaderValue::from_static(value));
+        }
+        response.extensions_mut().insert(Proxied);
+        response
+    }
+
+    #[test]
+    fn edge_responses_get_strict_headers() {
+        let headers = SecurityHeaders::new(&config(false));
+
+        let mut response = edge_response();
+        headers.apply(&mut response);
+
+        let csp = response.headers()[CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap();
+        assert!(csp.starts_with("default-src 'none'; script-src 'sha256-"));
+        assert!(csp.contains("form-action 'self' https://idp.example.com;"));
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+        assert_eq!(response.headers()[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(response.headers()[REFERRER_POLICY], "no-referrer");
+        assert_eq!(response.headers()[STRICT_TRANSPORT_SECURITY], HSTS);
+    }
+
+    #[test]
+    fn dev_mode_skips_hsts() {
+        let headers = SecurityHeaders::new(&config(true)).with_proxied_defaults();
+
+        let mut edge = edge_response();
+        headers.apply(&mut edge);
+        let mut proxied = proxied_response(&[]);
+        headers.apply(&mut proxied);
+
+        assert!(edge.headers().get(STRICT_TRANSPORT_SECURITY).is_none());
+        assert!(proxied.headers().get(STRICT_TRANSPORT_SECURITY).is_none());
+    }
+
+    #[test]
+    fn proxied_responses_are_left_alone_by_default() {
+        let headers = SecurityHeaders::new(&config(false));
+
+        let mut response = proxied_response(&[(CACHE_CONTROL, "max-age=60")]);
+        headers.apply(&mut response);
+
+        assert_eq!(response.headers().len(), 1);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+    }
+
+    #[test]
+    fn proxied_defaults_do_not_override_the_upstream() {
+        let headers = SecurityHeaders::new(&config(false)).with_proxied_defaults();
+
+        let mut response = proxied_response(&[(X_FRAME_OPTIONS, "DENY")]);
+        headers.apply(&mut response);
+
+        assert_eq!(response.headers()[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(response.headers()[STRICT_TRANSPORT_SECURITY], HSTS);
+        assert!(response.headers().get(CONTENT_SECURITY_POLICY).is_none());
+    }
+
+    #[test]
+    fn configuration_sets_and_removes_headers() {
+        let headers = SecurityHeaders::new(&config(false))
+            .configure(
+                r#"{
+                    "proxied_defaults": true,
+                    "edge": {"referrer-policy": "same-origin"},
+                    "proxied": {"x-frame-options": null, "permissions-policy": "camera=()"}
+                }"#,
+            )
+            .unwrap();
+
+        let mut edge = edge_response();
+        headers.apply(&mut edge);
+        let mut proxied = proxied_response(&[]);
+        headers.apply(&mut proxied);
+
+        assert_eq!(edge.headers()[REFERRER_POLICY], "same-origin");
+        assert!(proxied.headers().get(X_FRAME_OPTIONS).is_none());
+        assert_eq!(proxied.headers()["permissions-policy"], "camera=()");
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let headers = SecurityHeaders::new(&config(false));
+
+        assert!(matches!(
+            headers
+                .clone()
+                .configure(r#"{"edge": {"not a header": "x"}}"#),
+            Err(SecurityHeadersError::InvalidHeader { .. })
+        ));
+        assert!(matches!(
+            headers.configure(r#"{"edge": ["x-frame-options"]}"#),
+            Err(SecurityHeadersError::InvalidConfig { .. })
+        ));
+    }
+}
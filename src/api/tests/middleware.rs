@@ -1,96 +1,616 @@
 use super::*;
+use crate::api::cors::CorsPolicy;
+use crate::api::csrf::CsrfPolicy;
+use crate::session_storage::cookie::CookieStore;
 use axum::http::Method;
+use base64::Engine;
 
 #[tokio::test]
 async fn test_auth_middleware_rejects_invalid_token() {
-    let app = test_app().await;
-    
-    let (status, _) = make_request(
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, headers) = make_request_with_response_headers(
         app,
         Method::GET,
         "/protected",
         None,
-        Some(vec![("Authorization".to_string(), "Bearer invalid-token".to_string())]),
-    ).await;
-    
-    // Should redirect to login or return unauthorized
-    assert!(status == StatusCode::UNAUTHORIZED || status == StatusCode::FOUND);
+        Some(vec![(
+            "Authorization".to_string(),
+            "Bearer invalid-token".to_string(),
+        )]),
+    )
+    .await;
+
+    // Inactive tokens are sent back to the login
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
 }
 
 #[tokio::test]
 async fn test_auth_middleware_accepts_valid_token() {
-    let app = test_app().await;
-    
-    // Create a valid token for testing
-    let token = create_test_token();
-    
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let token = valid_token(&provider);
+
     let (status, _) = make_request(
         app,
         Method::GET,
         "/protected",
         None,
-        Some(vec![("Authorization".to_string(), format!("Bearer {}", token))]),
-    ).await;
-    
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", token),
+        )]),
+    )
+    .await;
+
     assert_eq!(status, StatusCode::OK);
 }
 
 #[tokio::test]
 async fn test_session_middleware_creates_session() {
-    let app = test_app().await;
-    
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, headers) =
+        make_request_with_response_headers(app, Method::GET, "/login", None, None).await;
+
+    assert_eq!(status, StatusCode::OK);
+
+    // Check that a session cookie was set
+    assert!(session_cookie(&headers).is_some());
+}
+
+#[tokio::test]
+async fn test_error_handling_middleware_redirects_to_login() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    // A non-bearer authorization header makes the guard fail with "invalid schema"
     let (status, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/protected",
+        None,
+        Some(vec![(
+            "Authorization".to_string(),
+            "Basic dXNlcjpwYXNz".to_string(),
+        )]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+}
+
+#[tokio::test]
+async fn test_error_handling_middleware_redirects_when_idp_is_down() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+    let token = valid_token(&provider);
+    provider.set_unavailable(true);
+
+    let (status, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/protected",
+        None,
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", token),
+        )]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+}
+
+#[tokio::test]
+async fn test_cors_middleware() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (_, headers) = make_request_with_response_headers(
         app,
         Method::GET,
         "/login",
         None,
+        Some(vec![(
+            "Origin".to_string(),
+            "http://example.com".to_string(),
+        )]),
+    )
+    .await;
+
+    // No origin is allowed by default
+    assert!(header_value(&headers, "access-control-allow-origin").is_none());
+}
+
+async fn cors_app(provider: &MockOidcProvider) -> Router {
+    let cors_policy = CorsPolicy::from_json(
+        r#"{
+            "allowed_origins": ["https://app.example.com"],
+            "allowed_methods": ["GET", "POST"],
+            "allowed_headers": ["content-type"],
+            "allow_credentials": true,
+            "max_age": 600
+        }"#,
+    )
+    .unwrap();
+    let state = test_state(provider, echo_upstream())
+        .await
+        .with_cors(cors_policy.layer().unwrap());
+    create_router(state, Keyring::generate())
+}
+
+#[tokio::test]
+async fn test_cors_allows_listed_origins() {
+    let provider = MockOidcProvider::new();
+    let app = cors_app(&provider).await;
+    let token = valid_token(&provider);
+
+    let (status, headers) = make_request_with_response_headers(
+        app.clone(),
+        Method::GET,
+        "/protected",
+        None,
+        Some(vec![
+            ("Origin".to_string(), "https://app.example.com".to_string()),
+            ("Authorization".to_string(), format!("Bearer {}", token)),
+        ]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        header_value(&headers, "access-control-allow-origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        header_value(&headers, "access-control-allow-credentials"),
+        Some("true")
+    );
+
+    let (_, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/protected",
         None,
-    ).await;
-    
+        Some(vec![
+            ("Origin".to_string(), "https://evil.example.com".to_string()),
+            ("Authorization".to_string(), format!("Bearer {}", token)),
+        ]),
+    )
+    .await;
+    assert!(header_value(&headers, "access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn test_cors_preflight_is_answered_without_authentication() {
+    let provider = MockOidcProvider::new();
+    let app = cors_app(&provider).await;
+
+    let (status, headers) = make_request_with_response_headers(
+        app,
+        Method::OPTIONS,
+        "/protected",
+        None,
+        Some(vec![
+            ("Origin".to_string(), "https://app.example.com".to_string()),
+            (
+                "Access-Control-Request-Method".to_string(),
+                "POST".to_string(),
+            ),
+            (
+                "Access-Control-Request-Headers".to_string(),
+                "content-type".to_string(),
+            ),
+        ]),
+    )
+    .await;
+
+    // not redirected to the login
     assert_eq!(status, StatusCode::OK);
-    
-    // Check that a session cookie was set
-    let has_session_cookie = headers.iter()
-        .any(|(name, value)| name.to_lowercase() == "set-cookie" && value.contains("session="));
-    
-    assert!(has_session_cookie);
+    assert_eq!(
+        header_value(&headers, "access-control-allow-origin"),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        header_value(&headers, "access-control-allow-methods"),
+        Some("GET,POST")
+    );
+    assert_eq!(
+        header_value(&headers, "access-control-allow-headers"),
+        Some("content-type")
+    );
+    assert_eq!(
+        header_value(&headers, "access-control-max-age"),
+        Some("600")
+    );
 }
 
 #[tokio::test]
-async fn test_error_handling_middleware_redirects_to_login() {
-    let app = test_app().await;
-    
-    // Make a request that will trigger an unauthorized error with the specific header
-    let (status, _) = make_request(
+async fn test_login_page_has_strict_security_headers() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let response = send(app, Method::GET, "/login", None, None).await;
+    let headers = response.headers().clone();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+    assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+    assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+    // dev mode
+    assert!(headers.get(header::STRICT_TRANSPORT_SECURITY).is_none());
+
+    // the policy allows exactly the inline script of the page
+    let script = body
+        .split("<script>")
+        .nth(1)
+        .and_then(|rest| rest.split("</script>").next())
+        .unwrap();
+    let hash = base64::engine::general_purpose::STANDARD.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        script.as_bytes(),
+    ));
+    let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    assert!(csp.contains(&format!("script-src 'sha256-{}'", hash)));
+}
+
+#[tokio::test]
+async fn test_login_redirect_is_not_cached() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, headers) =
+        make_request_with_response_headers(app, Method::GET, "/protected", None, None).await;
+
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "cache-control"), Some("no-store"));
+}
+
+#[tokio::test]
+async fn test_proxied_responses_get_opt_in_security_headers() {
+    let provider = MockOidcProvider::new();
+    let token = valid_token(&provider);
+    let authorization = vec![("Authorization".to_string(), format!("Bearer {}", token))];
+
+    let app = test_app(&provider).await;
+    let (_, headers) = make_request_with_response_headers(
         app,
         Method::GET,
         "/protected",
         None,
+        Some(authorization.clone()),
+    )
+    .await;
+    assert_eq!(header_value(&headers, "x-frame-options"), None);
+    assert_eq!(header_value(&headers, "cache-control"), None);
+
+    let state = test_state(&provider, echo_upstream()).await;
+    let security_headers = state.security_headers.clone().with_proxied_defaults();
+    let app = create_router(
+        state.with_security_headers(security_headers),
+        Keyring::generate(),
+    );
+    let (_, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/protected",
+        None,
+        Some(authorization),
+    )
+    .await;
+    assert_eq!(
+        header_value(&headers, "x-frame-options"),
+        Some("SAMEORIGIN")
+    );
+    assert_eq!(
+        header_value(&headers, "x-content-type-options"),
+        Some("nosniff")
+    );
+    // proxied responses keep their own caching
+    assert_eq!(header_value(&headers, "cache-control"), None);
+}
+
+async fn signed_in_app(provider: &MockOidcProvider, csrf_policy: CsrfPolicy) -> (Router, String) {
+    provider.login_as(MockUser::new("user1").with_username("alice"));
+    let state = test_state(provider, echo_upstream())
+        .await
+        .with_csrf_policy(csrf_policy);
+    let app = create_router(state, Keyring::generate());
+    let session = sign_in(&app, provider).await;
+    (app, session)
+}
+
+#[tokio::test]
+async fn test_csrf_rejects_cross_site_posts_with_the_session() {
+    let provider = MockOidcProvider::new();
+    let (app, session) = signed_in_app(&provider, CsrfPolicy::default()).await;
+
+    let (status, _) = make_request(
+        app.clone(),
+        Method::POST,
+        "/items",
+        None,
         Some(vec![
-            ("Authorization".to_string(), "Bearer invalid-token".to_string()),
-            ("X-Introspection-Error".to_string(), "unauthorized".to_string()),
+            ("Cookie".to_string(), session.clone()),
+            ("Origin".to_string(), "https://evil.example".to_string()),
         ]),
-    ).await;
-    
-    // Should redirect to login
-    assert_eq!(status, StatusCode::FOUND);
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = make_request(
+        app,
+        Method::POST,
+        "/items",
+        None,
+        Some(vec![
+            ("Cookie".to_string(), session),
+            ("Origin".to_string(), APP_URL.to_string()),
+        ]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "proxied /items");
 }
 
 #[tokio::test]
-async fn test_cors_middleware() {
-    let app = test_app().await;
-    
+async fn test_csrf_rejects_cross_site_upgrades_with_the_session() {
+    let provider = MockOidcProvider::new();
+    let (app, session) = signed_in_app(&provider, CsrfPolicy::default()).await;
+    let upgrade = |origin: &str| {
+        vec![
+            ("Cookie".to_string(), session.clone()),
+            ("Origin".to_string(), origin.to_string()),
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+            (
+                "Sec-WebSocket-Key".to_string(),
+                "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
+            ),
+        ]
+    };
+
+    let (status, _) = make_request(
+        app.clone(),
+        Method::GET,
+        "/socket",
+        None,
+        Some(upgrade("https://evil.localhost:3000")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) =
+        make_request(app, Method::GET, "/socket", None, Some(upgrade(APP_URL))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "proxied /socket");
+}
+
+#[tokio::test]
+async fn test_csrf_token_from_the_cookie_lets_posts_pass() {
+    let provider = MockOidcProvider::new();
+    let (app, session) = signed_in_app(&provider, CsrfPolicy::default()).await;
+
+    // the page gets its token with the first authenticated request
     let (_, headers) = make_request_with_response_headers(
+        app.clone(),
+        Method::GET,
+        "/",
+        None,
+        Some(vec![("Cookie".to_string(), session.clone())]),
+    )
+    .await;
+    let csrf_cookie = cookie(&headers, "csrf_token").unwrap();
+    let csrf_token = csrf_cookie.trim_start_matches("csrf_token=").to_string();
+
+    let (status, _) = make_request(
+        app.clone(),
+        Method::POST,
+        "/items",
+        None,
+        Some(vec![(
+            "Cookie".to_string(),
+            format!("{}; {}", session, csrf_cookie),
+        )]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = make_request(
         app,
+        Method::DELETE,
+        "/items/1",
+        None,
+        Some(vec![
+            (
+                "Cookie".to_string(),
+                format!("{}; {}", session, csrf_cookie),
+            ),
+            ("X-CSRF-Token".to_string(), csrf_token),
+        ]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_csrf_exempt_paths_and_bearer_tokens_are_not_checked() {
+    let provider = MockOidcProvider::new();
+    let (app, session) = signed_in_app(
+        &provider,
+        CsrfPolicy::from_json(r#"{"exempt_paths": ["/webhooks"]}"#).unwrap(),
+    )
+    .await;
+
+    let (status, _) = make_request(
+        app.clone(),
+        Method::POST,
+        "/webhooks/github",
+        None,
+        Some(vec![("Cookie".to_string(), session)]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = make_request(
+        app,
+        Method::POST,
+        "/items",
+        None,
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", valid_token(&provider)),
+        )]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_sessions_survive_key_rotation() {
+    let provider = MockOidcProvider::new();
+    provider.login_as(MockUser::new("user1").with_username("alice"));
+    let state = test_state(&provider, echo_upstream()).await;
+    let original = Keyring::generate();
+    let session = sign_in(&create_router(state.clone(), original.clone()), &provider).await;
+
+    let mut next = Keyring::generate().active().clone();
+    next.version = 2;
+    let rotated = Keyring::new(vec![original.active().clone(), next.clone()]).unwrap();
+    let (status, headers) = make_request_with_response_headers(
+        create_router(state.clone(), rotated),
+        Method::GET,
+        "/api/whoami",
+        None,
+        Some(vec![("Cookie".to_string(), session.clone())]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // re-issued under the new key, which is all the next rotation keeps
+    let resealed = session_cookie(&headers).unwrap();
+    assert_ne!(resealed, session);
+    let (status, _) = make_request(
+        create_router(state, Keyring::new(vec![next]).unwrap()),
+        Method::GET,
+        "/api/whoami",
+        None,
+        Some(vec![("Cookie".to_string(), resealed)]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// Keeps the cookies set on responses like a browser, as a `Cookie` header.
+fn update_cookie_jar(
+    jar: &mut std::collections::BTreeMap<String, String>,
+    headers: &[(String, String)],
+) {
+    for (_, set_cookie) in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(header::SET_COOKIE.as_str()))
+    {
+        let (name, value) = set_cookie
+            .split(';')
+            .next()
+            .and_then(|pair| pair.split_once('='))
+            .unwrap();
+        if set_cookie.contains("Max-Age=0") {
+            jar.remove(name);
+        } else {
+            jar.insert(name.to_string(), value.to_string());
+        }
+    }
+}
+
+fn cookie_header(jar: &std::collections::BTreeMap<String, String>) -> Vec<(String, String)> {
+    let cookies: Vec<String> = jar
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    vec![("Cookie".to_string(), cookies.join("; "))]
+}
+
+#[tokio::test]
+async fn test_cookie_store_keeps_sessions_in_cookies() {
+    let provider = MockOidcProvider::new();
+    provider.login_as(MockUser::new("user1").with_username("alice"));
+    let keyring = Keyring::generate();
+    // every request gets a fresh store, nothing is kept on the server
+    let (provider, keyring) = (&provider, &keyring);
+    let app = || async move {
+        let state =
+            test_state_with_store(provider, echo_upstream(), CookieStore::new(keyring.clone()))
+                .await;
+        create_router(state, keyring.clone())
+    };
+    let mut jar = std::collections::BTreeMap::new();
+
+    let (_, headers) =
+        make_request_with_response_headers(app().await, Method::GET, "/login", None, None).await;
+    update_cookie_jar(&mut jar, &headers);
+    assert!(jar.contains_key("session_data"));
+
+    let (_, headers) = make_request_with_response_headers(
+        app().await,
+        Method::GET,
+        "/login/authorize",
+        None,
+        Some(cookie_header(&jar)),
+    )
+    .await;
+    update_cookie_jar(&mut jar, &headers);
+    // the pending login is kept next to the session
+    assert!(jar.contains_key("session_data") && jar.contains_key("session_data_1"));
+    let authorize_url = header_value(&headers, "location").unwrap();
+
+    let idp_response = provider
+        .http_client()
+        .execute_request(http::Request::get(authorize_url).body(Vec::new()).unwrap())
+        .await
+        .unwrap();
+    let callback_url = idp_response.headers()[header::LOCATION].to_str().unwrap();
+    let (status, headers) = make_request_with_response_headers(
+        app().await,
         Method::GET,
-        "/public",
-        None,
-        Some(vec![("Origin".to_string(), "http://example.com".to_string())]),
-    ).await;
-    
-    // Check that CORS headers were set
-    let has_cors_headers = headers.iter()
-        .any(|(name, _)| name.to_lowercase() == "access-control-allow-origin");
-    
-    assert!(has_cors_headers);
-}
\ No newline at end of file
+        callback_url.strip_prefix(APP_URL).unwrap(),
+        None,
+        Some(cookie_header(&jar)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FOUND);
+    update_cookie_jar(&mut jar, &headers);
+    assert!(!jar.contains_key("session_data_1"));
+
+    let (status, body) = make_request(
+        app().await,
+        Method::GET,
+        "/api/whoami",
+        None,
+        Some(cookie_header(&jar)),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let user: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(user["user_id"], "user1");
+
+    // without the data cookie the session is gone
+    jar.remove("session_data");
+    let (status, _) = make_request(
+        app().await,
+        Method::GET,
+        "/api/whoami",
+        None,
+        Some(cookie_header(&jar)),
+    )
+    .await;
+    assert_ne!(status, StatusCode::OK);
+}
@@ -1,20 +1,78 @@
+#![allow(clippy::all)]
+
 use axum::{
     body::Body,
-    http::{Request, StatusCode},
+    extract::Request,
+    http::{header, StatusCode, Uri},
+    response::Response,
     Router,
 };
+use http_body_util::BodyExt;
 use tower::ServiceExt;
 
-// Import your API router
-use crate::api::router;
+use crate::api::router::{create_router, AppConfig, AppState};
+use crate::api::upstream::{RouterUpstream, Upstream};
+use crate::axum_introspector::introspection::IntrospectionStateBuilder;
+use crate::keyring::Keyring;
+use crate::oidc::introspection::cache::in_memory::InMemoryIntrospectionCache;
+use crate::session_storage::in_memory::MemoryStore;
+use crate::test_support::{MockOidcProvider, MockUser};
+
+const APP_URL: &str = "http://localhost:3000";
+
+/// Stands in for `PROXY_TARGET`, answering with the path it was asked for.
+fn echo_upstream() -> RouterUpstream {
+    RouterUpstream::new(
+        Router::new().fallback(|uri: Uri| async move { format!("proxied {}", uri.path()) }),
+    )
+}
+
+// The production router, wired to in-memory stores and the mock IdP
+async fn test_app(provider: &MockOidcProvider) -> Router {
+    test_app_with_upstream(provider, echo_upstream()).await
+}
+
+async fn test_app_with_upstream<U>(provider: &MockOidcProvider, upstream: U) -> Router
+where
+    U: Upstream + Clone + 'static,
+{
+    let state = test_state(provider, upstream).await;
+    create_router(state, Keyring::generate())
+}
+
+async fn test_state<U>(provider: &MockOidcProvider, upstream: U) -> AppState<MemoryStore, U> {
+    test_state_with_store(provider, upstream, MemoryStore::default()).await
+}
+
+async fn test_state_with_store<S, U>(
+    provider: &MockOidcProvider,
+    upstream: U,
+    session_store: S,
+) -> AppState<S, U> {
+    let config = AppConfig {
+        auth_server_url: provider.issuer().to_string(),
+        client_id: "test-client-id".to_string(),
+        client_secret: "test-client-secret".to_string(),
+        app_url: APP_URL.to_string(),
+        org_id: None,
+        project_id: None,
+        dev_mode: true,
+    };
 
-// Helper function to create a test app
-async fn test_app() -> Router {
-    // Create a test configuration
-    let config = TestConfig::default();
-    
-    // Create the router with test configuration
-    router::create_router(config).await
+    let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
+        .with_basic_auth(&config.client_id, &config.client_secret)
+        .with_introspection_cache(InMemoryIntrospectionCache::new())
+        .with_http_client(provider.http_client())
+        .build()
+        .await
+        .unwrap();
+
+    AppState::new(config, introspection_state, session_store, upstream)
+        .with_http_client(provider.http_client())
+}
+
+fn valid_token(provider: &MockOidcProvider) -> String {
+    provider.issue_token(MockUser::new("user1").with_username("alice"))
 }
 
 // Helper function to make a test request
@@ -25,37 +83,15 @@ async fn make_request(
     body: Option<String>,
     headers: Option<Vec<(String, String)>>,
 ) -> (StatusCode, String) {
-    let mut req_builder = Request::builder()
-        .method(method)
-        .uri(uri);
-    
-    // Add headers if provided
-    if let Some(headers) = headers {
-        for (name, value) in headers {
-            req_builder = req_builder.header(name, value);
-        }
-    }
-    
-    // Add body if provided
-    let body = match body {
-        Some(b) => Body::from(b),
-        None => Body::empty(),
-    };
-    
-    let req = req_builder.body(Body::from(body)).unwrap();
-    
-    // Process the request
-    let response = app.oneshot(req).await.unwrap();
-    
+    let response = send(app, method, uri, body, headers).await;
+
     // Extract status code
     let status = response.status();
-    
+
     // Extract body
-    let body = hyper::body::to_bytes(response.into_body())
-        .await
-        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
     let body = String::from_utf8(body.to_vec()).unwrap();
-    
+
     (status, body)
 }
 
@@ -67,60 +103,109 @@ async fn make_request_with_response_headers(
     body: Option<String>,
     headers: Option<Vec<(String, String)>>,
 ) -> (StatusCode, Vec<(String, String)>) {
+    let response = send(app, method, uri, body, headers).await;
+
+    // Extract status code
+    let status = response.status();
+
+    // Extract headers
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
+        .collect();
+
+    (status, headers)
+}
+
+async fn send(
+    app: Router,
+    method: http::Method,
+    uri: &str,
+    body: Option<String>,
+    headers: Option<Vec<(String, String)>>,
+) -> Response {
+    // Workers always see absolute request URLs
     let mut req_builder = Request::builder()
         .method(method)
-        .uri(uri);
-    
+        .uri(format!("{}{}", APP_URL, uri));
+
     // Add headers if provided
     if let Some(headers) = headers {
         for (name, value) in headers {
             req_builder = req_builder.header(name, value);
         }
     }
-    
+
     // Add body if provided
     let body = match body {
         Some(b) => Body::from(b),
         None => Body::empty(),
     };
-    
-    let req = req_builder.body(Body::from(body)).unwrap();
-    
-    // Process the request
-    let response = app.oneshot(req).await.unwrap();
-    
-    // Extract status code
-    let status = response.status();
-    
-    // Extract headers
-    let headers = response.headers().iter()
-        .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
-        .collect();
-    
-    (status, headers)
+
+    let req = req_builder.body(body).unwrap();
+
+    app.oneshot(req).await.unwrap()
 }
 
-// Helper function to create a test token
-fn create_test_token() -> String {
-    // In a real implementation, this would create a valid JWT token
-    // For testing purposes, we can use a placeholder
-    "test-token".to_string()
+/// The `name=value` pair of the session cookie set on a response, ready for a `Cookie` header.
+fn session_cookie(headers: &[(String, String)]) -> Option<String> {
+    cookie(headers, "session")
 }
 
-// Helper struct for test configuration
-#[derive(Clone)]
-struct TestConfig {
-    // Add fields as needed for your tests
+/// The `name=value` pair of a cookie set on a response.
+fn cookie(headers: &[(String, String)], cookie_name: &str) -> Option<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(header::SET_COOKIE.as_str()))
+        .filter_map(|(_, value)| value.split(';').next())
+        .find(|pair| pair.starts_with(&format!("{}=", cookie_name)))
+        .map(str::to_string)
 }
 
-impl Default for TestConfig {
-    fn default() -> Self {
-        Self {
-            // Initialize with default values
-        }
-    }
+/// Goes through the login flow as `provider`'s logged in user, returning the session cookie.
+async fn sign_in(app: &Router, provider: &MockOidcProvider) -> String {
+    let (_, headers) =
+        make_request_with_response_headers(app.clone(), http::Method::GET, "/login", None, None)
+            .await;
+    let cookie = session_cookie(&headers).unwrap();
+
+    let (_, headers) = make_request_with_response_headers(
+        app.clone(),
+        http::Method::GET,
+        "/login/authorize",
+        None,
+        Some(vec![("Cookie".to_string(), cookie.clone())]),
+    )
+    .await;
+    let authorize_url = header_value(&headers, "location").unwrap();
+
+    let idp_response = provider
+        .http_client()
+        .execute_request(http::Request::get(authorize_url).body(Vec::new()).unwrap())
+        .await
+        .unwrap();
+    let callback_url = idp_response.headers()[header::LOCATION].to_str().unwrap();
+
+    let (_, headers) = make_request_with_response_headers(
+        app.clone(),
+        http::Method::GET,
+        callback_url.strip_prefix(APP_URL).unwrap(),
+        None,
+        Some(vec![("Cookie".to_string(), cookie.clone())]),
+    )
+    .await;
+    session_cookie(&headers).unwrap_or(cookie)
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
 }
 
 // Export the test modules
+pub mod middleware;
 pub mod routes;
-pub mod middleware;
\ No newline at end of file
+pub mod streaming;
@@ -1,87 +1,329 @@
 use super::*;
+use crate::api::response_transforms::ResponseTransforms;
 use axum::http::Method;
+use axum::routing::get;
 
 #[tokio::test]
-async fn test_public_route_accessible() {
-    let app = test_app().await;
-    
+async fn test_protected_route_requires_auth() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, headers) =
+        make_request_with_response_headers(app, Method::GET, "/protected", None, None).await;
+
+    // Should redirect to login
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+}
+
+#[tokio::test]
+async fn test_protected_route_with_valid_token() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let token = valid_token(&provider);
+
     let (status, body) = make_request(
         app,
         Method::GET,
-        "/public",
+        "/protected",
         None,
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", token),
+        )]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "proxied /protected");
+}
+
+#[tokio::test]
+async fn test_root_is_proxied() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let token = valid_token(&provider);
+
+    let (status, body) = make_request(
+        app,
+        Method::GET,
+        "/",
         None,
-    ).await;
-    
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", token),
+        )]),
+    )
+    .await;
+
     assert_eq!(status, StatusCode::OK);
-    assert_eq!(body, "public route");
+    assert_eq!(body, "proxied /");
 }
 
 #[tokio::test]
-async fn test_protected_route_requires_auth() {
-    let app = test_app().await;
-    
-    let (status, _) = make_request(
+async fn test_login_page_accessible() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, body) = make_request(app, Method::GET, "/login", None, None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"action="/login/authorize""#));
+}
+
+#[tokio::test]
+async fn test_whoami_endpoint() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let token = valid_token(&provider);
+
+    let (status, body) = make_request(
         app,
         Method::GET,
-        "/protected",
+        "/api/whoami",
         None,
+        Some(vec![(
+            "Authorization".to_string(),
+            format!("Bearer {}", token),
+        )]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    let user: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(user["user_id"], "user1");
+    assert_eq!(user["username"], "alice");
+}
+
+#[tokio::test]
+async fn test_authorize_redirects_to_idp_with_pkce() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (_, headers) =
+        make_request_with_response_headers(app.clone(), Method::GET, "/login", None, None).await;
+    let cookie = session_cookie(&headers).unwrap();
+
+    let (status, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/login/authorize",
         None,
-    ).await;
-    
-    // Should redirect to login or return unauthorized
-    assert!(status == StatusCode::UNAUTHORIZED || status == StatusCode::FOUND);
+        Some(vec![("Cookie".to_string(), cookie)]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::FOUND);
+    let location = header_value(&headers, "location").unwrap();
+    assert!(location.starts_with(&provider.endpoint("/oauth/v2/authorize")));
+    assert!(location.contains("code_challenge_method=S256"));
+    assert!(location.contains("state="));
 }
 
 #[tokio::test]
-async fn test_protected_route_with_valid_token() {
-    let app = test_app().await;
-    
-    // Create a valid token for testing
-    let token = create_test_token();
-    
+async fn test_login_flow_signs_the_session_in() {
+    let provider = MockOidcProvider::new();
+    provider.login_as(MockUser::new("user1").with_username("alice"));
+    let app = test_app(&provider).await;
+
+    let cookie = sign_in(&app, &provider).await;
+
+    // The session alone now authenticates
     let (status, body) = make_request(
         app,
         Method::GET,
-        "/protected",
+        "/api/whoami",
         None,
-        Some(vec![("Authorization".to_string(), format!("Bearer {}", token))]),
-    ).await;
-    
+        Some(vec![("Cookie".to_string(), cookie)]),
+    )
+    .await;
+
     assert_eq!(status, StatusCode::OK);
-    assert_eq!(body, "protected route");
+    let user: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(user["user_id"], "user1");
 }
 
 #[tokio::test]
-async fn test_login_page_accessible() {
-    let app = test_app().await;
-    
+async fn test_callback_rejects_unknown_state() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (_, headers) =
+        make_request_with_response_headers(app.clone(), Method::GET, "/login", None, None).await;
+    let cookie = session_cookie(&headers).unwrap();
+
     let (status, _) = make_request(
         app,
         Method::GET,
-        "/login",
+        "/login/callback?code=some-code&state=not-a-pending-login",
+        None,
+        Some(vec![("Cookie".to_string(), cookie)]),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_logout_ends_the_session() {
+    let provider = MockOidcProvider::new();
+    provider.login_as(MockUser::new("user1").with_username("alice"));
+    let app = test_app(&provider).await;
+    let cookie = sign_in(&app, &provider).await;
+
+    // A page on another site cannot log the user out
+    let (status, _) = make_request(
+        app.clone(),
+        Method::POST,
+        "/logout",
+        None,
+        Some(vec![
+            ("Cookie".to_string(), cookie.clone()),
+            ("Origin".to_string(), "https://evil.example".to_string()),
+        ]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = make_request(
+        app.clone(),
+        Method::GET,
+        "/logout",
         None,
+        Some(vec![("Cookie".to_string(), cookie.clone())]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, _) = make_request(
+        app.clone(),
+        Method::GET,
+        "/api/whoami",
         None,
-    ).await;
-    
+        Some(vec![("Cookie".to_string(), cookie.clone())]),
+    )
+    .await;
     assert_eq!(status, StatusCode::OK);
+
+    let (status, headers) = make_request_with_response_headers(
+        app.clone(),
+        Method::POST,
+        "/logout",
+        None,
+        Some(vec![
+            ("Cookie".to_string(), cookie.clone()),
+            ("Origin".to_string(), APP_URL.to_string()),
+        ]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+
+    // The old cookie no longer leads to a session
+    let (status, headers) = make_request_with_response_headers(
+        app,
+        Method::GET,
+        "/api/whoami",
+        None,
+        Some(vec![("Cookie".to_string(), cookie)]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+}
+
+fn websocket_headers() -> Vec<(String, String)> {
+    vec![
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Connection".to_string(), "Upgrade".to_string()),
+        ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+        (
+            "Sec-WebSocket-Key".to_string(),
+            "dGhlIHNhbXBsZSBub25jZQ==".to_string(),
+        ),
+    ]
 }
 
 #[tokio::test]
-async fn test_whoami_endpoint() {
-    let app = test_app().await;
-    
-    // Create a valid token for testing
-    let token = create_test_token();
-    
-    let (status, body) = make_request(
+async fn test_websocket_upgrade_requires_auth() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+
+    let (status, headers) = make_request_with_response_headers(
         app,
         Method::GET,
-        "/api/whoami",
+        "/socket",
         None,
-        Some(vec![("Authorization".to_string(), format!("Bearer {}", token))]),
-    ).await;
-    
+        Some(websocket_headers()),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+}
+
+#[tokio::test]
+async fn test_websocket_upgrade_is_forwarded() {
+    let provider = MockOidcProvider::new();
+    let app = test_app(&provider).await;
+    let token = valid_token(&provider);
+
+    let mut headers = websocket_headers();
+    headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+
+    let (status, body) = make_request(app, Method::GET, "/socket", None, Some(headers)).await;
+
     assert_eq!(status, StatusCode::OK);
-    assert_eq!(body, "test user");
-}
\ No newline at end of file
+    assert_eq!(body, "proxied /socket");
+}
+
+#[tokio::test]
+async fn test_upstream_responses_are_transformed() {
+    let provider = MockOidcProvider::new();
+    let upstream = RouterUpstream::new(
+        Router::new()
+            .route("/expired", get(|| async { StatusCode::UNAUTHORIZED }))
+            .route(
+                "/moved",
+                get(|| async {
+                    (
+                        StatusCode::FOUND,
+                        [("location", "http://backend.internal/elsewhere")],
+                    )
+                }),
+            ),
+    );
+    let transforms = ResponseTransforms::from_json(
+        r#"[
+            {"rewrite_location": {"from": "http://backend.internal", "to": ""}},
+            {"login_on_status": 401}
+        ]"#,
+    )
+    .unwrap();
+    let state = test_state(&provider, upstream)
+        .await
+        .with_response_transforms(transforms);
+    let app = create_router(state, Keyring::generate());
+    let authorization = vec![(
+        "Authorization".to_string(),
+        format!("Bearer {}", valid_token(&provider)),
+    )];
+
+    let (status, headers) = make_request_with_response_headers(
+        app.clone(),
+        Method::GET,
+        "/expired",
+        None,
+        Some(authorization.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&headers, "location"), Some("/login"));
+
+    let (status, headers) =
+        make_request_with_response_headers(app, Method::GET, "/moved", None, Some(authorization))
+            .await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(header_value(&headers, "location"), Some("/elsewhere"));
+}
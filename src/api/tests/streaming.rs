@@ -0,0 +1,262 @@
+use super::*;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::api::upstream::OriginUpstream;
+use crate::http_client::{HttpTransport, TransportError, TransportRequest, TransportResponse};
+
+const MIB: usize = 1024 * 1024;
+
+/// Buffering anywhere on the way would wait for the end of a body that never comes.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A body whose chunks are sent by the test, so it stays open until the sender is dropped.
+fn channel_body() -> (mpsc::Sender<Vec<u8>>, Body) {
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>(4);
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|chunk| (Ok::<_, Infallible>(chunk), receiver))
+    });
+    (sender, Body::from_stream(stream))
+}
+
+fn authorized(
+    provider: &MockOidcProvider,
+    builder: http::request::Builder,
+) -> http::request::Builder {
+    builder.header(
+        header::AUTHORIZATION,
+        format!("Bearer {}", valid_token(provider)),
+    )
+}
+
+#[tokio::test]
+async fn test_large_upload_keeps_content_length() {
+    let provider = MockOidcProvider::new();
+    let upstream = RouterUpstream::new(Router::new().fallback(|request: Request| async move {
+        let content_length = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let body = request.into_body().collect().await.unwrap().to_bytes();
+        format!("{} {}", content_length, body.len())
+    }));
+    let app = test_app_with_upstream(&provider, upstream).await;
+
+    let request = authorized(&provider, Request::post(format!("{}/upload", APP_URL)))
+        .header(header::CONTENT_LENGTH, 5 * MIB)
+        .body(Body::from(vec![7u8; 5 * MIB]))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, format!("{} {}", 5 * MIB, 5 * MIB));
+}
+
+#[tokio::test]
+async fn test_large_download_keeps_content_length() {
+    let provider = MockOidcProvider::new();
+    let upstream = RouterUpstream::new(Router::new().fallback(|| async { vec![1u8; 3 * MIB] }));
+    let app = test_app_with_upstream(&provider, upstream).await;
+
+    let request = authorized(&provider, Request::get(format!("{}/download", APP_URL)))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(header::CONTENT_LENGTH).unwrap(),
+        &(3 * MIB).to_string()
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), 3 * MIB);
+}
+
+#[tokio::test]
+async fn test_streamed_download_stays_chunked() {
+    let provider = MockOidcProvider::new();
+    let upstream = RouterUpstream::new(Router::new().fallback(|| async {
+        let chunks =
+            futures_util::stream::iter((0..8).map(|_| Ok::<_, Infallible>(vec![2u8; MIB])));
+        Body::from_stream(chunks)
+    }));
+    let app = test_app_with_upstream(&provider, upstream).await;
+
+    let request = authorized(&provider, Request::get(format!("{}/download", APP_URL)))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    // the length is unknown up front, so none may be made up on the way
+    assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), 8 * MIB);
+}
+
+#[tokio::test]
+async fn test_request_body_is_not_buffered() {
+    let provider = MockOidcProvider::new();
+    // answers with the first chunk without waiting for the rest of the body
+    let upstream = RouterUpstream::new(Router::new().fallback(|request: Request| async move {
+        let mut chunks = request.into_body().into_data_stream();
+        let first = chunks.next().await.unwrap().unwrap();
+        format!("first chunk of {} bytes", first.len())
+    }));
+    let app = test_app_with_upstream(&provider, upstream).await;
+
+    let (sender, body) = channel_body();
+    sender.send(vec![3u8; MIB]).await.unwrap();
+    let request = authorized(&provider, Request::post(format!("{}/upload", APP_URL)))
+        .header(header::TRANSFER_ENCODING, "chunked")
+        .body(body)
+        .unwrap();
+    let response = tokio::time::timeout(STREAM_TIMEOUT, app.oneshot(request))
+        .await
+        .expect("the upstream should answer while the upload is still open")
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, format!("first chunk of {} bytes", MIB));
+    drop(sender);
+}
+
+#[tokio::test]
+async fn test_response_body_is_not_buffered() {
+    let provider = MockOidcProvider::new();
+    let (sender, body) = channel_body();
+    let body = Arc::new(Mutex::new(Some(body)));
+    let upstream = RouterUpstream::new(Router::new().fallback(move || {
+        let body = body.lock().unwrap().take().unwrap();
+        async move { body }
+    }));
+    let app = test_app_with_upstream(&provider, upstream).await;
+
+    let request = authorized(&provider, Request::get(format!("{}/events", APP_URL)))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    sender.send(vec![4u8; 2 * MIB]).await.unwrap();
+    let mut body = response.into_body();
+    let first = tokio::time::timeout(STREAM_TIMEOUT, body.frame())
+        .await
+        .expect("the first chunk should arrive while the upstream is still sending")
+        .unwrap()
+        .unwrap()
+        .into_data()
+        .unwrap();
+    assert_eq!(first.len(), 2 * MIB);
+
+    drop(sender);
+    assert!(body.frame().await.is_none());
+}
+
+/// Stands in for an origin reached through a custom transport, answering with the
+/// `Content-Length` and size of the body it got, or with `download` bytes for a GET.
+#[derive(Debug)]
+struct MeasuringOrigin {
+    download: usize,
+}
+
+#[async_trait]
+impl HttpTransport for MeasuringOrigin {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        let body = if request.method() == http::Method::GET {
+            vec![5u8; self.download]
+        } else {
+            let content_length = request
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .map(|value| value.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            format!("{} {}", content_length, request.body().len()).into_bytes()
+        };
+        Ok(http::Response::builder()
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(body)
+            .unwrap())
+    }
+}
+
+fn measuring_origin(download: usize) -> OriginUpstream {
+    OriginUpstream::new("https://backend.internal".parse().unwrap())
+        .with_transport(MeasuringOrigin { download })
+}
+
+#[tokio::test]
+async fn test_origin_upload_keeps_content_length() {
+    let provider = MockOidcProvider::new();
+    let app = test_app_with_upstream(&provider, measuring_origin(0)).await;
+
+    let request = authorized(&provider, Request::post(format!("{}/upload", APP_URL)))
+        .header(header::CONTENT_LENGTH, 5 * MIB)
+        .body(Body::from(vec![7u8; 5 * MIB]))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, format!("{} {}", 5 * MIB, 5 * MIB));
+}
+
+#[tokio::test]
+async fn test_origin_download_keeps_content_length() {
+    let provider = MockOidcProvider::new();
+    let app = test_app_with_upstream(&provider, measuring_origin(3 * MIB)).await;
+
+    let request = authorized(&provider, Request::get(format!("{}/download", APP_URL)))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(header::CONTENT_LENGTH).unwrap(),
+        &(3 * MIB).to_string()
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), 3 * MIB);
+}
+
+/// Only service bindings and `fetch` on Workers stream. A custom transport, like the native
+/// default, gets the whole request body, so the origin is not reached before the upload ends.
+#[tokio::test]
+async fn test_origin_transport_buffers_the_request_body() {
+    let provider = MockOidcProvider::new();
+    let app = test_app_with_upstream(&provider, measuring_origin(0)).await;
+
+    let (sender, body) = channel_body();
+    sender.send(vec![3u8; MIB]).await.unwrap();
+    let request = authorized(&provider, Request::post(format!("{}/upload", APP_URL)))
+        .header(header::TRANSFER_ENCODING, "chunked")
+        .body(body)
+        .unwrap();
+    let response = tokio::spawn(app.oneshot(request));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!response.is_finished());
+
+    sender.send(vec![3u8; MIB]).await.unwrap();
+    drop(sender);
+    let response = tokio::time::timeout(STREAM_TIMEOUT, response)
+        .await
+        .expect("the origin should answer once the upload is complete")
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, format!(" {}", 2 * MIB));
+}
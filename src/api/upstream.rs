@@ -0,0 +1,375 @@
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tower::ServiceExt;
+use worker::Fetcher;
+
+use crate::api::router::SESSION_COOKIE;
+use crate::http_client::{
+    default_transport, HttpTransport, TransportError, TransportRequest, TransportResponse,
+};
+use crate::session_storage::cookie::is_data_cookie;
+
+/// Where authenticated requests are proxied to.
+#[async_trait]
+pub trait Upstream: Send + Sync + Debug {
+    async fn forward(&self, request: Request) -> Response;
+}
+
+#[async_trait]
+impl<T: Upstream + ?Sized> Upstream for Arc<T> {
+    async fn forward(&self, request: Request) -> Response {
+        (**self).forward(request).await
+    }
+}
+
+/// Forwards requests to a Workers service binding, without the edge's session cookies.
+#[derive(Clone)]
+pub struct ServiceBindingUpstream {
+    fetcher: Fetcher,
+}
+
+impl ServiceBindingUpstream {
+    pub fn new(fetcher: Fetcher) -> Self {
+        Self { fetcher }
+    }
+}
+
+impl Debug for ServiceBindingUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceBindingUpstream")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Hands the request to the binding as is, so bodies are streamed in both directions
+/// and `Content-Length`/`Transfer-Encoding` are left to the runtime.
+#[worker::send]
+async fn fetch_service_binding(fetcher: Fetcher, request: Request) -> Response {
+    match fetcher.fetch_request(request).await {
+        Ok(response) => response.into_response(),
+        Err(err) => {
+            tracing::warn!("failed to reach the proxy target: {}", err);
+            http::StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+#[async_trait]
+impl Upstream for ServiceBindingUpstream {
+    async fn forward(&self, mut request: Request) -> Response {
+        strip_edge_cookies(request.headers_mut());
+        fetch_service_binding(self.fetcher.clone(), request).await
+    }
+}
+
+/// Forwards requests to an origin over HTTP, for backends that are not Workers.
+/// The path and query of the request are kept, scheme and authority are the origin's.
+///
+/// The edge's session cookies are removed before forwarding, and so is the `Authorization`
+/// header unless the origin is trusted with it.
+///
+/// On Workers bodies are streamed through `fetch`. A custom [`HttpTransport`], and the
+/// native default, exchange whole bodies instead.
+#[derive(Clone, Debug)]
+pub struct OriginUpstream {
+    origin: http::Uri,
+    transport: Option<Arc<dyn HttpTransport>>,
+    forward_authorization: bool,
+}
+
+impl OriginUpstream {
+    pub fn new(origin: http::Uri) -> Self {
+        Self {
+            origin,
+            transport: None,
+            forward_authorization: false,
+        }
+    }
+
+    /// Passes the client's `Authorization` header, e.g. its bearer token, on to the origin.
+    pub fn with_forward_authorization(mut self, forward_authorization: bool) -> Self {
+        self.forward_authorization = forward_authorization;
+        self
+    }
+
+    pub fn with_transport(mut self, transport: impl HttpTransport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    fn target(&self, uri: &http::Uri) -> http::Uri {
+        let origin_path = self.origin.path().trim_end_matches('/');
+        let path_and_query = uri
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or("/");
+
+        let mut parts = self.origin.clone().into_parts();
+        parts.path_and_query = Some(
+            format!("{}{}", origin_path, path_and_query)
+                .parse()
+                .expect("a valid path joined to a valid path is valid"),
+        );
+        http::Uri::from_parts(parts).expect("the origin has a scheme and authority")
+    }
+}
+
+/// Drops the cookies the edge keeps its session in from the `Cookie` headers.
+fn strip_edge_cookies(headers: &mut http::HeaderMap) {
+    let is_edge_cookie = |cookie: &str| {
+        let name = cookie.split('=').next().unwrap_or_default().trim();
+        name == SESSION_COOKIE || is_data_cookie(name)
+    };
+    let cookies: Vec<String> = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty() && !is_edge_cookie(cookie))
+        .map(str::to_string)
+        .collect();
+
+    headers.remove(http::header::COOKIE);
+    if cookies.is_empty() {
+        return;
+    }
+    if let Ok(value) = http::HeaderValue::from_str(&cookies.join("; ")) {
+        headers.insert(http::header::COOKIE, value);
+    }
+}
+
+#[worker::send]
+async fn send_to_origin(
+    transport: Arc<dyn HttpTransport>,
+    request: TransportRequest,
+) -> Result<TransportResponse, TransportError> {
+    transport.send(request).await
+}
+
+async fn forward_buffered(transport: Arc<dyn HttpTransport>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body.to_vec(),
+        Err(_) => return http::StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    match send_to_origin(transport, http::Request::from_parts(parts, body)).await {
+        Ok(response) => response.map(Body::from).into_response(),
+        Err(err) => {
+            tracing::warn!("failed to reach the proxy origin: {}", err);
+            http::StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+/// Passes the request body to `fetch` as a stream and hands back the response body the
+/// same way. Like the transports, it does not follow redirects.
+#[cfg(target_arch = "wasm32")]
+#[worker::send]
+async fn stream_to_origin(request: Request) -> Response {
+    async fn fetch(request: Request) -> worker::Result<worker::HttpResponse> {
+        let request = worker::Request::try_from(request)?;
+
+        let mut init = worker::RequestInit::new();
+        init.with_method(request.method())
+            .with_headers(request.headers().clone())
+            .with_body(request.inner().body().map(Into::into))
+            .with_redirect(worker::RequestRedirect::Manual);
+        let request = worker::Request::new_with_init(&request.url()?.to_string(), &init)?;
+
+        worker::Fetch::Request(request)
+            .send()
+            .await
+            .and_then(worker::HttpResponse::try_from)
+    }
+
+    match fetch(request).await {
+        Ok(response) => response.into_response(),
+        Err(err) => {
+            tracing::warn!("failed to reach the proxy origin: {}", err);
+            http::StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn stream_to_origin(request: Request) -> Response {
+    forward_buffered(default_transport(), request).await
+}
+
+#[async_trait]
+impl Upstream for OriginUpstream {
+    async fn forward(&self, request: Request) -> Response {
+        let (mut parts, body) = request.into_parts();
+        parts.uri = self.target(&parts.uri);
+        // the origin answers for its own host
+        parts.headers.remove(http::header::HOST);
+        strip_edge_cookies(&mut parts.headers);
+        if !self.forward_authorization {
+            parts.headers.remove(http::header::AUTHORIZATION);
+        }
+        let request = Request::from_parts(parts, body);
+
+        match &self.transport {
+            Some(transport) => forward_buffered(transport.clone(), request).await,
+            None => stream_to_origin(request).await,
+        }
+    }
+}
+
+/// Serves proxied requests from an axum router in the same process, e.g. in tests.
+#[derive(Clone, Debug)]
+pub struct RouterUpstream {
+    router: Router,
+}
+
+impl RouterUpstream {
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+}
+
+#[async_trait]
+impl Upstream for RouterUpstream {
+    async fn forward(&self, request: Request) -> Response {
+        match self.router.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(err) => match err {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::routing::get;
+    use http_body_util::BodyExt;
+
+    use crate::http_client::scripted::ScriptedTransport;
+
+    use super::*;
+
+    async fn body_string(response: Response) -> String {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn origin_keeps_path_and_query() {
+        let upstream = OriginUpstream::new("https://backend.internal".parse().unwrap());
+
+        let target = upstream.target(&"https://edge.example.com/a/b?c=d".parse().unwrap());
+
+        assert_eq!(target.to_string(), "https://backend.internal/a/b?c=d");
+    }
+
+    #[test]
+    fn origin_path_is_prefixed() {
+        let upstream = OriginUpstream::new("https://backend.internal/app/".parse().unwrap());
+
+        let target = upstream.target(&"/a?c=d".parse().unwrap());
+
+        assert_eq!(target.to_string(), "https://backend.internal/app/a?c=d");
+    }
+
+    #[tokio::test]
+    async fn origin_forwards_through_the_transport() {
+        let transport = Arc::new(ScriptedTransport::new().respond(201, r#"{"ok":true}"#));
+        let upstream = OriginUpstream {
+            origin: "https://backend.internal".parse().unwrap(),
+            transport: Some(transport.clone()),
+            forward_authorization: false,
+        };
+
+        let response = upstream
+            .forward(
+                Request::post("https://edge.example.com/items?x=1")
+                    .header(http::header::HOST, "edge.example.com")
+                    .body(Body::from("payload"))
+                    .unwrap(),
+            )
+            .await;
+
+        assert_eq!(response.status(), http::StatusCode::CREATED);
+        assert_eq!(body_string(response).await, r#"{"ok":true}"#);
+        assert_eq!(
+            transport.requests(),
+            vec![(
+                http::Method::POST,
+                "https://backend.internal/items?x=1".to_string()
+            )]
+        );
+    }
+
+    async fn forward_with_credentials(forward_authorization: bool) -> http::HeaderMap {
+        let transport = Arc::new(ScriptedTransport::new().respond(200, "{}"));
+        let upstream = OriginUpstream {
+            origin: "https://backend.internal".parse().unwrap(),
+            transport: Some(transport.clone()),
+            forward_authorization,
+        };
+
+        let request = Request::get("https://edge.example.com/")
+            .header(http::header::AUTHORIZATION, "Bearer token")
+            .header(
+                http::header::COOKIE,
+                "session=abc; theme=dark; session_data=sealed; session_data.1=more",
+            )
+            .header(http::header::COOKIE, "session_database=kept")
+            .body(Body::empty())
+            .unwrap();
+        upstream.forward(request).await;
+
+        transport.headers().pop().unwrap()
+    }
+
+    #[tokio::test]
+    async fn origin_does_not_get_the_session_cookies() {
+        let headers = forward_with_credentials(false).await;
+
+        let cookies: Vec<_> = headers.get_all(http::header::COOKIE).iter().collect();
+        assert_eq!(cookies, vec!["theme=dark; session_database=kept"]);
+    }
+
+    #[tokio::test]
+    async fn origin_gets_the_authorization_header_only_when_trusted() {
+        let headers = forward_with_credentials(false).await;
+        assert!(headers.get(http::header::AUTHORIZATION).is_none());
+
+        let headers = forward_with_credentials(true).await;
+        assert_eq!(headers[http::header::AUTHORIZATION], "Bearer token");
+    }
+
+    #[tokio::test]
+    async fn unreachable_origin_is_a_bad_gateway() {
+        let upstream = OriginUpstream::new("https://backend.internal".parse().unwrap())
+            .with_transport(ScriptedTransport::new().fail("connection refused"));
+
+        let response = upstream
+            .forward(Request::get("/").body(Body::empty()).unwrap())
+            .await;
+
+        assert_eq!(response.status(), http::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn router_upstream_serves_in_process() {
+        let upstream: Arc<dyn Upstream> = Arc::new(RouterUpstream::new(
+            Router::new().route("/hello", get(|| async { "hello from the upstream" })),
+        ));
+
+        let response = upstream
+            .forward(Request::get("/hello").body(Body::empty()).unwrap())
+            .await;
+
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(body_string(response).await, "hello from the upstream");
+    }
+}
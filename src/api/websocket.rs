@@ -0,0 +1,328 @@
+use axum::extract::Request;
+use std::future::Future;
+use std::time::Duration;
+
+/// How often proxied WebSocket connections re-check the session they were opened with.
+/// Connections are closed once the session is no longer active.
+#[derive(Clone, Copy, Debug)]
+pub struct WebSocketPolicy {
+    /// Re-check at least this often, even on idle connections.
+    pub revalidate_every: Option<Duration>,
+    /// Re-check before relaying each message from the client.
+    pub revalidate_on_message: bool,
+}
+
+impl Default for WebSocketPolicy {
+    fn default() -> Self {
+        Self {
+            revalidate_every: Some(Duration::from_secs(60)),
+            revalidate_on_message: false,
+        }
+    }
+}
+
+/// Close code sent to the client when its session was revoked (policy violation).
+pub const SESSION_REVOKED_CLOSE_CODE: u16 = 1008;
+
+pub fn is_websocket_upgrade(request: &Request) -> bool {
+    let headers = request.headers();
+    let upgrade = headers
+        .get(http::header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let connection = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("upgrade"));
+
+    upgrade && connection
+}
+
+/// What woke up the relay between client and upstream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RelayEvent {
+    ClientMessage,
+    UpstreamMessage,
+    Tick,
+}
+
+/// Decides when a relayed connection has to re-check its session.
+#[derive(Debug)]
+pub(crate) struct Revalidation {
+    policy: WebSocketPolicy,
+    last_checked_ms: f64,
+}
+
+impl Revalidation {
+    pub(crate) fn new(policy: WebSocketPolicy, now_ms: f64) -> Self {
+        Self {
+            policy,
+            last_checked_ms: now_ms,
+        }
+    }
+
+    pub(crate) fn is_due(&self, event: RelayEvent, now_ms: f64) -> bool {
+        let overdue = self
+            .policy
+            .revalidate_every
+            .is_some_and(|every| now_ms - self.last_checked_ms >= every.as_millis() as f64);
+
+        overdue || (event == RelayEvent::ClientMessage && self.policy.revalidate_on_message)
+    }
+
+    pub(crate) fn checked(&mut self, now_ms: f64) {
+        self.last_checked_ms = now_ms;
+    }
+}
+
+/// Relays an authenticated WebSocket upgrade to the upstream.
+///
+/// On Workers the edge sits in the middle of the connection, so it can re-check the session
+/// with `revalidate` as configured by the policy and close both sides once it was revoked.
+/// Elsewhere the upstream response is returned as is.
+pub(crate) async fn relay<Fut>(
+    upstream_response: axum::response::Response,
+    policy: WebSocketPolicy,
+    revalidate: impl Fn() -> Fut + 'static,
+) -> axum::response::Response
+where
+    Fut: Future<Output = bool> + 'static,
+{
+    #[cfg(target_arch = "wasm32")]
+    return wasm::relay(upstream_response, policy, revalidate);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let _ = (policy, revalidate);
+        upstream_response
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use super::*;
+    use axum::response::{IntoResponse, Response};
+    use futures_util::Stream;
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use std::task::Poll;
+    use worker::{Date, Delay, EventStream, WebSocket, WebSocketPair, WebsocketEvent};
+
+    pub(super) fn relay<Fut>(
+        mut upstream_response: Response,
+        policy: WebSocketPolicy,
+        revalidate: impl Fn() -> Fut + 'static,
+    ) -> Response
+    where
+        Fut: Future<Output = bool> + 'static,
+    {
+        let Some(upstream) = upstream_response.extensions_mut().remove::<WebSocket>() else {
+            // the upstream refused the upgrade
+            return upstream_response;
+        };
+
+        let pair = match WebSocketPair::new() {
+            Ok(pair) => pair,
+            Err(err) => {
+                worker::console_error!("failed to create a WebSocket pair: {:?}", err);
+                return http::StatusCode::BAD_GATEWAY.into_response();
+            }
+        };
+        if let Err(err) = pair.server.accept().and_then(|_| upstream.accept()) {
+            worker::console_error!("failed to accept the WebSocket: {:?}", err);
+            return http::StatusCode::BAD_GATEWAY.into_response();
+        }
+
+        wasm_bindgen_futures::spawn_local(pump(pair.server, upstream, policy, revalidate));
+
+        let mut response = http::Response::builder()
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        response.extensions_mut().insert(pair.client);
+        response
+    }
+
+    enum Next {
+        Client(Option<worker::Result<WebsocketEvent>>),
+        Upstream(Option<worker::Result<WebsocketEvent>>),
+        Tick,
+    }
+
+    async fn next(
+        client: &mut Pin<Box<EventStream<'_>>>,
+        upstream: &mut Pin<Box<EventStream<'_>>>,
+        tick: &mut Pin<Box<Delay>>,
+    ) -> Next {
+        poll_fn(|cx| {
+            if let Poll::Ready(event) = client.as_mut().poll_next(cx) {
+                return Poll::Ready(Next::Client(event));
+            }
+            if let Poll::Ready(event) = upstream.as_mut().poll_next(cx) {
+                return Poll::Ready(Next::Upstream(event));
+            }
+            if tick.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Next::Tick);
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn relay_message(to: &WebSocket, event: &worker::MessageEvent) -> worker::Result<()> {
+        if let Some(text) = event.text() {
+            to.send_with_str(text)
+        } else if let Some(bytes) = event.bytes() {
+            to.send_with_bytes(bytes)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn idle_tick(policy: &WebSocketPolicy) -> Pin<Box<Delay>> {
+        // without periodic checks the tick only keeps the loop simple
+        Box::pin(Delay::from(
+            policy.revalidate_every.unwrap_or(Duration::from_secs(3600)),
+        ))
+    }
+
+    async fn pump<Fut>(
+        client: WebSocket,
+        upstream: WebSocket,
+        policy: WebSocketPolicy,
+        revalidate: impl Fn() -> Fut,
+    ) where
+        Fut: Future<Output = bool>,
+    {
+        let (Ok(client_events), Ok(upstream_events)) = (client.events(), upstream.events()) else {
+            let _ = client.close(Some(1011), Some("relay failed"));
+            let _ = upstream.close(Some(1011), Some("relay failed"));
+            return;
+        };
+        let mut client_events = Box::pin(client_events);
+        let mut upstream_events = Box::pin(upstream_events);
+
+        let mut revalidation = Revalidation::new(policy, Date::now().as_millis() as f64);
+        let mut tick = idle_tick(&policy);
+
+        loop {
+            let next = next(&mut client_events, &mut upstream_events, &mut tick).await;
+
+            let event = match &next {
+                Next::Client(_) => RelayEvent::ClientMessage,
+                Next::Upstream(_) => RelayEvent::UpstreamMessage,
+                Next::Tick => {
+                    tick = idle_tick(&policy);
+                    RelayEvent::Tick
+                }
+            };
+
+            let now = Date::now().as_millis() as f64;
+            if revalidation.is_due(event, now) {
+                if !revalidate().await {
+                    let _ = client.close(Some(SESSION_REVOKED_CLOSE_CODE), Some("session revoked"));
+                    let _ = upstream.close(Some(1000), Some("session revoked"));
+                    return;
+                }
+                revalidation.checked(now);
+            }
+
+            let relayed = match next {
+                Next::Client(Some(Ok(WebsocketEvent::Message(message)))) => {
+                    relay_message(&upstream, &message)
+                }
+                Next::Upstream(Some(Ok(WebsocketEvent::Message(message)))) => {
+                    relay_message(&client, &message)
+                }
+                Next::Client(Some(Ok(WebsocketEvent::Close(close)))) => {
+                    let _ = upstream.close(Some(close.code()), Some(close.reason()));
+                    return;
+                }
+                Next::Upstream(Some(Ok(WebsocketEvent::Close(close)))) => {
+                    let _ = client.close(Some(close.code()), Some(close.reason()));
+                    return;
+                }
+                Next::Client(None) | Next::Upstream(None) => {
+                    let _ = client.close(Some(1000), None::<&str>);
+                    let _ = upstream.close(Some(1000), None::<&str>);
+                    return;
+                }
+                Next::Client(Some(Err(err))) | Next::Upstream(Some(Err(err))) => Err(err),
+                Next::Tick => Ok(()),
+            };
+
+            if let Err(err) = relayed {
+                worker::console_error!("WebSocket relay failed: {:?}", err);
+                let _ = client.close(Some(1011), Some("relay failed"));
+                let _ = upstream.close(Some(1011), Some("relay failed"));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use axum::body::Body;
+
+    use super::*;
+
+    fn upgrade_request(upgrade: &str, connection: &str) -> Request {
+        Request::get("/socket")
+            .header(http::header::UPGRADE, upgrade)
+            .header(http::header::CONNECTION, connection)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn detects_websocket_upgrades() {
+        assert!(is_websocket_upgrade(&upgrade_request(
+            "websocket",
+            "Upgrade"
+        )));
+        assert!(is_websocket_upgrade(&upgrade_request(
+            "WebSocket",
+            "keep-alive, upgrade"
+        )));
+        assert!(!is_websocket_upgrade(&upgrade_request("h2c", "Upgrade")));
+        assert!(!is_websocket_upgrade(&upgrade_request(
+            "websocket",
+            "keep-alive"
+        )));
+        assert!(!is_websocket_upgrade(
+            &Request::get("/socket").body(Body::empty()).unwrap()
+        ));
+    }
+
+    #[test]
+    fn revalidates_periodically() {
+        let mut revalidation = Revalidation::new(WebSocketPolicy::default(), 0.0);
+
+        assert!(!revalidation.is_due(RelayEvent::ClientMessage, 59_000.0));
+        assert!(revalidation.is_due(RelayEvent::UpstreamMessage, 60_000.0));
+        assert!(revalidation.is_due(RelayEvent::Tick, 60_000.0));
+
+        revalidation.checked(60_000.0);
+        assert!(!revalidation.is_due(RelayEvent::Tick, 61_000.0));
+    }
+
+    #[test]
+    fn revalidates_on_client_messages() {
+        let revalidation = Revalidation::new(
+            WebSocketPolicy {
+                revalidate_every: None,
+                revalidate_on_message: true,
+            },
+            0.0,
+        );
+
+        assert!(revalidation.is_due(RelayEvent::ClientMessage, 1.0));
+        assert!(!revalidation.is_due(RelayEvent::UpstreamMessage, 1.0));
+        assert!(!revalidation.is_due(RelayEvent::Tick, 1_000_000.0));
+    }
+}
@@ -1,7 +1,10 @@
+mod resilience;
+mod single_flight;
 mod state;
 mod state_builder;
 mod user;
 
+pub use resilience::{BackgroundTask, BackgroundTasks, DegradedSignal};
 pub use state::IntrospectionState;
 pub use state_builder::{IntrospectionStateBuilder, IntrospectionStateBuilderError};
-pub use user::{IntrospectedUser, IntrospectionGuardError};
+pub use user::{IntrospectedUser, IntrospectionGuardError, OptionalIntrospectedUser};
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use openidconnect::TokenIntrospectionResponse;
+
+use crate::oidc::introspection::cache::token_key;
+use crate::oidc::introspection::ZitadelIntrospectionResponse;
+
+pub type BackgroundTask = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Runs work after the response has been sent, e.g. through `Context::wait_until` on Workers.
+#[derive(Clone)]
+pub struct BackgroundTasks(Arc<dyn Fn(BackgroundTask) + Send + Sync>);
+
+impl BackgroundTasks {
+    pub fn new(spawn: impl Fn(BackgroundTask) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(spawn))
+    }
+
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        (self.0)(Box::pin(task))
+    }
+}
+
+impl Debug for BackgroundTasks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BackgroundTasks")
+    }
+}
+
+/// Request extension flagged when the user was authenticated from a stale introspection
+/// result because the IdP was unavailable. Insert one before the handler runs and check
+/// it on the way out, e.g. to add a response header.
+#[derive(Clone, Debug, Default)]
+pub struct DegradedSignal(Arc<AtomicBool>);
+
+impl DegradedSignal {
+    pub fn is_degraded(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+}
+
+/// Results kept by [`LastKnownGood`] unless configured otherwise.
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+/// How often [`LastKnownGood`] drops results outside of the grace period.
+const PRUNE_INTERVAL_SECONDS: i64 = 60;
+
+/// The last active introspection result fetched from the IdP per token, used as a fallback
+/// while the IdP is unavailable. Unlike the introspection cache it is not bounded by the
+/// cache's `max_ttl`, but a fallback never outlives the token's `exp`.
+pub(crate) struct LastKnownGood {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+}
+
+#[derive(Default)]
+struct Entries {
+    results: HashMap<String, (ZitadelIntrospectionResponse, i64)>,
+    pruned_at: i64,
+}
+
+impl Entries {
+    /// Drops the results outside of `grace` and, if still full, the oldest tenth of them.
+    fn prune(&mut self, grace: time::Duration, now: i64, max_entries: usize) {
+        self.results
+            .retain(|_, (response, fetched_at)| is_usable(response, *fetched_at, grace, now));
+        self.pruned_at = now;
+
+        if self.results.len() < max_entries {
+            return;
+        }
+        let count = (self.results.len() + 1 - max_entries).max(max_entries / 10);
+        let mut by_age: Vec<(i64, String)> = self
+            .results
+            .iter()
+            .map(|(key, (_, fetched_at))| (*fetched_at, key.clone()))
+            .collect();
+        by_age.select_nth_unstable(count - 1);
+        for (_, key) in &by_age[..count] {
+            self.results.remove(key);
+        }
+    }
+}
+
+impl LastKnownGood {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Remembers an active result, or forgets the token if it is no longer active.
+    /// Entries outside of `grace` are pruned once a minute, or sooner when the store is full.
+    pub(crate) fn record(
+        &self,
+        token: &str,
+        response: &ZitadelIntrospectionResponse,
+        grace: time::Duration,
+    ) {
+        let now = chrono::Utc::now().timestamp();
+        let key = token_key(token);
+        let mut entries = self.entries.lock().unwrap();
+
+        if !response.active() {
+            entries.results.remove(&key);
+            return;
+        }
+
+        let full = entries.results.len() >= self.max_entries && !entries.results.contains_key(&key);
+        if full || now - entries.pruned_at >= PRUNE_INTERVAL_SECONDS {
+            entries.prune(grace, now, self.max_entries);
+        }
+        entries.results.insert(key, (response.clone(), now));
+    }
+
+    /// Forgets the token, e.g. when its session ends.
+    pub(crate) fn forget(&self, token: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .results
+            .remove(&token_key(token));
+    }
+
+    /// The last active result for the token if it was fetched within `grace` and the token has not expired.
+    pub(crate) fn get(
+        &self,
+        token: &str,
+        grace: time::Duration,
+    ) -> Option<ZitadelIntrospectionResponse> {
+        let now = chrono::Utc::now().timestamp();
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .results
+            .get(&token_key(token))
+            .filter(|(response, fetched_at)| is_usable(response, *fetched_at, grace, now))
+            .map(|(response, _)| response.clone())
+    }
+}
+
+fn is_usable(
+    response: &ZitadelIntrospectionResponse,
+    fetched_at: i64,
+    grace: time::Duration,
+    now: i64,
+) -> bool {
+    let within_grace = fetched_at + grace.whole_seconds() >= now;
+    let unexpired = response.exp().is_some_and(|exp| exp.timestamp() > now);
+    within_grace && unexpired
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use chrono::{TimeDelta, Utc};
+
+    use super::*;
+
+    const GRACE: time::Duration = time::Duration::minutes(5);
+
+    fn active_response(expires_in: i64) -> ZitadelIntrospectionResponse {
+        let mut response = ZitadelIntrospectionResponse::new(true, Default::default());
+        response.set_exp(Some(
+            Utc::now() + TimeDelta::try_seconds(expires_in).unwrap(),
+        ));
+        response
+    }
+
+    #[test]
+    fn returns_recorded_result_within_grace() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(600), GRACE);
+
+        assert!(store.get("token1", GRACE).is_some());
+        assert!(store.get("token2", GRACE).is_none());
+    }
+
+    #[test]
+    fn ignores_results_outside_of_grace() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(600), GRACE);
+
+        assert!(store.get("token1", time::Duration::seconds(-1)).is_none());
+    }
+
+    #[test]
+    fn never_outlives_the_token() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(-10), GRACE);
+
+        assert!(store.get("token1", GRACE).is_none());
+    }
+
+    #[test]
+    fn inactive_result_forgets_the_token() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(600), GRACE);
+        store.record(
+            "token1",
+            &ZitadelIntrospectionResponse::new(false, Default::default()),
+            GRACE,
+        );
+
+        assert!(store.get("token1", GRACE).is_none());
+    }
+
+    #[test]
+    fn holds_at_most_max_entries() {
+        let mut store = LastKnownGood::new();
+        store.max_entries = 20;
+
+        for i in 0..100 {
+            store.record(&format!("token{}", i), &active_response(600), GRACE);
+        }
+
+        assert!(store.entries.lock().unwrap().results.len() <= 20);
+        assert!(store.get("token99", GRACE).is_some());
+    }
+
+    #[test]
+    fn prunes_results_outside_of_grace() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(-10), GRACE);
+        store.entries.lock().unwrap().pruned_at = 0;
+        store.record("token2", &active_response(600), GRACE);
+
+        let entries = store.entries.lock().unwrap();
+        assert_eq!(entries.results.len(), 1);
+        assert!(entries.results.contains_key(&token_key("token2")));
+    }
+
+    #[test]
+    fn forgets_the_token() {
+        let store = LastKnownGood::new();
+
+        store.record("token1", &active_response(600), GRACE);
+        store.forget("token1");
+
+        assert!(store.get("token1", GRACE).is_none());
+    }
+
+    #[test]
+    fn degraded_signal_is_shared_between_clones() {
+        let signal = DegradedSignal::default();
+
+        signal.clone().mark();
+
+        assert!(signal.is_degraded());
+    }
+}
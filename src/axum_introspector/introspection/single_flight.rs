@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// Deduplicates concurrent work by key: callers arriving while a call for the same key is
+/// in flight wait for it and share its result instead of starting their own.
+///
+/// Nothing is memoized, the key is released as soon as the in-flight call completes.
+pub(crate) struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) async fn run<F, Fut>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+
+        // If the caller running `f` is dropped, the next waiter takes over.
+        let value = cell.get_or_init(f).await.clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(key);
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_flight() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+
+        let call = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            "result".to_string()
+        };
+
+        let (a, b, c) = tokio::join!(
+            flight.run("token", call),
+            flight.run("token", call),
+            flight.run("token", call)
+        );
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            (a.as_str(), b.as_str(), c.as_str()),
+            ("result", "result", "result")
+        );
+    }
+
+    #[tokio::test]
+    async fn different_keys_do_not_share() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+
+        let call = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+        };
+
+        tokio::join!(flight.run("token1", call), flight.run("token2", call));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn completed_flights_are_not_memoized() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+
+        let call = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+        };
+
+        flight.run("token", call).await;
+        flight.run("token", call).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -1,9 +1,12 @@
 use openidconnect::IntrospectionUrl;
 use std::sync::Arc;
 
-use crate::oidc::introspection::cache::IntrospectionCache;
+use crate::oidc::introspection::cache::{CachePolicy, IntrospectionCache};
+use crate::http_client::HttpClient;
 use crate::oidc::introspection::AuthorityAuthentication;
 
+use super::resilience::BackgroundTasks;
+
 #[derive(Clone, Debug)]
 pub struct IntrospectionState {
     pub(crate) config: Arc<IntrospectionConfig>,
@@ -14,5 +17,9 @@ pub(crate) struct IntrospectionConfig {
     pub(crate) authority: String,
     pub(crate) authentication: AuthorityAuthentication,
     pub(crate) introspection_uri: IntrospectionUrl,
+    pub(crate) http_client: HttpClient,
     pub(crate) cache: Option<Box<dyn IntrospectionCache>>,
+    pub(crate) cache_policy: CachePolicy,
+    pub(crate) stale_if_error: Option<time::Duration>,
+    pub(crate) background_tasks: Option<BackgroundTasks>,
 }
@@ -3,11 +3,13 @@ use std::sync::Arc;
 
 use crate::axum_introspector::introspection::state::IntrospectionConfig;
 use crate::credentials::Application;
-use crate::oidc::discovery::{discover, DiscoveryError};
+use crate::http_client::HttpClient;
+use crate::oidc::discovery::{discover_with, DiscoveryError};
 use crate::oidc::introspection::AuthorityAuthentication;
 
-use crate::oidc::introspection::cache::IntrospectionCache;
+use crate::oidc::introspection::cache::{CachePolicy, IntrospectionCache};
 
+use super::resilience::BackgroundTasks;
 use super::state::IntrospectionState;
 
 custom_error! {
@@ -20,7 +22,11 @@ custom_error! {
 pub struct IntrospectionStateBuilder {
     authority: String,
     authentication: Option<AuthorityAuthentication>,
+    http_client: HttpClient,
     cache: Option<Box<dyn IntrospectionCache>>,
+    cache_policy: CachePolicy,
+    stale_if_error: Option<time::Duration>,
+    background_tasks: Option<BackgroundTasks>,
 }
 
 impl IntrospectionStateBuilder {
@@ -28,7 +34,11 @@ impl IntrospectionStateBuilder {
         Self {
             authority: authority.to_string(),
             authentication: None,
+            http_client: HttpClient::default(),
             cache: None,
+            cache_policy: CachePolicy::default(),
+            stale_if_error: None,
+            background_tasks: None,
         }
     }
 
@@ -51,6 +61,13 @@ impl IntrospectionStateBuilder {
         self
     }
     
+    /// Replaces the default timeouts, retries and circuit breaker used for discovery and introspection.
+    pub fn with_http_client(&mut self, http_client: HttpClient) -> &mut IntrospectionStateBuilder {
+        self.http_client = http_client;
+
+        self
+    }
+
     pub fn with_introspection_cache(
         &mut self,
         cache: impl IntrospectionCache + 'static,
@@ -60,12 +77,47 @@ impl IntrospectionStateBuilder {
         self
     }
 
+    /// Re-introspects cached results after `max_ttl` even if the token is valid for longer,
+    /// bounding how long a revoked token is still accepted at the edge.
+    pub fn with_max_cache_ttl(&mut self, max_ttl: time::Duration) -> &mut IntrospectionStateBuilder {
+        self.cache_policy.max_ttl = Some(max_ttl);
+
+        self
+    }
+
+    /// Treats cached results as expired `leeway` before their `exp` to tolerate clock skew.
+    pub fn with_cache_leeway(&mut self, leeway: time::Duration) -> &mut IntrospectionStateBuilder {
+        self.cache_policy.leeway = leeway;
+
+        self
+    }
+
+    /// Keeps authenticating users from their last active introspection result for up to `grace`
+    /// after it was fetched while the IdP times out or fails with 5xx, instead of rejecting them.
+    /// Such requests are flagged through [`DegradedSignal`](super::DegradedSignal).
+    pub fn with_stale_if_error(&mut self, grace: time::Duration) -> &mut IntrospectionStateBuilder {
+        self.stale_if_error = Some(grace);
+
+        self
+    }
+
+    /// Where to re-introspect after a stale result was served, so the cache is refreshed as soon
+    /// as the IdP is back without holding up the response.
+    pub fn with_background_tasks(
+        &mut self,
+        background_tasks: BackgroundTasks,
+    ) -> &mut IntrospectionStateBuilder {
+        self.background_tasks = Some(background_tasks);
+
+        self
+    }
+
     pub async fn build(&mut self) -> Result<IntrospectionState, IntrospectionStateBuilderError> {
         if self.authentication.is_none() {
             return Err(IntrospectionStateBuilderError::NoAuthSchema);
         }
 
-        let metadata = discover(&self.authority)
+        let metadata = discover_with(&self.http_client, &self.authority)
             .await
             .map_err(|source| IntrospectionStateBuilderError::Discovery { source })?;
 
@@ -82,9 +134,13 @@ impl IntrospectionStateBuilder {
             config: Arc::new(IntrospectionConfig {
                 authority: self.authority.clone(),
                 introspection_uri: introspection_uri.unwrap(),
+                http_client: self.http_client.clone(),
                 authentication: self.authentication.as_ref().unwrap().clone(),
                 // #[cfg(feature = "introspection_cache")]
                 cache: self.cache.take(),
+                cache_policy: self.cache_policy,
+                stale_if_error: self.stale_if_error,
+                background_tasks: self.background_tasks.clone(),
             }),
         })
     }
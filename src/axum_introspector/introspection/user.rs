@@ -9,11 +9,16 @@ use serde::Serialize;
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use worker::console_log;
 
 use crate::axum_introspector::introspection::IntrospectionState;
-use crate::oidc::introspection::{introspect, IntrospectionError, ZitadelIntrospectionResponse};
+use crate::oidc::introspection::cache::token_key;
+use crate::oidc::introspection::{introspect_with, IntrospectionError, ZitadelIntrospectionResponse};
+
+use super::resilience::{DegradedSignal, LastKnownGood};
+use super::single_flight::SingleFlight;
+use super::state::IntrospectionConfig;
 
 custom_error! {
     pub IntrospectionGuardError
@@ -21,7 +26,7 @@ custom_error! {
         Unauthorized = "no HTTP authorization header found",
         InvalidHeader = "authorization header is invalid",
         WrongScheme = "Authorization header is not a bearer token",
-        Introspection{source: IntrospectionError} = "introspection returned an error: {source}",
+        Introspection{source: Arc<IntrospectionError>} = "introspection returned an error: {source}",
         Inactive = "access token is inactive",
         NoUserId = "introspection result contained no user id",
 }
@@ -123,99 +128,233 @@ where
     type Rejection = IntrospectionGuardError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let mut parts_clone = parts.clone();
+        let token = Self::token_from_parts(parts, state)
+            .await?
+            .ok_or(IntrospectionGuardError::Unauthorized)?;
 
-        let session = tower_sessions_core::Session::from_request_parts(&mut parts_clone, state)
-            .await
-            .ok();
+        let introspection_state = IntrospectionState::from_ref(state);
+        let degraded = parts.extensions.get::<DegradedSignal>().cloned();
 
-        let unwrapped_session = session.unwrap();
+        wrap_future(Self::introspect_token(introspection_state, token, degraded)).await
+    }
+}
 
-        let _ = unwrapped_session.load().await.unwrap();
+/// Extractor for routes that serve both anonymous and authenticated users.
+///
+/// Resolves to `None` when the request carries no usable credentials or the token
+/// is inactive, but still rejects when the introspection backend itself fails.
+#[derive(Debug)]
+pub struct OptionalIntrospectedUser(pub Option<IntrospectedUser>);
 
-        let token = if let Some(tok) = unwrapped_session.get::<String>("token").await.ok() {
-            if !tok.is_none() {
-                Some(tok.unwrap())
-            } else {
-                None
-            }
-        } else {
-            let token_from_header = Self::token_from_header(parts)?;
-            
-            Some(token_from_header)
+#[async_trait]
+impl<S> FromRequestParts<S> for OptionalIntrospectedUser
+where
+    S: 'static + Sync,
+    IntrospectionState: FromRef<S>,
+    tower_sessions_core::Session: FromRequestParts<S>,
+{
+    type Rejection = IntrospectionGuardError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = match IntrospectedUser::token_from_parts(parts, state).await {
+            Ok(Some(token)) => token,
+            Ok(None)
+            | Err(IntrospectionGuardError::InvalidHeader)
+            | Err(IntrospectionGuardError::WrongScheme) => return Ok(Self(None)),
+            Err(err) => return Err(err),
         };
 
         let introspection_state = IntrospectionState::from_ref(state);
+        let degraded = parts.extensions.get::<DegradedSignal>().cloned();
+
+        match wrap_future(IntrospectedUser::introspect_token(
+            introspection_state,
+            token,
+            degraded,
+        ))
+        .await
+        {
+            Ok(user) => Ok(Self(Some(user))),
+            Err(IntrospectionGuardError::Inactive) => Ok(Self(None)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl IntrospectedUser {
+    /// Whether the token still belongs to an active user, to re-check long-lived connections.
+    /// Asks the IdP instead of the cache, so a revocation is noticed by the next check, and an
+    /// unreachable IdP counts as active so an outage does not drop every connection.
+    pub(crate) async fn revalidate(introspection_state: IntrospectionState, token: String) -> bool {
         let config = Arc::clone(&introspection_state.config);
+        let introspection = async move { Self::introspect_coalesced(&config, &token).await };
+        match wrap_future(introspection).await {
+            Ok(res) => res.active() && res.sub().is_some(),
+            Err(_) => true,
+        }
+    }
 
-        let fut = async move {
-            let result = match introspection_state.config.cache.as_deref() {
-                None => {
-                    introspect(
-                        &config.introspection_uri,
-                        &config.authority,
-                        &config.authentication,
-                        &token.unwrap(),
-                    )
-                    .await
-                }
-                Some(cache) => match cache.get(token.clone().unwrap_or(String::new()).as_str()).await {
-                    Some(cached_response) => Ok(cached_response),
-                    None => {
-                        let res = introspect(
-                            &config.introspection_uri,
-                            &config.authority,
-                            &config.authentication,
-                            token.clone().unwrap_or(String::new()).as_str(),
-                        )
-                        .await;
-                        if let Ok(res) = &res {
-                            cache
-                                .set(token.clone().unwrap().as_str(), res.clone())
-                                .await;
-                        }
-                        res
-                    }
-                },
-            };
+    /// Drops every cached introspection result of the token in this isolate and in the shared
+    /// cache tier, e.g. when its session ends. Other isolates notice once their own entry expires.
+    pub(crate) async fn forget(introspection_state: &IntrospectionState, token: &str) {
+        if let Some(cache) = introspection_state.config.cache.as_deref() {
+            cache.remove(token).await;
+        }
+        last_known_good().forget(token);
+    }
 
-            let user: Result<IntrospectedUser, IntrospectionGuardError> = match result {
-                Ok(res) => match res.active() {
-                    true if res.sub().is_some() => Ok(res.into()),
-                    false => Err(IntrospectionGuardError::Inactive),
-                    _ => Err(IntrospectionGuardError::NoUserId),
-                },
-                Err(source) => return Err(IntrospectionGuardError::Introspection { source }),
-            };
-            user
-        };
+    /// Looks up the access token, preferring the session over the `Authorization` header.
+    /// Returns `Ok(None)` if the request carries neither.
+    pub(crate) async fn token_from_parts<S>(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<String>, IntrospectionGuardError>
+    where
+        S: Sync,
+        tower_sessions_core::Session: FromRequestParts<S>,
+    {
+        let mut parts_clone = parts.clone();
+
+        if let Ok(session) =
+            tower_sessions_core::Session::from_request_parts(&mut parts_clone, state).await
+        {
+            let _ = session.load().await;
+
+            if let Ok(Some(token)) = session.get::<String>("token").await {
+                return Ok(Some(token));
+            }
+        }
 
-        wrap_future(fut).await
+        Self::token_from_header(parts)
     }
-}
 
-impl IntrospectedUser {
-    fn token_from_header(parts: &mut Parts) -> Result<String, IntrospectionGuardError> {
-        let auth_header = parts
-            .headers
-            .get("Authorization")
-            .ok_or(IntrospectionGuardError::InvalidHeader)
-            .unwrap();
+    fn token_from_header(parts: &mut Parts) -> Result<Option<String>, IntrospectionGuardError> {
+        let Some(auth_header) = parts.headers.get("Authorization") else {
+            return Ok(None);
+        };
 
         let auth_str = auth_header
             .to_str()
-            .map_err(|_| IntrospectionGuardError::InvalidHeader)
-            .unwrap();
+            .map_err(|_| IntrospectionGuardError::InvalidHeader)?;
 
         if !auth_str.starts_with("Bearer ") {
             return Err(IntrospectionGuardError::WrongScheme);
         }
 
         let token = auth_str.trim_start_matches("Bearer ").trim().to_string();
-        Ok(token)
+        Ok(Some(token))
+    }
+
+    async fn introspect_token(
+        introspection_state: IntrospectionState,
+        token: String,
+        degraded: Option<DegradedSignal>,
+    ) -> Result<IntrospectedUser, IntrospectionGuardError> {
+        let config = Arc::clone(&introspection_state.config);
+
+        let result = match config.cache.as_deref() {
+            Some(cache) => match cache.get(&token).await {
+                Some(cached_response) if config.cache_policy.is_fresh(&cached_response) => {
+                    Ok(cached_response)
+                }
+                _ => Self::introspect_coalesced(&config, &token).await,
+            },
+            None => Self::introspect_coalesced(&config, &token).await,
+        };
+
+        let result = match result {
+            Err(source) if source.is_idp_unavailable() => {
+                match Self::stale_if_error(&config, &token) {
+                    Some(stale) => {
+                        tracing::warn!(
+                            error = %source,
+                            "IdP unavailable, serving stale introspection result"
+                        );
+                        if let Some(degraded) = degraded {
+                            degraded.mark();
+                        }
+                        Ok(stale)
+                    }
+                    None => Err(source),
+                }
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(res) => match res.active() {
+                true if res.sub().is_some() => Ok(res.into()),
+                false => Err(IntrospectionGuardError::Inactive),
+                _ => Err(IntrospectionGuardError::NoUserId),
+            },
+            Err(source) => Err(IntrospectionGuardError::Introspection { source }),
+        }
+    }
+
+    /// Introspects the token, sharing the in-flight request with concurrent callers
+    /// for the same token in this isolate. Only the caller doing the request fills the cache.
+    async fn introspect_coalesced(
+        config: &IntrospectionConfig,
+        token: &str,
+    ) -> Result<ZitadelIntrospectionResponse, Arc<IntrospectionError>> {
+        let key = format!("{}::{}", config.introspection_uri.as_str(), token_key(token));
+
+        in_flight_introspections()
+            .run(&key, || async {
+                let res = introspect_with(
+                    &config.http_client,
+                    &config.introspection_uri,
+                    &config.authority,
+                    &config.authentication,
+                    token,
+                )
+                .await;
+                if let (Ok(res), Some(cache)) = (&res, config.cache.as_deref()) {
+                    cache
+                        .set_entry(token, config.cache_policy.entry(res.clone()))
+                        .await;
+                }
+                if let (Ok(res), Some(grace)) = (&res, config.stale_if_error) {
+                    last_known_good().record(token, res, grace);
+                }
+                res.map_err(Arc::new)
+            })
+            .await
+    }
+
+    /// The last known active result for the token if resilience is enabled, scheduling a refresh
+    /// in the background so the cache recovers together with the IdP.
+    fn stale_if_error(
+        config: &Arc<IntrospectionConfig>,
+        token: &str,
+    ) -> Option<ZitadelIntrospectionResponse> {
+        let stale = last_known_good().get(token, config.stale_if_error?)?;
+
+        if let Some(background_tasks) = &config.background_tasks {
+            let config = Arc::clone(config);
+            let token = token.to_string();
+            background_tasks.spawn(async move {
+                let _ = Self::introspect_coalesced(&config, &token).await;
+            });
+        }
+
+        Some(stale)
     }
 }
 
+type SharedIntrospectionResult = Result<ZitadelIntrospectionResponse, Arc<IntrospectionError>>;
+
+/// The introspection state is rebuilt per request, so in-flight introspections are tracked per isolate.
+fn in_flight_introspections() -> &'static SingleFlight<SharedIntrospectionResult> {
+    static IN_FLIGHT: OnceLock<SingleFlight<SharedIntrospectionResult>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(SingleFlight::new)
+}
+
+fn last_known_good() -> &'static LastKnownGood {
+    static LAST_KNOWN_GOOD: OnceLock<LastKnownGood> = OnceLock::new();
+    LAST_KNOWN_GOOD.get_or_init(LastKnownGood::new)
+}
+
 impl From<ZitadelIntrospectionResponse> for IntrospectedUser {
     fn from(response: ZitadelIntrospectionResponse) -> Self {
         Self {
@@ -243,17 +382,16 @@ mod tests {
     use axum::response::IntoResponse;
     use axum::routing::get;
     use axum::Router;
+    use http_body_util::BodyExt;
 
     use tower::ServiceExt;
 
     use crate::axum_introspector::introspection::{IntrospectionState, IntrospectionStateBuilder};
     use crate::credentials::Application;
+    use crate::test_support::{MockOidcProvider, MockUser};
 
     use super::*;
 
-    const ZITADEL_URL: &str = "https://zitadel-libraries-l8boqa.zitadel.cloud";
-    const PERSONAL_ACCESS_TOKEN: &str =
-        "dEnGhIFs3VnqcQU5D2zRSeiarB1nwH6goIKY0J8MWZbsnWcTuu1C59lW9DgCq1y096GYdXA";
     const APPLICATION: &str = r#"
     {
         "type": "application",
@@ -274,6 +412,13 @@ mod tests {
         "Hello unauthorized"
     }
 
+    async fn maybe_authed(OptionalIntrospectedUser(user): OptionalIntrospectedUser) -> impl IntoResponse {
+        match user {
+            Some(user) => format!("Hello authorized user with id {}", user.user_id),
+            None => "Hello anonymous".to_string(),
+        }
+    }
+
     #[derive(Clone)]
     struct SomeUserState {
         introspection_state: IntrospectionState,
@@ -285,9 +430,14 @@ mod tests {
         }
     }
 
-    async fn app() -> Router {
-        let introspection_state = IntrospectionStateBuilder::new(ZITADEL_URL)
+    fn valid_token(provider: &MockOidcProvider) -> String {
+        provider.issue_token(MockUser::new("user1").with_username("alice"))
+    }
+
+    async fn app(provider: &MockOidcProvider) -> Router {
+        let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
             .with_jwt_profile(Application::load_from_json(APPLICATION).unwrap())
+            .with_http_client(provider.http_client())
             .build()
             .await
             .unwrap();
@@ -299,6 +449,7 @@ mod tests {
         let app = Router::new()
             .route("/unauthed", get(unauthed))
             .route("/authed", get(authed))
+            .route("/maybe_authed", get(maybe_authed))
             .with_state(state);
 
         return app;
@@ -306,7 +457,8 @@ mod tests {
 
     #[tokio::test]
     async fn can_guard() {
-        let app = app().await;
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
 
         // Create a mock request with a session containing a token
         let mut test_request = Request::builder()
@@ -332,7 +484,8 @@ mod tests {
 
     #[tokio::test]
     async fn guard_protects_if_non_bearer_present() {
-        let app = app().await;
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
 
         // Create a mock request with a session containing a token
         let mut test_request = Request::builder()
@@ -360,7 +513,8 @@ mod tests {
 
     #[tokio::test]
     async fn guard_protects_if_multiple_auth_headers_present() {
-        let app = app().await;
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
 
         // Create a mock request with a session containing a token
         let mut test_request = Request::builder()
@@ -390,7 +544,8 @@ mod tests {
 
     #[tokio::test]
     async fn guard_protects_if_invalid_token() {
-        let app = app().await;
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
 
         // Create a mock request with a session containing a token
         let mut test_request = Request::builder()
@@ -417,7 +572,8 @@ mod tests {
 
     #[tokio::test]
     async fn guard_allows_valid_token() {
-        let app = app().await;
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
 
         // Create a mock request with a session containing a token
         let mut test_request = Request::builder()
@@ -428,7 +584,7 @@ mod tests {
 
         // Add the session to the extensions
         let mut session = tower_sessions::Session::new(None, Arc::new(tower_sessions::MemoryStore::default()), None);
-        session.insert("token", PERSONAL_ACCESS_TOKEN).await.unwrap();
+        session.insert("token", valid_token(&provider)).await.unwrap();
         session.save().await.unwrap();
         test_request.extensions_mut().insert(session);
 
@@ -443,6 +599,228 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn guard_rejects_missing_credentials() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+
+        let test_request = Request::builder()
+            .uri("/authed")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(test_request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn optional_guard_allows_anonymous() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+
+        let test_request = Request::builder()
+            .uri("/maybe_authed")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(test_request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn optional_guard_treats_inactive_token_as_anonymous() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+
+        let mut test_request = Request::builder()
+            .uri("/maybe_authed")
+            .body(Body::empty())
+            .unwrap();
+
+        let session = tower_sessions::Session::new(None, Arc::new(tower_sessions::MemoryStore::default()), None);
+        session.insert("token", "something").await.unwrap();
+        test_request.extensions_mut().insert(session);
+
+        let resp = app.oneshot(test_request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn optional_guard_resolves_valid_token() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+
+        let mut test_request = Request::builder()
+            .uri("/maybe_authed")
+            .body(Body::empty())
+            .unwrap();
+
+        let session = tower_sessions::Session::new(None, Arc::new(tower_sessions::MemoryStore::default()), None);
+        session.insert("token", valid_token(&provider)).await.unwrap();
+        test_request.extensions_mut().insert(session);
+
+        let resp = app.oneshot(test_request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Hello authorized user with id user1");
+    }
+
+    fn bearer_request(uri: &str, token: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn guard_accepts_bearer_header() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+
+        let resp = app
+            .oneshot(bearer_request("/authed", &valid_token(&provider)))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn guard_rejects_revoked_token() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+        let token = valid_token(&provider);
+        provider.revoke(&token);
+
+        let resp = app.oneshot(bearer_request("/authed", &token)).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_introspection() {
+        let provider = MockOidcProvider::new();
+        let app = app(&provider).await;
+        let token = valid_token(&provider);
+
+        let (a, b, c) = tokio::join!(
+            app.clone().oneshot(bearer_request("/authed", &token)),
+            app.clone().oneshot(bearer_request("/authed", &token)),
+            app.clone().oneshot(bearer_request("/authed", &token))
+        );
+
+        for resp in [a, b, c] {
+            assert_eq!(resp.unwrap().status(), StatusCode::OK);
+        }
+        assert_eq!(provider.introspection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn revalidate_notices_revoked_tokens() {
+        let provider = MockOidcProvider::new();
+        let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
+            .with_basic_auth("client", "secret")
+            .with_http_client(provider.http_client())
+            .build()
+            .await
+            .unwrap();
+        let token = valid_token(&provider);
+
+        assert!(IntrospectedUser::revalidate(introspection_state.clone(), token.clone()).await);
+
+        provider.revoke(&token);
+
+        assert!(!IntrospectedUser::revalidate(introspection_state, token).await);
+    }
+
+    #[tokio::test]
+    async fn revalidate_does_not_trust_the_cache() {
+        use crate::oidc::introspection::cache::in_memory::InMemoryIntrospectionCache;
+
+        let provider = MockOidcProvider::new();
+        let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
+            .with_basic_auth("client", "secret")
+            .with_http_client(provider.http_client())
+            .with_introspection_cache(InMemoryIntrospectionCache::new())
+            .build()
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/authed", get(authed))
+            .with_state(SomeUserState {
+                introspection_state: introspection_state.clone(),
+            });
+        let token = valid_token(&provider);
+
+        let resp = app
+            .oneshot(bearer_request("/authed", &token))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        provider.revoke(&token);
+
+        assert!(!IntrospectedUser::revalidate(introspection_state, token).await);
+        assert_eq!(provider.introspection_count(), 2);
+    }
+
+    mod stale_if_error {
+        use super::*;
+
+        async fn resilient_app(provider: &MockOidcProvider) -> Router {
+            let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
+                .with_basic_auth("client", "secret")
+                .with_http_client(provider.http_client().with_max_retries(0))
+                .with_stale_if_error(time::Duration::minutes(5))
+                .build()
+                .await
+                .unwrap();
+
+            Router::new()
+                .route("/authed", get(authed))
+                .with_state(SomeUserState {
+                    introspection_state,
+                })
+        }
+
+        #[tokio::test]
+        async fn serves_last_known_result_while_idp_is_down() {
+            let provider = MockOidcProvider::new();
+            let app = resilient_app(&provider).await;
+            let token = valid_token(&provider);
+
+            let resp = app.clone().oneshot(bearer_request("/authed", &token)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            provider.set_unavailable(true);
+            let degraded = DegradedSignal::default();
+            let mut request = bearer_request("/authed", &token);
+            request.extensions_mut().insert(degraded.clone());
+
+            let resp = app.oneshot(request).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(degraded.is_degraded());
+        }
+
+        #[tokio::test]
+        async fn rejects_unknown_tokens_while_idp_is_down() {
+            let provider = MockOidcProvider::new();
+            let app = resilient_app(&provider).await;
+            let token = valid_token(&provider);
+
+            provider.set_unavailable(true);
+            let resp = app.oneshot(bearer_request("/authed", &token)).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
     // #[cfg(feature = "introspection_cache")]
     mod introspection_cache {
         use super::*;
@@ -454,9 +832,13 @@ mod tests {
         use std::ops::Add;
         use std::sync::Arc;
 
-        async fn app_witch_cache(cache: impl IntrospectionCache + 'static) -> Router {
-            let introspection_state = IntrospectionStateBuilder::new(ZITADEL_URL)
+        async fn app_witch_cache(
+            provider: &MockOidcProvider,
+            cache: impl IntrospectionCache + 'static,
+        ) -> Router {
+            let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
                 .with_jwt_profile(Application::load_from_json(APPLICATION).unwrap())
+                .with_http_client(provider.http_client())
                 .with_introspection_cache(cache)
                 .build()
                 .await
@@ -476,8 +858,10 @@ mod tests {
 
         #[tokio::test]
         async fn guard_uses_cached_response() {
+            let provider = MockOidcProvider::new();
+            let token = valid_token(&provider);
             let cache = Arc::new(InMemoryIntrospectionCache::default());
-            let app = app_witch_cache(cache.clone()).await;
+            let app = app_witch_cache(&provider, cache.clone()).await;
 
             let mut res = ZitadelIntrospectionResponse::new(
                 true,
@@ -485,7 +869,7 @@ mod tests {
             );
             res.set_sub(Some("cached_sub".to_string()));
             res.set_exp(Some(Utc::now().add(TimeDelta::days(1))));
-            cache.set(PERSONAL_ACCESS_TOKEN, res).await;
+            cache.set(&token, res).await;
 
 
             // Create a mock request with a session containing a token
@@ -497,7 +881,7 @@ mod tests {
 
             // Add the session to the extensions
             let mut session = tower_sessions::Session::new(None, Arc::new(tower_sessions::MemoryStore::default()), None);
-            session.insert("token", PERSONAL_ACCESS_TOKEN).await.unwrap();
+            session.insert("token", &token).await.unwrap();
             session.save().await.unwrap();
             test_request.extensions_mut().insert(session);
 
@@ -524,8 +908,10 @@ mod tests {
 
         #[tokio::test]
         async fn guard_caches_response() {
+            let provider = MockOidcProvider::new();
+            let token = valid_token(&provider);
             let cache = Arc::new(InMemoryIntrospectionCache::default());
-            let app = app_witch_cache(cache.clone()).await;
+            let app = app_witch_cache(&provider, cache.clone()).await;
 
 
             // Create a mock request with a session containing a token
@@ -537,7 +923,7 @@ mod tests {
 
             // Add the session to the extensions
             let mut session = tower_sessions::Session::new(None, Arc::new(tower_sessions::MemoryStore::default()), None);
-            session.insert("token", PERSONAL_ACCESS_TOKEN).await.unwrap();
+            session.insert("token", &token).await.unwrap();
             session.save().await.unwrap();
             test_request.extensions_mut().insert(session);
 
@@ -560,9 +946,35 @@ mod tests {
             )
             .unwrap();
 
-            let cached_response = cache.get(PERSONAL_ACCESS_TOKEN).await.unwrap();
+            let cached_response = cache.get(&token).await.unwrap();
 
             assert!(text.contains(cached_response.sub().unwrap()));
         }
+
+        #[tokio::test]
+        async fn forget_evicts_the_cached_response() {
+            let provider = MockOidcProvider::new();
+            let token = valid_token(&provider);
+            let cache = Arc::new(InMemoryIntrospectionCache::default());
+            let introspection_state = IntrospectionStateBuilder::new(provider.issuer())
+                .with_basic_auth("client", "secret")
+                .with_http_client(provider.http_client())
+                .with_introspection_cache(cache.clone())
+                .build()
+                .await
+                .unwrap();
+
+            let mut res = ZitadelIntrospectionResponse::new(
+                true,
+                ZitadelIntrospectionExtraTokenFields::default(),
+            );
+            res.set_sub(Some("cached_sub".to_string()));
+            res.set_exp(Some(Utc::now().add(TimeDelta::days(1))));
+            cache.set(&token, res).await;
+
+            IntrospectedUser::forget(&introspection_state, &token).await;
+
+            assert!(cache.get(&token).await.is_none());
+        }
     }
 }
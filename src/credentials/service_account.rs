@@ -3,7 +3,6 @@ use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use openidconnect::{
     core::{CoreProviderMetadata, CoreTokenType},
     http::HeaderMap,
-    reqwest::async_http_client,
     EmptyExtraTokenFields, HttpRequest, IssuerUrl, OAuth2TokenResponse, StandardTokenResponse,
 };
 use reqwest::{
@@ -14,6 +13,7 @@ use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
 
 use crate::credentials::jwt::JwtClaims;
+use crate::http_client::{HttpClient, HttpClientError};
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -34,6 +34,10 @@ pub struct AuthenticationOptions {
     pub roles: Vec<String>,
     
     pub project_audiences: Vec<String>,
+
+    /// Sends the discovery and token requests. Pass the application's client to share its
+    /// transport, timeouts and circuit breaker.
+    pub http_client: HttpClient,
 }
 
 custom_error! {
@@ -44,7 +48,7 @@ custom_error! {
         AudienceUrl{source: openidconnect::url::ParseError} = "audience url could not be parsed: {source}",
         DiscoveryError{source: Box<dyn std::error::Error>} = "could not discover OIDC document: {source}",
         TokenEndpointMissing = "OIDC document does not contain token endpoint",
-        HttpError{source: openidconnect::reqwest::Error<reqwest::Error>} = "http error: {source}",
+        HttpError{source: HttpClientError} = "http error: {source}",
         UrlEncodeError = "could not encode url params for token request",
         TokenError = "could not fetch token from endpoint",
         AccessTokenMissing = "token response does not contain access token",
@@ -74,11 +78,13 @@ impl ServiceAccount {
     ) -> Result<String, ServiceAccountError> {
         let issuer = IssuerUrl::new(audience.to_string())
             .map_err(|e| ServiceAccountError::AudienceUrl { source: e })?;
-        let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
-            .await
-            .map_err(|e| ServiceAccountError::DiscoveryError {
-                source: Box::new(e),
-            })?;
+        let http_client = &options.http_client;
+        let metadata =
+            CoreProviderMetadata::discover_async(issuer, |request| http_client.execute(request))
+                .await
+                .map_err(|e| ServiceAccountError::DiscoveryError {
+                    source: Box::new(e),
+                })?;
 
         let jwt = self.create_signed_jwt(audience)?;
         let url = metadata
@@ -99,14 +105,15 @@ impl ServiceAccount {
 
         let url =
             Url::parse(url.as_str()).map_err(|_| ServiceAccountError::TokenEndpointMissing)?;
-        let response = async_http_client(HttpRequest {
-            url,
-            method: Method::POST,
-            headers,
-            body: body.into_bytes(),
-        })
-        .await
-        .map_err(|e| ServiceAccountError::HttpError { source: e })?;
+        let response = http_client
+            .execute(HttpRequest {
+                url,
+                method: Method::POST,
+                headers,
+                body: body.into_bytes(),
+            })
+            .await
+            .map_err(|e| ServiceAccountError::HttpError { source: e })?;
 
         serde_json::from_slice(response.body.as_slice())
             .map_err(|e| ServiceAccountError::Json { source: e })
@@ -168,6 +175,11 @@ mod tests {
 
     use std::fs::File;
     use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::http_client::scripted::ScriptedTransport;
+    use crate::http_client::CircuitBreaker;
 
     use super::*;
 
@@ -222,6 +234,27 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn authenticates_through_the_given_client() {
+        let sa = ServiceAccount::load_from_json(SERVICE_ACCOUNT).unwrap();
+        let circuit_breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(30)));
+        let options = AuthenticationOptions {
+            http_client: HttpClient::new()
+                .with_transport(ScriptedTransport::new().fail("connection refused"))
+                .with_max_retries(0)
+                .with_circuit_breaker(circuit_breaker.clone()),
+            ..Default::default()
+        };
+
+        let result = sa.authenticate_with_options(ZITADEL_URL, &options).await;
+
+        assert!(matches!(
+            result,
+            Err(ServiceAccountError::DiscoveryError { .. })
+        ));
+        assert!(circuit_breaker.is_open("zitadel-libraries-l8boqa.zitadel.cloud"));
+    }
+
     #[test]
     fn creates_a_signed_jwt() {
         let sa = ServiceAccount::load_from_json(SERVICE_ACCOUNT).unwrap();
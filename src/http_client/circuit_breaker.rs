@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct HostState {
+    consecutive_failures: u32,
+    open_until: Option<i64>,
+}
+
+/// Fails fast on hosts that keep failing: after `threshold` consecutive failures requests to
+/// the host are refused for `cooldown`. Afterwards requests are let through again, and the
+/// circuit opens again on the next failure until one succeeds.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_open(&self, host: &str) -> bool {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .get(host)
+            .and_then(|state| state.open_until)
+            .is_some_and(|open_until| open_until > now())
+    }
+
+    pub(crate) fn record_success(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+
+    pub(crate) fn record_failure(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.threshold {
+            state.open_until = Some(now() + self.cooldown.as_millis() as i64);
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(30))
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+        breaker.record_failure("idp");
+        breaker.record_failure("idp");
+        assert!(!breaker.is_open("idp"));
+
+        breaker.record_failure("idp");
+        assert!(breaker.is_open("idp"));
+        assert!(!breaker.is_open("other"));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure("idp");
+        breaker.record_success("idp");
+        breaker.record_failure("idp");
+
+        assert!(!breaker.is_open("idp"));
+    }
+
+    #[test]
+    fn closes_after_cooldown_and_reopens_on_next_failure() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure("idp");
+        breaker.record_failure("idp");
+        breaker
+            .hosts
+            .lock()
+            .unwrap()
+            .get_mut("idp")
+            .unwrap()
+            .open_until = Some(now() - 1);
+        assert!(!breaker.is_open("idp"));
+
+        breaker.record_failure("idp");
+        assert!(breaker.is_open("idp"));
+    }
+}
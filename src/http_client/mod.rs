@@ -0,0 +1,464 @@
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::{Arc, OnceLock};
+use std::task::Poll;
+use std::time::Duration;
+
+use custom_error::custom_error;
+use http::{Method, StatusCode};
+use openidconnect::{HttpRequest, HttpResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+
+mod circuit_breaker;
+#[cfg(test)]
+pub(crate) mod scripted;
+mod transport;
+
+pub use circuit_breaker::CircuitBreaker;
+#[cfg(target_arch = "wasm32")]
+pub use transport::FetchTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub use transport::ReqwestTransport;
+pub use transport::{
+    default_transport, HttpTransport, TransportError, TransportRequest, TransportResponse,
+};
+
+custom_error! {
+    pub HttpClientError
+        InvalidRequest{source: http::Error} = "could not build the request: {source}",
+        Transport{source: TransportError} = "the request did fail: {source}",
+        Timeout{timeout_ms: u128} = "the request timed out after {timeout_ms}ms",
+        CircuitOpen{host: String} = "too many failed requests to {host}, failing fast",
+}
+
+/// HTTP client for all calls to the IdP.
+///
+/// Every attempt is bounded by `timeout`. Failed attempts (transport errors, timeouts,
+/// 429 and 502-504) are retried up to `max_retries` times with jittered exponential backoff,
+/// but only for requests that are safe to repeat. Failures are tracked per host in a circuit
+/// breaker, shared by all clients of the isolate unless configured otherwise. A call counts
+/// once there, however many attempts it took.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    transport: Arc<dyn HttpTransport>,
+    timeout: Duration,
+    max_retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        Self {
+            transport: default_transport(),
+            timeout: Duration::from_secs(5),
+            max_retries: 2,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            circuit_breaker: isolate_circuit_breaker(),
+        }
+    }
+
+    pub fn with_transport(mut self, transport: impl HttpTransport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the base and the maximum delay between retries.
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Sends an `openidconnect` request, retrying only if its method is safe (`GET`, `HEAD`, `OPTIONS`).
+    /// Can be passed to `openidconnect` as `|request| http_client.execute(request)`.
+    pub async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, HttpClientError> {
+        let response = self.execute_request(from_oidc_request(request)?).await?;
+        Ok(into_oidc_response(response))
+    }
+
+    /// Sends an `openidconnect` request that has no side effects regardless of its method,
+    /// e.g. a token introspection `POST`, so it may be retried.
+    pub async fn execute_safe(
+        &self,
+        request: HttpRequest,
+    ) -> Result<HttpResponse, HttpClientError> {
+        let response = self.send(from_oidc_request(request)?, true).await?;
+        Ok(into_oidc_response(response))
+    }
+
+    /// Sends a plain `http` request, retrying only if its method is safe.
+    /// Can be passed to `oauth2` as `&|request| http_client.execute_request(request)`.
+    pub async fn execute_request(
+        &self,
+        request: TransportRequest,
+    ) -> Result<TransportResponse, HttpClientError> {
+        let retry = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        );
+        self.send(request, retry).await
+    }
+
+    async fn send(
+        &self,
+        request: TransportRequest,
+        retry: bool,
+    ) -> Result<TransportResponse, HttpClientError> {
+        let host = request.uri().host().unwrap_or_default().to_string();
+        let mut attempt = 0;
+
+        loop {
+            if self.circuit_breaker.is_open(&host) {
+                return Err(HttpClientError::CircuitOpen { host });
+            }
+
+            let result = self.attempt(clone_request(&request)).await;
+
+            let retryable = match &result {
+                Ok(response) => is_retryable_status(response.status()),
+                Err(_) => true,
+            };
+            if !retry || !retryable || attempt >= self.max_retries {
+                match &result {
+                    Ok(response) if !response.status().is_server_error() => {
+                        self.circuit_breaker.record_success(&host)
+                    }
+                    _ => self.circuit_breaker.record_failure(&host),
+                }
+                return result;
+            }
+
+            sleep(self.backoff_delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn attempt(
+        &self,
+        request: TransportRequest,
+    ) -> Result<TransportResponse, HttpClientError> {
+        match timeout(self.timeout, self.transport.send(request)).await {
+            Some(result) => result.map_err(|source| HttpClientError::Transport { source }),
+            None => Err(HttpClientError::Timeout {
+                timeout_ms: self.timeout.as_millis(),
+            }),
+        }
+    }
+
+    /// Full jitter: a random delay between zero and the exponential backoff for the attempt.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        let mut random = [0u8; 4];
+        if SystemRandom::new().fill(&mut random).is_err() {
+            return ceiling;
+        }
+        ceiling.mul_f64(u32::from_le_bytes(random) as f64 / u32::MAX as f64)
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_retryable_status(status_code: StatusCode) -> bool {
+    matches!(
+        status_code,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn clone_request(request: &TransportRequest) -> TransportRequest {
+    let mut clone = http::Request::new(request.body().clone());
+    *clone.method_mut() = request.method().clone();
+    *clone.uri_mut() = request.uri().clone();
+    *clone.version_mut() = request.version();
+    *clone.headers_mut() = request.headers().clone();
+    clone
+}
+
+// `openidconnect` still speaks `http` 0.2, the transports `http` 1.
+
+fn from_oidc_request(request: HttpRequest) -> Result<TransportRequest, HttpClientError> {
+    let mut builder = http::Request::builder()
+        .method(request.method.as_str())
+        .uri(request.url.as_str());
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder
+        .body(request.body)
+        .map_err(|source| HttpClientError::InvalidRequest { source })
+}
+
+fn into_oidc_response(response: TransportResponse) -> HttpResponse {
+    let mut headers = openidconnect::http::HeaderMap::new();
+    for (name, value) in response.headers() {
+        if let (Ok(name), Ok(value)) = (
+            openidconnect::http::HeaderName::from_bytes(name.as_str().as_bytes()),
+            openidconnect::http::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+
+    HttpResponse {
+        status_code: openidconnect::http::StatusCode::from_u16(response.status().as_u16())
+            .unwrap_or(openidconnect::http::StatusCode::BAD_GATEWAY),
+        headers,
+        body: response.into_body(),
+    }
+}
+
+fn isolate_circuit_breaker() -> Arc<CircuitBreaker> {
+    static CIRCUIT_BREAKER: OnceLock<Arc<CircuitBreaker>> = OnceLock::new();
+    CIRCUIT_BREAKER
+        .get_or_init(|| Arc::new(CircuitBreaker::default()))
+        .clone()
+}
+
+/// Resolves to `None` if `future` did not complete within `duration`.
+async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut delay = pin!(sleep(duration));
+
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        delay.as_mut().poll(cx).map(|_| None)
+    })
+    .await
+}
+
+#[cfg(target_arch = "wasm32")]
+fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    worker::Delay::from(duration)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    tokio::time::sleep(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use openidconnect::http::HeaderMap;
+    use openidconnect::url::Url;
+
+    use super::scripted::ScriptedTransport;
+    use super::*;
+
+    fn client(transport: ScriptedTransport) -> (HttpClient, Arc<CircuitBreaker>) {
+        let circuit_breaker = Arc::new(CircuitBreaker::new(3, Duration::from_secs(30)));
+        let client = HttpClient::new()
+            .with_transport(transport)
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+            .with_timeout(Duration::from_millis(50))
+            .with_circuit_breaker(circuit_breaker.clone());
+        (client, circuit_breaker)
+    }
+
+    fn request(method: Method) -> TransportRequest {
+        http::Request::builder()
+            .method(method)
+            .uri("https://idp.invalid/oauth/v2/introspect")
+            .body(vec![])
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn timeout_resolves_to_none_when_elapsed() {
+        let result = timeout(Duration::from_millis(10), std::future::pending::<()>()).await;
+
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn timeout_resolves_to_the_output_in_time() {
+        let result = timeout(Duration::from_secs(1), async { 42 }).await;
+
+        assert_eq!(result, Some(42));
+    }
+
+    #[test]
+    fn backoff_is_jittered_and_bounded() {
+        let client =
+            HttpClient::new().with_backoff(Duration::from_millis(100), Duration::from_millis(300));
+
+        for attempt in 0..10 {
+            let ceiling =
+                Duration::from_millis(100 * 2u64.pow(attempt)).min(Duration::from_millis(300));
+            assert!(client.backoff_delay(attempt) <= ceiling);
+        }
+    }
+
+    #[test]
+    fn only_transient_statuses_are_retried() {
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_retryable_status(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn fails_fast_while_the_circuit_is_open() {
+        let circuit_breaker = Arc::new(CircuitBreaker::new(1, Duration::from_secs(30)));
+        circuit_breaker.record_failure("idp.invalid");
+
+        let client = HttpClient::new().with_circuit_breaker(circuit_breaker);
+        let result = client
+            .execute(HttpRequest {
+                url: Url::parse("https://idp.invalid/.well-known/openid-configuration").unwrap(),
+                method: openidconnect::http::Method::GET,
+                headers: HeaderMap::new(),
+                body: vec![],
+            })
+            .await;
+
+        assert!(matches!(result, Err(HttpClientError::CircuitOpen { .. })));
+    }
+
+    #[tokio::test]
+    async fn retries_safe_requests_on_transient_failures() {
+        let transport = ScriptedTransport::new()
+            .respond(503, "")
+            .fail("connection reset")
+            .respond(200, "{}");
+        let (client, _) = client(transport);
+
+        let response = client.execute_request(request(Method::GET)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let transport = ScriptedTransport::new()
+            .respond(503, "")
+            .respond(503, "")
+            .respond(503, "")
+            .respond(200, "{}");
+        let (client, _) = client(transport);
+
+        let response = client
+            .with_max_retries(2)
+            .execute_request(request(Method::GET))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_unsafe_requests() {
+        let transport = ScriptedTransport::new().respond(503, "").respond(200, "{}");
+        let (client, _) = client(transport);
+
+        let response = client.execute_request(request(Method::POST)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn times_out_hanging_requests() {
+        let transport = ScriptedTransport::new().hang();
+        let (client, _) = client(transport);
+
+        let result = client
+            .with_max_retries(0)
+            .execute_request(request(Method::GET))
+            .await;
+
+        assert!(matches!(result, Err(HttpClientError::Timeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_open_the_circuit() {
+        let transport = ScriptedTransport::new()
+            .fail("connection refused")
+            .fail("connection refused")
+            .fail("connection refused");
+        let (client, circuit_breaker) = client(transport);
+        let client = client.with_max_retries(0);
+
+        for _ in 0..3 {
+            let result = client.execute_request(request(Method::GET)).await;
+            assert!(matches!(result, Err(HttpClientError::Transport { .. })));
+        }
+
+        assert!(circuit_breaker.is_open("idp.invalid"));
+        assert!(matches!(
+            client.execute_request(request(Method::GET)).await,
+            Err(HttpClientError::CircuitOpen { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retried_call_counts_as_one_failure() {
+        let transport = ScriptedTransport::new()
+            .fail("connection refused")
+            .fail("connection refused")
+            .fail("connection refused");
+        let (client, circuit_breaker) = client(transport);
+
+        let result = client
+            .with_max_retries(2)
+            .execute_request(request(Method::GET))
+            .await;
+
+        assert!(matches!(result, Err(HttpClientError::Transport { .. })));
+        assert!(!circuit_breaker.is_open("idp.invalid"));
+    }
+
+    #[tokio::test]
+    async fn converts_openidconnect_requests() {
+        let transport = ScriptedTransport::new().respond(200, r#"{"active":false}"#);
+        let (client, _) = client(transport);
+
+        let response = client
+            .execute_safe(HttpRequest {
+                url: Url::parse("https://idp.invalid/oauth/v2/introspect").unwrap(),
+                method: openidconnect::http::Method::POST,
+                headers: HeaderMap::new(),
+                body: b"token=abc".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.status_code, openidconnect::http::StatusCode::OK);
+        assert_eq!(response.body, br#"{"active":false}"#.to_vec());
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+    }
+}
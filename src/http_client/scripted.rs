@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::transport::{HttpTransport, TransportError, TransportRequest, TransportResponse};
+
+#[derive(Debug)]
+enum Scripted {
+    Respond(TransportResponse),
+    Fail(String),
+    Hang,
+}
+
+/// A transport answering with scripted responses in order, recording every request it gets.
+/// Runs out with an error once the script is exhausted.
+#[derive(Debug, Default)]
+pub(crate) struct ScriptedTransport {
+    script: Mutex<VecDeque<Scripted>>,
+    requests: Mutex<Vec<(http::Method, String)>>,
+    headers: Mutex<Vec<http::HeaderMap>>,
+}
+
+impl ScriptedTransport {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn respond(self, status: u16, body: &str) -> Self {
+        let response = http::Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body.as_bytes().to_vec())
+            .unwrap();
+        self.script
+            .lock()
+            .unwrap()
+            .push_back(Scripted::Respond(response));
+        self
+    }
+
+    pub(crate) fn fail(self, message: &str) -> Self {
+        self.script
+            .lock()
+            .unwrap()
+            .push_back(Scripted::Fail(message.to_string()));
+        self
+    }
+
+    /// The next request never completes.
+    pub(crate) fn hang(self) -> Self {
+        self.script.lock().unwrap().push_back(Scripted::Hang);
+        self
+    }
+
+    /// Method and URI of every request sent so far.
+    pub(crate) fn requests(&self) -> Vec<(http::Method, String)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Headers of every request sent so far.
+    pub(crate) fn headers(&self) -> Vec<http::HeaderMap> {
+        self.headers.lock().unwrap().clone()
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl HttpTransport for ScriptedTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push((request.method().clone(), request.uri().to_string()));
+        self.headers.lock().unwrap().push(request.headers().clone());
+
+        let next = self.script.lock().unwrap().pop_front();
+        match next {
+            Some(Scripted::Respond(response)) => Ok(response),
+            Some(Scripted::Fail(message)) => Err(TransportError::Failed { message }),
+            Some(Scripted::Hang) => std::future::pending().await,
+            None => Err(TransportError::failed(format!(
+                "no scripted response left for {} {}",
+                request.method(),
+                request.uri()
+            ))),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use custom_error::custom_error;
+
+pub type TransportRequest = http::Request<Vec<u8>>;
+pub type TransportResponse = http::Response<Vec<u8>>;
+
+custom_error! {
+    pub TransportError
+        Failed{message: String} = "the request did fail: {message}",
+}
+
+impl TransportError {
+    pub(crate) fn failed(error: impl Display) -> Self {
+        TransportError::Failed {
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Sends a single HTTP request. Everything talking to the IdP goes through a transport,
+/// so the whole OIDC flow can run against [`FetchTransport`] on Workers, [`ReqwestTransport`]
+/// natively, or a scripted transport in tests.
+///
+/// Transports must not follow redirects.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait HttpTransport: Send + Sync + Debug {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError>;
+}
+
+/// The transport of the current target.
+pub fn default_transport() -> Arc<dyn HttpTransport> {
+    #[cfg(target_arch = "wasm32")]
+    return Arc::new(FetchTransport);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    return Arc::new(ReqwestTransport::new());
+}
+
+/// Sends requests through the Workers `fetch` API.
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchTransport;
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl HttpTransport for FetchTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        let (parts, body) = request.into_parts();
+
+        let headers = worker::Headers::new();
+        for (name, value) in &parts.headers {
+            let value = value.to_str().map_err(TransportError::failed)?;
+            headers
+                .append(name.as_str(), value)
+                .map_err(TransportError::failed)?;
+        }
+
+        let mut init = worker::RequestInit::new();
+        init.with_method(worker::Method::from(parts.method.to_string()))
+            .with_headers(headers)
+            .with_redirect(worker::RequestRedirect::Manual);
+        if !body.is_empty() {
+            init.with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+        }
+
+        let request = worker::Request::new_with_init(&parts.uri.to_string(), &init)
+            .map_err(TransportError::failed)?;
+        let mut response = worker::Fetch::Request(request)
+            .send()
+            .await
+            .map_err(TransportError::failed)?;
+
+        let mut builder = http::Response::builder().status(response.status_code());
+        for (name, value) in response.headers().entries() {
+            builder = builder.header(name, value);
+        }
+        let body = response.bytes().await.map_err(TransportError::failed)?;
+
+        builder.body(body).map_err(TransportError::failed)
+    }
+}
+
+/// Sends requests with `reqwest`, for native builds and tests.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReqwestTransport {
+    pub fn new() -> Self {
+        Self {
+            // Following redirects would open the client up to SSRF.
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("reqwest client should build"),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for ReqwestTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl HttpTransport for ReqwestTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        let (parts, body) = request.into_parts();
+
+        let method = reqwest::Method::from_bytes(parts.method.as_str().as_bytes())
+            .map_err(TransportError::failed)?;
+        let mut builder = self
+            .client
+            .request(method, parts.uri.to_string())
+            .body(body);
+        for (name, value) in &parts.headers {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+
+        let response = builder.send().await.map_err(TransportError::failed)?;
+
+        let mut converted = http::Response::builder().status(response.status().as_u16());
+        for (name, value) in response.headers() {
+            converted = converted.header(name.as_str(), value.as_bytes());
+        }
+        let body = response.bytes().await.map_err(TransportError::failed)?;
+
+        converted
+            .body(body.to_vec())
+            .map_err(TransportError::failed)
+    }
+}
@@ -0,0 +1,209 @@
+use async_trait::async_trait;
+use base64::Engine;
+use std::fmt::Debug;
+use tower_cookies::cookie::Key;
+use worker::kv::KvStore;
+use worker::send::SendWrapper;
+use worker::D1Database;
+
+use super::{KeyringError, KeyringStore, StoredKey};
+
+const KEYRING: &str = "keystore::ring";
+// the single key pair earlier versions kept in KV_STORAGE
+const LEGACY_SIGNING_KEY: &str = "keystore::sig";
+const LEGACY_ENCRYPTION_KEY: &str = "keystore::enc";
+
+/// Keeps the keyring in the `KEYRING_KV` namespace, for local development only.
+///
+/// KV has no compare-and-swap, so isolates racing to write the same version can disagree on
+/// its key, and sessions sealed with the losing key are lost. Deployments use [`D1KeyringStore`].
+#[derive(Clone)]
+pub struct KvKeyringStore {
+    kv_storage: KvStore,
+}
+
+impl KvKeyringStore {
+    pub fn new(kv_storage: KvStore) -> Self {
+        Self { kv_storage }
+    }
+}
+
+impl Debug for KvKeyringStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvKeyringStore").finish_non_exhaustive()
+    }
+}
+
+#[worker::send]
+async fn read_ring(kv_storage: KvStore) -> Result<Vec<StoredKey>, KeyringError> {
+    kv_storage
+        .get(KEYRING)
+        .json::<Vec<StoredKey>>()
+        .await
+        .map(Option::unwrap_or_default)
+        .map_err(KeyringError::storage)
+}
+
+#[worker::send]
+async fn write_ring(kv_storage: KvStore, ring: Vec<StoredKey>) -> Result<(), KeyringError> {
+    kv_storage
+        .put(KEYRING, ring)
+        .map_err(KeyringError::storage)?
+        .execute()
+        .await
+        .map_err(KeyringError::storage)
+}
+
+/// The keys earlier versions wrote to `kv_storage`, as version 1. They were used through
+/// `Key::derive_from`.
+#[worker::send]
+pub async fn read_legacy_key(kv_storage: KvStore) -> Result<Option<StoredKey>, KeyringError> {
+    let signing = kv_storage
+        .get(LEGACY_SIGNING_KEY)
+        .bytes()
+        .await
+        .map_err(KeyringError::storage)?;
+    let encryption = kv_storage
+        .get(LEGACY_ENCRYPTION_KEY)
+        .bytes()
+        .await
+        .map_err(KeyringError::storage)?;
+
+    let encode = |master: Vec<u8>| {
+        base64::engine::general_purpose::STANDARD.encode(Key::derive_from(&master).master())
+    };
+    Ok(signing
+        .zip(encryption)
+        .map(|(signing, encryption)| StoredKey {
+            version: 1,
+            signing: encode(signing),
+            encryption: encode(encryption),
+            created_at: 0,
+        }))
+}
+
+#[async_trait]
+impl KeyringStore for KvKeyringStore {
+    async fn load(&self) -> Result<Vec<StoredKey>, KeyringError> {
+        read_ring(self.kv_storage.clone()).await
+    }
+
+    async fn insert_if_absent(&self, key: StoredKey) -> Result<StoredKey, KeyringError> {
+        let mut ring = self.load().await?;
+        if let Some(existing) = ring.iter().find(|stored| stored.version == key.version) {
+            return Ok(existing.clone());
+        }
+
+        let version = key.version;
+        ring.push(key);
+        write_ring(self.kv_storage.clone(), ring).await?;
+
+        // the write of a concurrent isolate may have won
+        read_ring(self.kv_storage.clone())
+            .await?
+            .into_iter()
+            .find(|stored| stored.version == version)
+            .ok_or_else(|| KeyringError::storage("the written key could not be read back"))
+    }
+
+    async fn remove_before(&self, version: u32) -> Result<(), KeyringError> {
+        let ring = self.load().await?;
+        let retained: Vec<StoredKey> = ring
+            .iter()
+            .filter(|stored| stored.version >= version)
+            .cloned()
+            .collect();
+        if retained.len() == ring.len() {
+            return Ok(());
+        }
+        write_ring(self.kv_storage.clone(), retained).await
+    }
+}
+
+/// Keeps the keyring in a D1 table, see `migrations/0001_create_session_keys.sql`.
+/// A version is written with `ON CONFLICT DO NOTHING`, so the first writer wins and every
+/// isolate reads back the same key.
+pub struct D1KeyringStore {
+    database: SendWrapper<D1Database>,
+}
+
+impl D1KeyringStore {
+    pub fn new(database: D1Database) -> Self {
+        Self {
+            database: SendWrapper(database),
+        }
+    }
+}
+
+impl Debug for D1KeyringStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("D1KeyringStore").finish_non_exhaustive()
+    }
+}
+
+#[worker::send]
+async fn select_keys(database: &D1Database) -> Result<Vec<StoredKey>, KeyringError> {
+    database
+        .prepare("SELECT version, signing, encryption, created_at FROM session_keys")
+        .all()
+        .await
+        .and_then(|result| result.results::<StoredKey>())
+        .map_err(KeyringError::storage)
+}
+
+#[worker::send]
+async fn insert_key(database: &D1Database, key: StoredKey) -> Result<StoredKey, KeyringError> {
+    database
+        .prepare(
+            "INSERT INTO session_keys (version, signing, encryption, created_at) \
+             VALUES (?1, ?2, ?3, ?4) ON CONFLICT (version) DO NOTHING",
+        )
+        .bind(&[
+            key.version.into(),
+            key.signing.into(),
+            key.encryption.into(),
+            (key.created_at as f64).into(),
+        ])
+        .map_err(KeyringError::storage)?
+        .run()
+        .await
+        .map_err(KeyringError::storage)?;
+
+    database
+        .prepare(
+            "SELECT version, signing, encryption, created_at FROM session_keys WHERE version = ?1",
+        )
+        .bind(&[key.version.into()])
+        .map_err(KeyringError::storage)?
+        .first::<StoredKey>(None)
+        .await
+        .map_err(KeyringError::storage)?
+        .ok_or_else(|| KeyringError::storage("the written key could not be read back"))
+}
+
+#[worker::send]
+async fn delete_keys_before(database: &D1Database, version: u32) -> Result<(), KeyringError> {
+    database
+        .prepare("DELETE FROM session_keys WHERE version < ?1")
+        .bind(&[version.into()])
+        .map_err(KeyringError::storage)?
+        .run()
+        .await
+        .map(|_| ())
+        .map_err(KeyringError::storage)
+}
+
+#[async_trait]
+impl KeyringStore for D1KeyringStore {
+    async fn load(&self) -> Result<Vec<StoredKey>, KeyringError> {
+        select_keys(&self.database.0).await
+    }
+
+    async fn insert_if_absent(&self, key: StoredKey) -> Result<StoredKey, KeyringError> {
+        insert_key(&self.database.0, key).await
+    }
+
+    async fn remove_before(&self, version: u32) -> Result<(), KeyringError> {
+        delete_keys_before(&self.database.0, version).await
+    }
+}
@@ -0,0 +1,36 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use super::{KeyringError, KeyringStore, StoredKey};
+
+/// A keyring store that lives only in memory, for tests and local development.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryKeyringStore(Arc<Mutex<BTreeMap<u32, StoredKey>>>);
+
+#[async_trait]
+impl KeyringStore for InMemoryKeyringStore {
+    async fn load(&self) -> Result<Vec<StoredKey>, KeyringError> {
+        Ok(self.0.lock().await.values().cloned().collect())
+    }
+
+    async fn insert_if_absent(&self, key: StoredKey) -> Result<StoredKey, KeyringError> {
+        Ok(self
+            .0
+            .lock()
+            .await
+            .entry(key.version)
+            .or_insert(key)
+            .clone())
+    }
+
+    async fn remove_before(&self, version: u32) -> Result<(), KeyringError> {
+        self.0
+            .lock()
+            .await
+            .retain(|key_version, _| *key_version >= version);
+        Ok(())
+    }
+}
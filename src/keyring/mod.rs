@@ -0,0 +1,404 @@
+pub mod cloudflare;
+pub mod in_memory;
+pub mod secrets;
+
+use async_trait::async_trait;
+use base64::Engine;
+use custom_error::custom_error;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::future::Future;
+use tower_cookies::cookie::{Cookie, CookieJar, Key};
+
+custom_error! {
+    pub KeyringError
+        Storage{message: String} = "the keyring storage failed: {message}",
+        InvalidKey{version: u32} = "session key version {version} is not valid",
+        Empty = "the keyring has no keys",
+        MissingSecret{name: String} = "the secret {name} is not set",
+        InvalidSecret{name: String} = "the secret {name} is not a base64 encoded 64 byte key",
+        MissingKeys = "no session keys are configured, set the SESSION_SIGNING_KEY and \
+            SESSION_ENCRYPTION_KEY secrets or opt into SESSION_KEY_STORAGE",
+        MissingDatabase = "stored session keys need the KEYRING_DB database outside of DEV_MODE",
+}
+
+impl KeyringError {
+    pub(crate) fn storage(error: impl std::fmt::Display) -> Self {
+        KeyringError::Storage {
+            message: error.to_string(),
+        }
+    }
+}
+
+/// A version of the session keys as persisted, master keys in base64.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub version: u32,
+    pub signing: String,
+    pub encryption: String,
+    /// Unix seconds.
+    pub created_at: i64,
+}
+
+impl StoredKey {
+    pub fn generate(version: u32) -> Self {
+        let encode = |key: Key| base64::engine::general_purpose::STANDARD.encode(key.master());
+        Self {
+            version,
+            signing: encode(Key::generate()),
+            encryption: encode(Key::generate()),
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn session_key(&self) -> Result<SessionKey, KeyringError> {
+        let decode = |master: &str| {
+            base64::engine::general_purpose::STANDARD
+                .decode(master)
+                .ok()
+                .and_then(|bytes| Key::try_from(bytes.as_slice()).ok())
+                .ok_or(KeyringError::InvalidKey {
+                    version: self.version,
+                })
+        };
+        Ok(SessionKey {
+            version: self.version,
+            signing: decode(&self.signing)?,
+            encryption: decode(&self.encryption)?,
+            created_at: self.created_at,
+        })
+    }
+}
+
+/// The keys session cookies are signed and encrypted with.
+#[derive(Clone)]
+pub struct SessionKey {
+    pub version: u32,
+    pub signing: Key,
+    pub encryption: Key,
+    /// Unix seconds, 0 for keys that do not know.
+    pub created_at: i64,
+}
+
+impl Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKey")
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where the versions of the session keys are kept. All isolates have to agree on them, so a
+/// version, once written, must never be replaced.
+#[async_trait]
+pub trait KeyringStore: Send + Sync + Debug {
+    async fn load(&self) -> Result<Vec<StoredKey>, KeyringError>;
+
+    /// Stores `key` unless its version is taken, and returns the key that holds the version
+    /// afterwards, so concurrent writers end up with the same key.
+    async fn insert_if_absent(&self, key: StoredKey) -> Result<StoredKey, KeyringError>;
+
+    /// Forgets every version before `version`.
+    async fn remove_before(&self, version: u32) -> Result<(), KeyringError>;
+}
+
+/// The active session key plus the others, newest first. Cookies are sealed with the active
+/// key; cookies sealed with another key are still accepted and re-sealed.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    keys: Vec<SessionKey>,
+}
+
+impl Keyring {
+    pub fn new(mut keys: Vec<SessionKey>) -> Result<Self, KeyringError> {
+        if keys.is_empty() {
+            return Err(KeyringError::Empty);
+        }
+        keys.sort_by(|a, b| b.version.cmp(&a.version));
+        Ok(Self { keys })
+    }
+
+    /// A keyring with a single fresh key, e.g. for tests.
+    pub fn generate() -> Self {
+        Self {
+            keys: vec![StoredKey::generate(1)
+                .session_key()
+                .expect("generated keys are valid")],
+        }
+    }
+
+    fn from_stored(stored: Vec<StoredKey>) -> Result<Self, KeyringError> {
+        Self::new(
+            stored
+                .iter()
+                .map(StoredKey::session_key)
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    }
+
+    /// Loads the keyring, creating its first key if there is none yet.
+    pub async fn load_or_create(store: &dyn KeyringStore) -> Result<Self, KeyringError> {
+        Self::load_or_import(store, || async { Ok(None) }).await
+    }
+
+    /// Loads the keyring. If there is none yet, its first key is the one `import` finds, e.g.
+    /// a key kept by an earlier version, or a new one.
+    pub async fn load_or_import<F, Fut>(
+        store: &dyn KeyringStore,
+        import: F,
+    ) -> Result<Self, KeyringError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<StoredKey>, KeyringError>>,
+    {
+        let stored = store.load().await?;
+        if !stored.is_empty() {
+            return Self::from_stored(stored);
+        }
+
+        let first = match import().await? {
+            Some(imported) => StoredKey {
+                version: 1,
+                ..imported
+            },
+            None => StoredKey::generate(1),
+        };
+        let first = store.insert_if_absent(first).await?;
+        Self::from_stored(vec![first])
+    }
+
+    /// Makes a new key active and keeps `keep_previous` of the older ones. Concurrent rotations
+    /// agree on the new key, as both try to write the same version.
+    ///
+    /// At least the key before the new one is kept: it stays in use until the new key is
+    /// [activated](Self::activated_before) everywhere.
+    pub async fn rotate(
+        store: &dyn KeyringStore,
+        keep_previous: u32,
+    ) -> Result<Self, KeyringError> {
+        let current = store.load().await?;
+        let next = current.iter().map(|key| key.version).max().unwrap_or(0) + 1;
+
+        store.insert_if_absent(StoredKey::generate(next)).await?;
+        store
+            .remove_before(next.saturating_sub(keep_previous.max(1)))
+            .await?;
+
+        Self::from_stored(store.load().await?)
+    }
+
+    /// Seals with the newest key created before `cutoff` and keeps the newer ones for opening
+    /// only, so that isolates caching the keyring all know a new key before it is used.
+    /// Without a key that old, the oldest is active.
+    pub fn activated_before(mut self, cutoff: i64) -> Self {
+        let active = self
+            .keys
+            .iter()
+            .position(|key| key.created_at <= cutoff)
+            .unwrap_or(self.keys.len() - 1);
+        let key = self.keys.remove(active);
+        self.keys.insert(0, key);
+        self
+    }
+
+    pub fn active(&self) -> &SessionKey {
+        &self.keys[0]
+    }
+
+    pub fn previous(&self) -> &[SessionKey] {
+        &self.keys[1..]
+    }
+
+    /// Seals a private cookie sealed with a previous key with the active key instead.
+    /// Returns `None` if the active key opens it already, or no key does.
+    pub(crate) fn reseal(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        let jar = CookieJar::new();
+        if jar
+            .private(&self.active().encryption)
+            .decrypt(cookie.clone())
+            .is_some()
+        {
+            return None;
+        }
+
+        let opened = self
+            .previous()
+            .iter()
+            .find_map(|key| jar.private(&key.encryption).decrypt(cookie.clone()))?;
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.active().encryption).add(opened);
+        jar.get(cookie.name()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use super::in_memory::InMemoryKeyringStore;
+    use super::*;
+
+    fn sealed(key: &SessionKey, value: &str) -> Cookie<'static> {
+        let mut jar = CookieJar::new();
+        jar.private_mut(&key.encryption)
+            .add(Cookie::new("session", value.to_string()));
+        jar.get("session").cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn creates_the_first_key_once() {
+        let store = InMemoryKeyringStore::default();
+
+        let first = Keyring::load_or_create(&store).await.unwrap();
+        let second = Keyring::load_or_create(&store).await.unwrap();
+
+        assert_eq!(first.active().version, 1);
+        assert_eq!(first.active().encryption, second.active().encryption);
+        assert!(first.previous().is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_empty_keyring_imports_its_first_key() {
+        let store = InMemoryKeyringStore::default();
+        let legacy = StoredKey::generate(1);
+
+        let imported = Keyring::load_or_import(&store, || async { Ok(Some(legacy.clone())) })
+            .await
+            .unwrap();
+        assert_eq!(store.load().await.unwrap(), vec![legacy.clone()]);
+        assert_eq!(imported.active().version, 1);
+
+        // a keyring that has keys does not take another
+        let other = StoredKey::generate(1);
+        let loaded = Keyring::load_or_import(&store, || async { Ok(Some(other)) })
+            .await
+            .unwrap();
+        assert_eq!(loaded.active().encryption, imported.active().encryption);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writers_agree_on_a_version() {
+        let store = InMemoryKeyringStore::default();
+
+        let winner = store
+            .insert_if_absent(StoredKey::generate(1))
+            .await
+            .unwrap();
+        let loser = store
+            .insert_if_absent(StoredKey::generate(1))
+            .await
+            .unwrap();
+
+        assert_eq!(winner, loser);
+        assert_eq!(store.load().await.unwrap(), vec![winner]);
+    }
+
+    #[tokio::test]
+    async fn rotation_keeps_previous_keys() {
+        let store = InMemoryKeyringStore::default();
+        let original = Keyring::load_or_create(&store).await.unwrap();
+
+        Keyring::rotate(&store, 2).await.unwrap();
+        Keyring::rotate(&store, 2).await.unwrap();
+        let rotated = Keyring::rotate(&store, 2).await.unwrap();
+
+        assert_eq!(rotated.active().version, 4);
+        assert_eq!(
+            rotated
+                .previous()
+                .iter()
+                .map(|key| key.version)
+                .collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        assert!(rotated
+            .keys
+            .iter()
+            .all(|key| key.encryption != original.active().encryption));
+    }
+
+    #[tokio::test]
+    async fn rotation_keeps_the_key_in_use() {
+        let store = InMemoryKeyringStore::default();
+        let original = Keyring::load_or_create(&store).await.unwrap();
+
+        let rotated = Keyring::rotate(&store, 0).await.unwrap();
+
+        assert_eq!(rotated.active().version, 2);
+        assert_eq!(rotated.previous().len(), 1);
+        assert_eq!(
+            rotated.previous()[0].encryption,
+            original.active().encryption
+        );
+    }
+
+    #[tokio::test]
+    async fn previous_keys_still_open_cookies() {
+        let store = InMemoryKeyringStore::default();
+        let original = Keyring::load_or_create(&store).await.unwrap();
+        let cookie = sealed(original.active(), "session-id");
+
+        let rotated = Keyring::rotate(&store, 1).await.unwrap();
+        let resealed = rotated.reseal(cookie).unwrap();
+
+        let opened = CookieJar::new()
+            .private(&rotated.active().encryption)
+            .decrypt(resealed)
+            .unwrap();
+        assert_eq!(opened.value(), "session-id");
+    }
+
+    #[test]
+    fn new_keys_are_only_active_after_the_cutoff() {
+        let stored = |version: u32, created_at: i64| StoredKey {
+            created_at,
+            ..StoredKey::generate(version)
+        };
+        let keyring =
+            Keyring::from_stored(vec![stored(1, 100), stored(2, 200), stored(3, 300)]).unwrap();
+        let versions = |keyring: &Keyring| {
+            std::iter::once(keyring.active())
+                .chain(keyring.previous())
+                .map(|key| key.version)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            versions(&keyring.clone().activated_before(300)),
+            vec![3, 2, 1]
+        );
+        assert_eq!(
+            versions(&keyring.clone().activated_before(250)),
+            vec![2, 3, 1]
+        );
+        assert_eq!(versions(&keyring.activated_before(50)), vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn current_and_unknown_cookies_are_not_resealed() {
+        let keyring = Keyring::generate();
+
+        assert!(keyring
+            .reseal(sealed(keyring.active(), "session-id"))
+            .is_none());
+        assert!(keyring
+            .reseal(sealed(Keyring::generate().active(), "session-id"))
+            .is_none());
+        assert!(keyring
+            .reseal(Cookie::new("session", "not sealed"))
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_empty_keyrings_and_invalid_keys() {
+        assert!(matches!(Keyring::new(vec![]), Err(KeyringError::Empty)));
+
+        let mut key = StoredKey::generate(7);
+        key.encryption = "dG9vIHNob3J0".to_string();
+        assert!(matches!(
+            Keyring::from_stored(vec![key]),
+            Err(KeyringError::InvalidKey { version: 7 })
+        ));
+    }
+}
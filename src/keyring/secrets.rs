@@ -0,0 +1,161 @@
+use base64::Engine;
+use std::iter::once;
+use tower_cookies::cookie::Key;
+use worker::Env;
+
+use super::{Keyring, KeyringError, SessionKey};
+
+pub const SIGNING_KEY: &str = "SESSION_SIGNING_KEY";
+pub const ENCRYPTION_KEY: &str = "SESSION_ENCRYPTION_KEY";
+// the keys being rotated out, still accepted until they are removed
+pub const PREVIOUS_SIGNING_KEY: &str = "SESSION_PREVIOUS_SIGNING_KEY";
+pub const PREVIOUS_ENCRYPTION_KEY: &str = "SESSION_PREVIOUS_ENCRYPTION_KEY";
+
+/// Master keys are exactly this long, e.g. `openssl rand -base64 64`.
+const KEY_LENGTH: usize = 64;
+
+fn decode_key(name: &str, value: &str) -> Result<Key, KeyringError> {
+    let invalid = || KeyringError::InvalidSecret {
+        name: name.to_string(),
+    };
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value.trim())
+        .map_err(|_| invalid())?;
+    if bytes.len() != KEY_LENGTH {
+        return Err(invalid());
+    }
+    Key::try_from(bytes.as_slice()).map_err(|_| invalid())
+}
+
+fn key_pair(
+    secret: &impl Fn(&str) -> Option<String>,
+    signing: &str,
+    encryption: &str,
+    version: u32,
+) -> Result<Option<SessionKey>, KeyringError> {
+    match (secret(signing), secret(encryption)) {
+        (None, None) => Ok(None),
+        (Some(signing_key), Some(encryption_key)) => Ok(Some(SessionKey {
+            version,
+            signing: decode_key(signing, &signing_key)?,
+            encryption: decode_key(encryption, &encryption_key)?,
+            created_at: 0,
+        })),
+        (Some(_), None) => Err(KeyringError::MissingSecret {
+            name: encryption.to_string(),
+        }),
+        (None, Some(_)) => Err(KeyringError::MissingSecret {
+            name: signing.to_string(),
+        }),
+    }
+}
+
+/// Builds the keyring from the key secrets `secret` looks up, or `None` if none are set.
+pub fn keyring_from_secrets(
+    secret: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Keyring>, KeyringError> {
+    let previous = key_pair(&secret, PREVIOUS_SIGNING_KEY, PREVIOUS_ENCRYPTION_KEY, 1)?;
+    let Some(active) = key_pair(&secret, SIGNING_KEY, ENCRYPTION_KEY, 2)? else {
+        return match previous {
+            Some(_) => Err(KeyringError::MissingSecret {
+                name: SIGNING_KEY.to_string(),
+            }),
+            None => Ok(None),
+        };
+    };
+
+    Keyring::new(once(active).chain(previous).collect()).map(Some)
+}
+
+/// The keyring held in the Worker's secrets, if any.
+pub fn from_env(env: &Env) -> Result<Option<Keyring>, KeyringError> {
+    keyring_from_secrets(|name| env.secret(name).ok().map(|secret| secret.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn encoded_key(byte: u8, length: usize) -> String {
+        base64::engine::general_purpose::STANDARD.encode(vec![byte; length])
+    }
+
+    fn keyring(secrets: &[(&str, String)]) -> Result<Option<Keyring>, KeyringError> {
+        let secrets: HashMap<String, String> = secrets
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        keyring_from_secrets(|name| secrets.get(name).cloned())
+    }
+
+    #[test]
+    fn loads_the_active_key() {
+        let keyring = keyring(&[
+            (SIGNING_KEY, encoded_key(1, 64)),
+            (ENCRYPTION_KEY, encoded_key(2, 64)),
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(keyring.active().signing.master(), &[1; 64]);
+        assert_eq!(keyring.active().encryption.master(), &[2; 64]);
+        assert!(keyring.previous().is_empty());
+    }
+
+    #[test]
+    fn loads_the_previous_key_while_rotating() {
+        let keyring = keyring(&[
+            (SIGNING_KEY, encoded_key(1, 64)),
+            (ENCRYPTION_KEY, encoded_key(2, 64)),
+            (PREVIOUS_SIGNING_KEY, encoded_key(3, 64)),
+            (PREVIOUS_ENCRYPTION_KEY, encoded_key(4, 64)),
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(keyring.active().encryption.master(), &[2; 64]);
+        assert_eq!(keyring.previous().len(), 1);
+        assert_eq!(keyring.previous()[0].encryption.master(), &[4; 64]);
+    }
+
+    #[test]
+    fn no_secrets_means_no_keyring() {
+        assert!(keyring(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_incomplete_secrets() {
+        assert!(matches!(
+            keyring(&[(SIGNING_KEY, encoded_key(1, 64))]),
+            Err(KeyringError::MissingSecret { name }) if name == ENCRYPTION_KEY
+        ));
+        assert!(matches!(
+            keyring(&[
+                (PREVIOUS_SIGNING_KEY, encoded_key(3, 64)),
+                (PREVIOUS_ENCRYPTION_KEY, encoded_key(4, 64)),
+            ]),
+            Err(KeyringError::MissingSecret { name }) if name == SIGNING_KEY
+        ));
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        for encryption_key in [
+            encoded_key(2, 32),
+            encoded_key(2, 65),
+            "not base64!".to_string(),
+        ] {
+            assert!(matches!(
+                keyring(&[
+                    (SIGNING_KEY, encoded_key(1, 64)),
+                    (ENCRYPTION_KEY, encryption_key),
+                ]),
+                Err(KeyringError::InvalidSecret { name }) if name == ENCRYPTION_KEY
+            ));
+        }
+    }
+}
@@ -1,39 +1,40 @@
 mod api;
 mod axum_introspector;
 mod credentials;
+mod http_client;
+mod keyring;
 mod oidc;
 mod session_storage;
+#[cfg(test)]
+mod test_support;
 mod utilities;
 mod zitadel_http;
 
-use crate::api::authenticated::AuthenticatedApi;
-use crate::api::public::PublicApi;
-use crate::axum_introspector::introspection::{
-    IntrospectedUser, IntrospectionState, IntrospectionStateBuilder,
-};
+use crate::api::cors::CorsPolicy;
+use crate::api::csrf::CsrfPolicy;
+use crate::api::response_transforms::ResponseTransforms;
+use crate::api::router::{create_router, AppConfig, AppState};
+use crate::api::routing::RoutingTable;
+use crate::api::upstream::{OriginUpstream, ServiceBindingUpstream, Upstream};
+use crate::api::websocket::WebSocketPolicy;
+use crate::axum_introspector::introspection::{BackgroundTasks, IntrospectionStateBuilder};
+use crate::keyring::cloudflare::{read_legacy_key, D1KeyringStore, KvKeyringStore};
+use crate::keyring::{Keyring, KeyringError, KeyringStore, StoredKey};
+use crate::oidc::introspection::cache::cache_api::CacheApiIntrospectionCache;
 use crate::oidc::introspection::cache::cloudflare::CloudflareIntrospectionCache;
+use crate::oidc::introspection::cache::in_memory::InMemoryIntrospectionCache;
+use crate::oidc::introspection::cache::tiered::TieredIntrospectionCache;
+use crate::session_storage::backend::SessionBackend;
 use crate::session_storage::cloudflare::CloudflareKvStore;
-use axum::extract::FromRef;
-use axum::response::{IntoResponse, Redirect};
-use axum::routing::{any, get};
+use crate::session_storage::cookie::CookieStore;
 use axum::{Router, ServiceExt};
 use bytes::Bytes;
-use http::HeaderName;
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
 use std::fmt::Debug;
-use std::iter::once;
 use std::ops::Deref;
+use std::sync::{Arc, Mutex, OnceLock};
 use tower::ServiceExt as TowerServiceExt;
-use tower_cookies::cookie::SameSite;
-use tower_cookies::CookieManagerLayer;
-use tower_http::cors::CorsLayer;
-use tower_http::propagate_header::PropagateHeaderLayer;
-use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 use tower_service::Service;
-use tower_sessions::cookie::Key;
-use tower_sessions::SessionManagerLayer;
-use tower_sessions_core::Expiry;
 use tracing::instrument::WithSubscriber;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -54,8 +55,24 @@ fn start() {
         .init()
 }
 
-const SIGNING_KEY: &str = "keystore::sig";
-const ENCRYPTION_KEY: &str = "keystore::enc";
+// previous session keys kept by a rotation unless SESSION_KEY_HISTORY says otherwise
+const DEFAULT_SESSION_KEY_HISTORY: u32 = 2;
+
+// the isolate cache is bounded, and its entries expire early so that an eviction from the shared
+// cache, e.g. on logout, reaches every isolate within ISOLATE_INTROSPECTION_CACHE_TTL
+const ISOLATE_INTROSPECTION_CACHE_ENTRIES: usize = 10_000;
+const ISOLATE_INTROSPECTION_CACHE_TTL: time::Duration = time::Duration::seconds(30);
+
+// lives as long as the isolate, shared by every request it serves
+static ISOLATE_INTROSPECTION_CACHE: OnceLock<InMemoryIntrospectionCache> = OnceLock::new();
+
+// stored session keys are reloaded every KEYRING_REFRESH_SECONDS, and a new key is only sealed
+// with once KEY_ACTIVATION_DELAY_SECONDS have passed, so that every isolate knows it by then
+const KEYRING_REFRESH_SECONDS: i64 = 300;
+const KEY_ACTIVATION_DELAY_SECONDS: i64 = 2 * KEYRING_REFRESH_SECONDS;
+
+// the stored keyring and when it was loaded, per isolate
+static ISOLATE_KEYRING: Mutex<Option<(Keyring, i64)>> = Mutex::new(None);
 
 // main entrypoint
 
@@ -67,7 +84,7 @@ async fn fetch(
 ) -> Result<axum::http::Response<axum::body::Body>> {
     console_error_panic_hook::set_once();
 
-    Ok(route(req, _env).await)
+    route(req, _env, _ctx).await
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -76,29 +93,22 @@ struct Callback {
     state: String,
 }
 
-#[derive(Clone)]
-struct AppState {
-    introspection_state: IntrospectionState,
-    env: Env,
-    session_store: CloudflareKvStore,
-}
-impl FromRef<AppState> for IntrospectionState {
-    fn from_ref(input: &AppState) -> Self {
-        input.introspection_state.clone()
-    }
-}
-
-async fn route(req: HttpRequest, _env: Env) -> axum_core::response::Response {
-    let kv = _env.kv("KV_STORAGE").unwrap();
-    let cache = CloudflareIntrospectionCache::new(kv.clone());
+async fn route(req: HttpRequest, _env: Env, ctx: Context) -> Result<axum_core::response::Response> {
+    let isolate_cache = ISOLATE_INTROSPECTION_CACHE
+        .get_or_init(|| {
+            InMemoryIntrospectionCache::new()
+                .with_max_entries(ISOLATE_INTROSPECTION_CACHE_ENTRIES)
+                .with_max_ttl(ISOLATE_INTROSPECTION_CACHE_TTL)
+        })
+        .clone();
 
-    let introspection_state = IntrospectionStateBuilder::new(
+    let mut introspection_state_builder = IntrospectionStateBuilder::new(
         _env.secret("AUTH_SERVER_URL")
             .unwrap()
             .to_string()
             .as_str(),
-    )
-    .with_basic_auth(
+    );
+    introspection_state_builder.with_basic_auth(
         _env.secret("CLIENT_ID")
             .unwrap()
             .to_string()
@@ -107,174 +117,260 @@ async fn route(req: HttpRequest, _env: Env) -> axum_core::response::Response {
             .unwrap()
             .to_string()
             .as_str(),
-    )
-    .with_introspection_cache(cache)
-    .build()
-    .await
-    .unwrap();
+    );
 
-    let session_store = CloudflareKvStore::new(kv.clone());
+    // Optional bounds on how long cached introspection results are trusted, in seconds
+    if let Some(max_ttl) = var_seconds(&_env, "INTROSPECTION_CACHE_MAX_TTL") {
+        introspection_state_builder.with_max_cache_ttl(max_ttl);
+    }
+    if let Some(leeway) = var_seconds(&_env, "INTROSPECTION_CACHE_LEEWAY") {
+        introspection_state_builder.with_cache_leeway(leeway);
+    }
 
-    let state = AppState {
-        introspection_state,
-        session_store: session_store.clone(),
-        env: _env.clone(),
+    // Optional grace period (seconds) during which users are still let in from their last
+    // introspection result while the IdP is down
+    if let Some(grace) = var_seconds(&_env, "INTROSPECTION_STALE_IF_ERROR") {
+        let ctx = worker::send::SendWrapper::new(ctx);
+        introspection_state_builder
+            .with_stale_if_error(grace)
+            .with_background_tasks(BackgroundTasks::new(move |task| ctx.wait_until(task)));
+    }
+
+    // INTROSPECTION_CACHE selects the shared tier behind the isolate cache: "kv" (default) or "cache_api".
+    // Without a KV_STORAGE binding, e.g. next to SESSION_STORE="cookie", the default is the isolate cache alone
+    let shared_cache = _env.var("INTROSPECTION_CACHE").map(|v| v.to_string()).ok();
+    match (shared_cache.as_deref(), _env.kv("KV_STORAGE")) {
+        (Some("cache_api"), _) => introspection_state_builder.with_introspection_cache(
+            TieredIntrospectionCache::new(isolate_cache, CacheApiIntrospectionCache::new()),
+        ),
+        (_, Ok(kv_storage)) => {
+            introspection_state_builder.with_introspection_cache(TieredIntrospectionCache::new(
+                isolate_cache,
+                CloudflareIntrospectionCache::new(kv_storage),
+            ))
+        }
+        (_, Err(_)) => introspection_state_builder.with_introspection_cache(isolate_cache),
     };
 
-    let dev_mode = _env.var("DEV_MODE").unwrap().to_string(); // Example check
+    let introspection_state = introspection_state_builder.build().await.unwrap();
 
-    let is_dev = dev_mode == "true";
+    let config = AppConfig::from_env(&_env);
 
-    let keystore = _env.kv("KV_STORAGE").unwrap();
+    // The SESSION_SIGNING_KEY and SESSION_ENCRYPTION_KEY secrets hold the session keys. Keys
+    // generated into KEYRING_DB are an opt-in (SESSION_KEY_STORAGE="true"), and the fallback in
+    // DEV_MODE, where the KEYRING_KV namespace stands in for KEYRING_DB
+    let keyring = load_keyring(&_env, config.dev_mode)
+        .await
+        .map_err(|error| {
+            console_error!("refusing to serve without session keys: {}", error);
+            Error::RustError(error.to_string())
+        })?;
 
-    let signing = if let Some(bytes) = keystore.get(SIGNING_KEY).bytes().await.unwrap() {
-        Key::derive_from(bytes.as_slice())
-    } else {
-        let key = Key::generate();
-        keystore
-            .put_bytes(SIGNING_KEY, key.master())
-            .unwrap()
-            .execute()
-            .await
-            .unwrap();
-        key
+    // SESSION_STORE="cookie" seals whole sessions into cookies instead of keeping them in KV_STORAGE
+    let session_store = match _env.var("SESSION_STORE").map(|v| v.to_string()).ok().as_deref() {
+        Some("cookie") => SessionBackend::Cookie(CookieStore::new(keyring.clone())),
+        _ => SessionBackend::Kv(CloudflareKvStore::new(_env.kv("KV_STORAGE").unwrap())),
     };
 
-    let encryption = if let Some(bytes) = keystore.get(ENCRYPTION_KEY).bytes().await.unwrap() {
-        Key::derive_from(bytes.as_slice())
+    // PROXY_ROUTES routes authenticated requests to several upstreams by host and path prefix,
+    // PROXY_ORIGIN sends them to a plain HTTP origin, otherwise they go to the PROXY_TARGET binding.
+    // Origins only get the Authorization header if PROXY_ORIGIN_FORWARD_AUTHORIZATION is "true"
+    let upstream: Arc<dyn Upstream> = if let Ok(routes) = _env.var("PROXY_ROUTES") {
+        let table = RoutingTable::from_json(&routes.to_string(), |name| {
+            let fetcher = _env.service(name).ok()?;
+            Some(Arc::new(ServiceBindingUpstream::new(fetcher)) as Arc<dyn Upstream>)
+        })
+        .map_err(|error| invalid_config("PROXY_ROUTES", error))?;
+        Arc::new(table)
+    } else if let Ok(origin) = _env.var("PROXY_ORIGIN") {
+        let forward_authorization = _env
+            .var("PROXY_ORIGIN_FORWARD_AUTHORIZATION")
+            .is_ok_and(|forward| forward.to_string() == "true");
+        let origin = origin
+            .to_string()
+            .parse()
+            .map_err(|error| invalid_config("PROXY_ORIGIN", error))?;
+        Arc::new(OriginUpstream::new(origin).with_forward_authorization(forward_authorization))
     } else {
-        let key = Key::generate();
-        keystore
-            .put_bytes(ENCRYPTION_KEY, key.master())
-            .unwrap()
-            .execute()
-            .await
-            .unwrap();
-        key
+        Arc::new(ServiceBindingUpstream::new(
+            _env.service("PROXY_TARGET").unwrap(),
+        ))
     };
 
-    let host_string = _env.secret("APP_URL").unwrap().to_string().as_str().to_owned();
+    // Proxied WebSockets re-check their session every WEBSOCKET_REVALIDATE_EVERY seconds ("0" turns
+    // this off), and before every client message if WEBSOCKET_REVALIDATE_ON_MESSAGE is "true"
+    let mut websocket_policy = WebSocketPolicy::default();
+    if let Some(every) = var_seconds(&_env, "WEBSOCKET_REVALIDATE_EVERY") {
+        websocket_policy.revalidate_every =
+            every.is_positive().then(|| std::time::Duration::from_secs(every.whole_seconds() as u64));
+    }
+    if let Ok(on_message) = _env.var("WEBSOCKET_REVALIDATE_ON_MESSAGE") {
+        websocket_policy.revalidate_on_message = on_message.to_string() == "true";
+    }
+
+    // PROXY_RESPONSE_TRANSFORMS post-processes upstream responses, e.g. to rewrite redirects to
+    // the internal service or to send users back to the login when the upstream answers 401
+    let response_transforms = match _env.var("PROXY_RESPONSE_TRANSFORMS") {
+        Ok(transforms) => ResponseTransforms::from_json(&transforms.to_string())
+            .map_err(|error| invalid_config("PROXY_RESPONSE_TRANSFORMS", error))?,
+        Err(_) => ResponseTransforms::default(),
+    };
 
-    let cookie_host_uri = host_string.parse::<http::Uri>().unwrap();
+    let mut state = AppState::new(
+        config,
+        introspection_state,
+        session_store,
+        upstream,
+    )
+    .with_websocket_policy(websocket_policy)
+    .with_response_transforms(response_transforms);
 
-    let mut cookie_host = cookie_host_uri.authority().unwrap().to_string();
-    
-    if cookie_host.starts_with("localhost:") {
-        cookie_host = "localhost".to_string();
+    // CORS_POLICY allow-lists the origins that may make cross-origin requests, none by default
+    let mut credentialed_origins = Vec::new();
+    if let Ok(cors_policy) = _env.var("CORS_POLICY") {
+        let cors_policy = CorsPolicy::from_json(&cors_policy.to_string())
+            .map_err(|error| invalid_config("CORS_POLICY", error))?;
+        let cors = cors_policy
+            .layer()
+            .map_err(|error| invalid_config("CORS_POLICY", error))?;
+        credentialed_origins = cors_policy.credentialed_origins();
+        state = state.with_cors(cors);
     }
 
-    let session_layer = SessionManagerLayer::new(state.session_store.clone())
-        .with_name("session")
-        .with_expiry(Expiry::OnSessionEnd)
-        .with_domain(cookie_host)
-        .with_same_site(SameSite::Lax)
-        .with_signed(signing)
-        .with_private(encryption)
-        .with_path("/")
-        .with_secure(!is_dev)
-        .with_always_save(false);
-
-    async fn handle_introspection_errors(
-        mut response: axum_core::response::Response,
-    ) -> axum_core::response::Response {
-        let x_error_header_value = response
-            .headers()
-            .get("x-introspection-error")
-            .and_then(|header_value| header_value.to_str().ok());
-
-        // not used but is available
-        let x_session_header_value = response
-            .headers()
-            .get("x-session")
-            .and_then(|header_value| header_value.to_str().ok());
-
-        match response.status() {
-            http::StatusCode::UNAUTHORIZED => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "unauthorized" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::BAD_REQUEST => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "invalid schema"
-                        || x_error == "invalid header"
-                        || x_error == "introspection error"
-                    {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::FORBIDDEN => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "user is inactive" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::NOT_FOUND => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "user was not found" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            http::StatusCode::INTERNAL_SERVER_ERROR => {
-                if let Some(x_error) = x_error_header_value {
-                    if x_error == "missing config" {
-                        return Redirect::to("/login").into_response();
-                    }
-                }
-                response
-            }
-            _ => response,
-        }
+    // CSRF_POLICY configures how state-changing requests authenticated by the session cookie are
+    // checked, e.g. trusted origins and exempt paths; the checks are on by default. Origins CORS
+    // lets make requests with the session are trusted as well, or their requests would be refused.
+    let csrf_policy = match _env.var("CSRF_POLICY") {
+        Ok(csrf_policy) => CsrfPolicy::from_json(&csrf_policy.to_string())
+            .map_err(|error| invalid_config("CSRF_POLICY", error))?,
+        Err(_) => CsrfPolicy::default(),
+    };
+    state = state.with_csrf_policy(csrf_policy.with_trusted_origins(credentialed_origins));
+
+    // SECURITY_HEADERS adjusts the strict headers of edge responses and opts proxied responses in
+    if let Ok(security_headers) = _env.var("SECURITY_HEADERS") {
+        let security_headers = state
+            .security_headers
+            .clone()
+            .configure(&security_headers.to_string())
+            .map_err(|error| invalid_config("SECURITY_HEADERS", error))?;
+        state = state.with_security_headers(security_headers);
     }
 
-    let mut router = Router::new()
-        .route("/", any(AuthenticatedApi::proxy))
-        .route("/login", get(PublicApi::login_page)) // Add the login page route
-        .route("/login/callback", get(PublicApi::callback))
-        .route("/login/authorize", get(PublicApi::authorize))
-        .route("/api/whoami", get(whoami))
-        .route("/*path", any(AuthenticatedApi::proxy))
-        .layer(PropagateHeaderLayer::new(HeaderName::from_static(
-            "x-request-id",
-        )))
-        .layer(axum::middleware::map_response(handle_introspection_errors))
-        .with_state(state)
-        .layer(session_layer)
-        .layer(CookieManagerLayer::new())
-        .layer(CorsLayer::very_permissive())
-        .layer(SetSensitiveRequestHeadersLayer::new(once(
-            http::header::AUTHORIZATION,
-        )));
-
-    router
+    let mut router = create_router(state, keyring);
+
+    Ok(router
         .as_service()
         .ready()
         .await
         .unwrap()
         .oneshot(req)
         .await
-        .unwrap()
+        .unwrap())
+}
+
+// a setting the worker cannot run with fails the request with a 500, and is logged so that it
+// shows up in the worker's logs rather than as a panic
+fn invalid_config(name: &str, error: impl std::fmt::Display) -> Error {
+    let message = format!("{} is not valid: {}", name, error);
+    console_error!("{}", message);
+    Error::RustError(message)
+}
+
+async fn load_keyring(env: &Env, dev_mode: bool) -> std::result::Result<Keyring, KeyringError> {
+    if let Some(keyring) = keyring::secrets::from_env(env)? {
+        return Ok(keyring);
+    }
+    if !session_key_storage(env) && !dev_mode {
+        return Err(KeyringError::MissingKeys);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let cached = ISOLATE_KEYRING
+        .lock()
+        .expect("the keyring lock is not poisoned")
+        .clone()
+        .filter(|(_, loaded_at)| now - loaded_at < KEYRING_REFRESH_SECONDS);
+    let keyring = match cached {
+        Some((keyring, _)) => keyring,
+        None => {
+            let store = keyring_store(env, dev_mode)?;
+            let keyring = Keyring::load_or_import(store.as_ref(), || legacy_key(env)).await?;
+            *ISOLATE_KEYRING
+                .lock()
+                .expect("the keyring lock is not poisoned") = Some((keyring.clone(), now));
+            keyring
+        }
+    };
+    Ok(keyring.activated_before(now - KEY_ACTIVATION_DELAY_SECONDS))
 }
 
-async fn whoami(
-    session: tower_sessions::Session,
-    introspected_user: IntrospectedUser,
-) -> impl IntoResponse {
-    console_log!("calling whoami");
-    to_string(&introspected_user).unwrap()
+fn session_key_storage(env: &Env) -> bool {
+    env.var("SESSION_KEY_STORAGE")
+        .is_ok_and(|value| value.to_string() == "true")
 }
 
-impl FromRef<AppState> for CloudflareKvStore {
-    fn from_ref(input: &AppState) -> Self {
-        input.session_store.clone()
+// rotates the session keys on the cron triggers in wrangler.jsonc; keys held in secrets are
+// rotated by hand instead
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, _env: Env, _ctx: ScheduleContext) {
+    if !session_key_storage(&_env) {
+        return;
     }
+
+    let keep_previous = _env
+        .var("SESSION_KEY_HISTORY")
+        .ok()
+        .and_then(|value| value.to_string().parse::<u32>().ok())
+        .unwrap_or(DEFAULT_SESSION_KEY_HISTORY);
+
+    let dev_mode = _env
+        .var("DEV_MODE")
+        .is_ok_and(|value| value.to_string() == "true");
+    let rotated = async {
+        let store = keyring_store(&_env, dev_mode)?;
+        // the keys of earlier versions become version 1 before the first rotation
+        Keyring::load_or_import(store.as_ref(), || legacy_key(&_env)).await?;
+        Keyring::rotate(store.as_ref(), keep_previous).await
+    }
+    .await;
+    match rotated {
+        Ok(keyring) => console_log!(
+            "rotated the session keys to version {}",
+            keyring.active().version
+        ),
+        Err(error) => console_error!("rotating the session keys failed: {}", error),
+    }
+}
+
+// KEYRING_DB (D1) agrees on new keys atomically across isolates. KV can not, so the KEYRING_KV
+// namespace is only the fallback in DEV_MODE, kept apart from the session data in KV_STORAGE
+fn keyring_store(
+    env: &Env,
+    dev_mode: bool,
+) -> std::result::Result<Box<dyn KeyringStore>, KeyringError> {
+    if let Ok(database) = env.d1("KEYRING_DB") {
+        return Ok(Box::new(D1KeyringStore::new(database)));
+    }
+    if !dev_mode {
+        return Err(KeyringError::MissingDatabase);
+    }
+    let keyring_kv = env.kv("KEYRING_KV").map_err(KeyringError::storage)?;
+    Ok(Box::new(KvKeyringStore::new(keyring_kv)))
+}
+
+// earlier versions kept a single key pair in KV_STORAGE; an empty keyring starts from it so that
+// their sessions survive the upgrade
+async fn legacy_key(env: &Env) -> std::result::Result<Option<StoredKey>, KeyringError> {
+    match env.kv("KV_STORAGE") {
+        Ok(kv_storage) => read_legacy_key(kv_storage).await,
+        Err(_) => Ok(None),
+    }
+}
+
+fn var_seconds(env: &Env, name: &str) -> Option<time::Duration> {
+    env.var(name)
+        .ok()
+        .and_then(|value| value.to_string().parse::<i64>().ok())
+        .map(time::Duration::seconds)
 }
@@ -1,5 +1,4 @@
 use custom_error::custom_error;
-use openidconnect::reqwest::async_http_client;
 use openidconnect::{
     core::{
         CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
@@ -11,6 +10,8 @@ use openidconnect::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::http_client::HttpClient;
+
 custom_error! {
     pub DiscoveryError
         IssuerUrl{source: url::ParseError} = "could not parse issuer url: {source}",
@@ -18,9 +19,16 @@ custom_error! {
 }
 
 pub async fn discover(authority: &str) -> Result<ZitadelProviderMetadata, DiscoveryError> {
+    discover_with(&HttpClient::default(), authority).await
+}
+
+pub async fn discover_with(
+    http_client: &HttpClient,
+    authority: &str,
+) -> Result<ZitadelProviderMetadata, DiscoveryError> {
     let issuer = IssuerUrl::new(authority.to_string())
         .map_err(|source| DiscoveryError::IssuerUrl { source })?;
-    ZitadelProviderMetadata::discover_async(issuer, async_http_client)
+    ZitadelProviderMetadata::discover_async(issuer, |request| http_client.execute(request))
         .await
         .map_err(|_| DiscoveryError::DiscoveryDocument)
 }
@@ -56,9 +64,56 @@ pub type ZitadelProviderMetadata = ProviderMetadata<
 mod tests {
     #![allow(clippy::all)]
 
+    use crate::http_client::scripted::ScriptedTransport;
+    use crate::test_support::MockOidcProvider;
+
     use super::*;
 
-    const ZITADEL_URL: &str = "https://zitadel-libraries-l8boqa.zitadel.cloud";
+    const DISCOVERY_DOCUMENT: &str = r#"{
+        "issuer": "https://idp.invalid",
+        "authorization_endpoint": "https://idp.invalid/oauth/v2/authorize",
+        "token_endpoint": "https://idp.invalid/oauth/v2/token",
+        "introspection_endpoint": "https://idp.invalid/oauth/v2/introspect",
+        "jwks_uri": "https://idp.invalid/oauth/v2/keys",
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"]
+    }"#;
+
+    #[tokio::test]
+    async fn discovery_succeeds_through_transport() {
+        let transport = ScriptedTransport::new()
+            .respond(200, DISCOVERY_DOCUMENT)
+            .respond(200, r#"{"keys":[]}"#);
+        let http_client = HttpClient::new().with_transport(transport);
+
+        let result = discover_with(&http_client, "https://idp.invalid")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result
+                .additional_metadata()
+                .introspection_endpoint
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "https://idp.invalid/oauth/v2/introspect"
+        );
+    }
+
+    #[tokio::test]
+    async fn discovery_fails_on_error_response() {
+        let transport = ScriptedTransport::new().respond(404, "not found");
+        let http_client = HttpClient::new().with_transport(transport);
+
+        let result = discover_with(&http_client, "https://idp.invalid").await;
+
+        assert!(matches!(
+            result.unwrap_err(),
+            DiscoveryError::DiscoveryDocument
+        ));
+    }
 
     #[tokio::test]
     async fn discovery_fails_with_invalid_url() {
@@ -73,7 +128,12 @@ mod tests {
 
     #[tokio::test]
     async fn discovery_fails_with_invalid_discovery() {
-        let result = discover("https://smartive.ch").await;
+        let provider = MockOidcProvider::new();
+        let result = discover_with(
+            &provider.http_client(),
+            &provider.endpoint("/not-an-issuer"),
+        )
+        .await;
 
         assert!(result.is_err());
         assert!(matches!(
@@ -84,11 +144,14 @@ mod tests {
 
     #[tokio::test]
     async fn discovery_succeeds() {
-        let result = discover(ZITADEL_URL).await.unwrap();
+        let provider = MockOidcProvider::new();
+        let result = discover_with(&provider.http_client(), provider.issuer())
+            .await
+            .unwrap();
 
         assert_eq!(
             result.token_endpoint().unwrap().to_string(),
-            "https://zitadel-libraries-l8boqa.zitadel.cloud/oauth/v2/token".to_string()
+            provider.endpoint("/oauth/v2/token")
         );
     }
 }
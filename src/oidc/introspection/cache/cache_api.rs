@@ -0,0 +1,222 @@
+use async_trait::async_trait;
+use openidconnect::TokenIntrospectionResponse;
+use time::Duration;
+use worker::{Cache, Response as WorkerResponse};
+
+use crate::oidc::introspection::cache::{inactive_response, token_key, CacheEntry, IntrospectionCache, DEFAULT_NEGATIVE_TTL};
+
+/// Synthetic origin for cache entries, never resolved.
+const CACHE_ORIGIN: &str = "https://introspection-cache.invalid";
+
+/// Stores introspection results in the per-colo Workers Cache API.
+///
+/// Entries are synthetic responses keyed by the token digest whose `Cache-Control: max-age`
+/// follows the entry's `cached_until`. The Cache API is cheaper and faster than KV for short-lived data but
+/// is neither global nor enumerable, so `clear` is a no-op and entries simply run out.
+/// Note that `cache.put` is a no-op on `*.workers.dev` routes.
+#[derive(Clone, Debug)]
+pub struct CacheApiIntrospectionCache {
+    cache_name: Option<String>,
+    negative_ttl: Duration,
+}
+
+impl CacheApiIntrospectionCache {
+    /// Uses the default cache of the zone.
+    pub fn new() -> Self {
+        Self {
+            cache_name: None,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+        }
+    }
+
+    /// Uses a named cache (`caches.open(name)`) instead of the default one.
+    pub fn with_cache_name(mut self, cache_name: &str) -> Self {
+        self.cache_name = Some(cache_name.to_string());
+        self
+    }
+
+    /// Sets how long inactive introspection results are remembered.
+    /// A zero (or negative) duration disables negative caching.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+}
+
+impl Default for CacheApiIntrospectionCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn entry_url(token: &str) -> String {
+    format!("{}/active/{}", CACHE_ORIGIN, token_key(token))
+}
+
+fn negative_entry_url(token: &str) -> String {
+    format!("{}/inactive/{}", CACHE_ORIGIN, token_key(token))
+}
+
+/// Seconds until the entry runs out, or `None` if it already has or the token has no `exp`.
+fn max_age(entry: &CacheEntry) -> Option<i64> {
+    let exp = entry.response.exp()?;
+    let max_age = entry.cached_until.min(exp.timestamp()) - chrono::Utc::now().timestamp();
+    (max_age > 0).then_some(max_age)
+}
+
+#[async_trait]
+impl IntrospectionCache for CacheApiIntrospectionCache {
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry> {
+        get(self.cache_name.clone(), token).await
+    }
+
+    async fn set_entry(&self, token: &str, entry: CacheEntry) {
+        if !entry.response.active() {
+            if self.negative_ttl.is_positive() {
+                let now = chrono::Utc::now().timestamp();
+                let cached_until = entry
+                    .cached_until
+                    .min(now + self.negative_ttl.whole_seconds());
+                if cached_until > now {
+                    // Only the expiry is stored, the response handed out on a hit is always the same.
+                    put(
+                        self.cache_name.clone(),
+                        negative_entry_url(token),
+                        cached_until.to_string(),
+                        cached_until - now,
+                    )
+                    .await;
+                }
+            }
+            return;
+        }
+
+        let Some(max_age) = max_age(&entry) else {
+            return;
+        };
+        if let Ok(json) = serde_json::to_string(&entry) {
+            put(self.cache_name.clone(), entry_url(token), json, max_age).await;
+        }
+    }
+
+    async fn remove(&self, token: &str) {
+        delete(self.cache_name.clone(), token).await;
+    }
+
+    async fn remove_by_subject(&self, _sub: &str) {
+        // The Cache API can't be enumerated, so entries can only be evicted by token.
+        // Use a max cache TTL to bound how long they outlive a revocation.
+        worker::console_warn!(
+            "the Cache API introspection cache cannot evict by subject, entries run out with their max-age"
+        );
+    }
+
+    async fn clear(&self) {
+        // The Cache API can't be enumerated, entries run out with their max-age.
+    }
+}
+
+async fn open(cache_name: Option<String>) -> Cache {
+    match cache_name {
+        Some(name) => Cache::open(name).await,
+        None => Cache::default(),
+    }
+}
+
+#[worker::send]
+async fn put(cache_name: Option<String>, url: String, body: String, max_age: i64) {
+    let Ok(mut response) = WorkerResponse::ok(body) else {
+        return;
+    };
+    let headers = response.headers_mut();
+    let _ = headers.set("Content-Type", "application/json");
+    let _ = headers.set("Cache-Control", &format!("max-age={}", max_age));
+
+    open(cache_name).await.put(url, response).await.unwrap_or(());
+}
+
+#[worker::send]
+async fn get(cache_name: Option<String>, token: &str) -> Option<CacheEntry> {
+    let cache = open(cache_name).await;
+    let now = chrono::Utc::now().timestamp();
+
+    if let Ok(Some(mut response)) = cache.get(entry_url(token), false).await {
+        return match response.text().await {
+            // entries written before `cached_until` was stored don't parse and count as a miss
+            Ok(data) => serde_json::from_str::<CacheEntry>(&data)
+                .ok()
+                .filter(|entry| entry.cached_until >= now),
+            Err(_) => None,
+        };
+    }
+
+    let Ok(Some(mut response)) = cache.get(negative_entry_url(token), false).await else {
+        return None;
+    };
+    let cached_until = response
+        .text()
+        .await
+        .ok()
+        .and_then(|data| data.parse::<i64>().ok())
+        // markers written before the expiry was stored run out with their max-age
+        .unwrap_or(i64::MAX);
+    (cached_until >= now).then(|| CacheEntry {
+        response: inactive_response(),
+        cached_until,
+    })
+}
+
+#[worker::send]
+async fn delete(cache_name: Option<String>, token: &str) {
+    let cache = open(cache_name).await;
+
+    let _ = cache.delete(entry_url(token), false).await;
+    let _ = cache.delete(negative_entry_url(token), false).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+
+    use crate::oidc::introspection::cache::Response;
+
+    use super::*;
+
+    #[test]
+    fn entry_urls_are_distinct_and_hashed() {
+        assert_ne!(entry_url("token"), negative_entry_url("token"));
+        assert!(!entry_url("secret-token").contains("secret-token"));
+        assert!(entry_url("token").starts_with(CACHE_ORIGIN));
+    }
+
+    #[test]
+    fn max_age_follows_exp() {
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now() + TimeDelta::try_seconds(120).unwrap()));
+
+        let max_age = max_age(&CacheEntry::new(response)).unwrap();
+        assert!(max_age > 110 && max_age <= 120);
+    }
+
+    #[test]
+    fn max_age_follows_cached_until() {
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now() + TimeDelta::try_seconds(120).unwrap()));
+        let entry = CacheEntry {
+            response,
+            cached_until: Utc::now().timestamp() + 30,
+        };
+
+        let max_age = max_age(&entry).unwrap();
+        assert!(max_age > 20 && max_age <= 30);
+    }
+
+    #[test]
+    fn max_age_is_none_for_expired_or_missing_exp() {
+        let mut response = Response::new(true, Default::default());
+        assert!(max_age(&CacheEntry::new(response.clone())).is_none());
+
+        response.set_exp(Some(Utc::now() - TimeDelta::try_seconds(10).unwrap()));
+        assert!(max_age(&CacheEntry::new(response)).is_none());
+    }
+}
@@ -2,25 +2,45 @@ use std::fmt::{Debug, Formatter};
 use async_trait::async_trait;
 // use axum_core::response::IntoResponse;
 use openidconnect::TokenIntrospectionResponse;
-use crate::oidc::introspection::cache::{IntrospectionCache, Response};
+use time::Duration;
+use crate::oidc::introspection::cache::{inactive_response, token_key, CacheEntry, IntrospectionCache, DEFAULT_NEGATIVE_TTL};
 // use crate::session_storage::cloudflare::CloudflareKvStore;
 
+const CACHE_PREFIX: &str = "introspectioncache::";
+const NEGATIVE_CACHE_PREFIX: &str = "introspectioncache-inactive::";
+const SUBJECT_INDEX_PREFIX: &str = "introspectioncache-subject::";
+
+/// KV rejects expiration TTLs shorter than this. Entries meant to run out sooner are kept
+/// this long and checked against their `cached_until` on read.
+const MIN_KV_TTL_SECONDS: u64 = 60;
 
 /// for storing introspection results.
 pub struct CloudflareIntrospectionCache {
     kv: worker::kv::KvStore,
+    negative_ttl: Duration,
 }
 
 impl CloudflareIntrospectionCache {
     /// Creates a new instance of `CloudflareIntrospectionCache` with the given KV namespace.
     pub fn new(kv: worker::kv::KvStore) -> Self {
-        Self { kv }
+        Self {
+            kv,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+        }
+    }
+
+    /// Sets how long inactive introspection results are remembered.
+    /// A zero (or negative) duration disables negative caching.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
     }
 }
 
 impl std::fmt::Debug for CloudflareIntrospectionCache {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CloudflareKvStore")
+            .field("negative_ttl", &self.negative_ttl)
             .finish_non_exhaustive()
         // Probably want to handle this differently
         // .field("kvstore", "KVStorePlaceholder")
@@ -28,19 +48,61 @@ impl std::fmt::Debug for CloudflareIntrospectionCache {
 }
 
 
+/// KV key names are limited to 512 bytes and show up in namespace listings,
+/// so entries are keyed by the token digest rather than the token itself.
 fn prefixed_key(token: &str) -> String {
-    format!("introspectioncache::{}", token)
+    format!("{}{}", CACHE_PREFIX, token_key(token))
+}
+
+fn negative_key(token: &str) -> String {
+    format!("{}{}", NEGATIVE_CACHE_PREFIX, token_key(token))
+}
+
+/// Prefix of the index entries pointing from a subject to its cached tokens.
+/// Subjects are hashed as well to keep key names bounded.
+fn subject_index_prefix(sub: &str) -> String {
+    format!("{}{}::", SUBJECT_INDEX_PREFIX, token_key(sub))
+}
+
+fn subject_index_key(sub: &str, token: &str) -> String {
+    format!("{}{}", subject_index_prefix(sub), token_key(token))
+}
+
+/// Seconds KV keeps an entry served until `cached_until`, at least KV's minimum.
+fn kv_ttl(cached_until: i64, now: i64) -> u64 {
+    cached_until
+        .saturating_sub(now)
+        .max(0)
+        .unsigned_abs()
+        .max(MIN_KV_TTL_SECONDS)
 }
 
 #[async_trait]
 impl IntrospectionCache for CloudflareIntrospectionCache {
-    async fn get(&self, token: &str) -> Option<Response> {
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry> {
         get(self.kv.clone(), token).await
     }
 
-    async fn set(&self, token: &str, response: Response) {
+    async fn set_entry(&self, token: &str, entry: CacheEntry) {
+        if !entry.response.active() {
+            if self.negative_ttl.is_positive() {
+                let negative_until =
+                    chrono::Utc::now().timestamp() + self.negative_ttl.whole_seconds();
+                let cached_until = entry.cached_until.min(negative_until);
+                set_negative(self.kv.clone(), token, cached_until).await;
+            }
+            return;
+        }
         // Check if the token is active and has an expiration time
-       set(self.kv.clone(), token, response).await;
+       set(self.kv.clone(), token, entry).await;
+    }
+
+    async fn remove(&self, token: &str) {
+        remove(self.kv.clone(), token).await
+    }
+
+    async fn remove_by_subject(&self, sub: &str) {
+        remove_by_subject(self.kv.clone(), sub).await
     }
 
     async fn clear(&self) {
@@ -49,33 +111,179 @@ impl IntrospectionCache for CloudflareIntrospectionCache {
 }
 
 #[worker::send]
-async fn set(kv: worker::kv::KvStore, token: &str, response: Response) {
-    if response.active() && response.exp().is_some() {
-        // Serialize the response to JSON
-        if let Ok(json) = serde_json::to_string(&response) {
-            // Set the expiration time
-            let expiration = response.exp().unwrap();
-            // Store the serialized response in the KV store with expiration
-            kv.put(prefixed_key(token).as_str(), json).unwrap().expiration(expiration.timestamp().unsigned_abs()).execute().await.unwrap_or(());
+async fn set(kv: worker::kv::KvStore, token: &str, entry: CacheEntry) {
+    let Some(exp) = entry.response.exp() else {
+        return;
+    };
+    let now = chrono::Utc::now().timestamp();
+    let entry = CacheEntry {
+        cached_until: entry.cached_until.min(exp.timestamp()),
+        ..entry
+    };
+    if entry.cached_until <= now {
+        return;
+    }
+    let ttl = kv_ttl(entry.cached_until, now);
+
+    // Serialize the entry to JSON
+    if let Ok(json) = serde_json::to_string(&entry) {
+        put(&kv, &prefixed_key(token), json, ttl).await;
+        // Index the entry by subject so it can be evicted without knowing the token
+        if let Some(sub) = entry.response.sub() {
+            put(&kv, &subject_index_key(sub, token), String::new(), ttl).await;
         }
     }
 }
 
+#[worker::send]
+async fn set_negative(kv: worker::kv::KvStore, token: &str, cached_until: i64) {
+    let now = chrono::Utc::now().timestamp();
+    if cached_until <= now {
+        return;
+    }
+    // Only the expiry is stored, the response handed out on a hit is always the same.
+    let ttl = kv_ttl(cached_until, now);
+    put(&kv, &negative_key(token), cached_until.to_string(), ttl).await;
+}
+
+/// Writes a value KV expires after `ttl` seconds. A failed write only costs a cache miss.
+async fn put(kv: &worker::kv::KvStore, key: &str, value: String, ttl: u64) {
+    let result = match kv.put(key, value) {
+        Ok(put) => put.expiration_ttl(ttl).execute().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        worker::console_error!("failed to write the introspection cache: {:?}", err);
+    }
+}
 
 #[worker::send]
-async fn get(kv: worker::kv::KvStore, token: &str) -> Option<Response> {
+async fn get(kv: worker::kv::KvStore, token: &str) -> Option<CacheEntry> {
+    let now = chrono::Utc::now().timestamp();
+
     if let Some(data) = kv.get(prefixed_key(token).as_str()).text().await.unwrap_or(None) {
-        serde_json::from_str(&data).ok()
-    } else {
-        None
+        // entries written before `cached_until` was stored don't parse and count as a miss
+        return serde_json::from_str::<CacheEntry>(&data)
+            .ok()
+            .filter(|entry| entry.cached_until >= now);
+    }
+
+    let data = kv.get(negative_key(token).as_str()).text().await.unwrap_or(None)?;
+    // markers written before the expiry was stored run out with their KV expiration
+    let cached_until = data.parse::<i64>().unwrap_or(i64::MAX);
+    (cached_until >= now).then(|| CacheEntry {
+        response: inactive_response(),
+        cached_until,
+    })
+}
+
+#[worker::send]
+async fn remove(kv: worker::kv::KvStore, token: &str) {
+    // the subject index entry can only be found through the subject of the cached response
+    let entry = kv
+        .get(prefixed_key(token).as_str())
+        .text()
+        .await
+        .unwrap_or(None);
+    let sub = entry
+        .and_then(|data| serde_json::from_str::<CacheEntry>(&data).ok())
+        .and_then(|entry| entry.response.sub().map(str::to_string));
+    if let Some(sub) = sub {
+        kv.delete(&subject_index_key(&sub, token))
+            .await
+            .unwrap_or(());
+    }
+    kv.delete(prefixed_key(token).as_str()).await.unwrap_or(());
+    kv.delete(negative_key(token).as_str()).await.unwrap_or(());
+}
+
+#[worker::send]
+async fn remove_by_subject(kv: worker::kv::KvStore, sub: &str) {
+    let prefix = subject_index_prefix(sub);
+
+    for index_key in list_keys(&kv, &prefix).await {
+        let digest = &index_key[prefix.len()..];
+        kv.delete(format!("{}{}", CACHE_PREFIX, digest).as_str()).await.unwrap_or(());
+        kv.delete(&index_key).await.unwrap_or(());
     }
 }
 
 #[worker::send]
 async fn wrapped_clear(kv: worker::kv::KvStore) {
-    let keys = kv.list().execute().await.unwrap().keys;
+    for prefix in [CACHE_PREFIX, NEGATIVE_CACHE_PREFIX, SUBJECT_INDEX_PREFIX] {
+        for key in list_keys(&kv, prefix).await {
+            kv.delete(&key).await.unwrap_or(());
+        }
+    }
+}
+
+/// Lists every key starting with `prefix`, following the pagination cursor until the listing is complete.
+async fn list_keys(kv: &worker::kv::KvStore, prefix: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut cursor: Option<String> = None;
+
+    loop {
+        let mut list = kv.list().prefix(prefix.to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+
+        let Ok(page) = list.execute().await else {
+            break;
+        };
+        names.extend(page.keys.into_iter().map(|key| key.name));
+
+        match page.cursor {
+            Some(next) if !page.list_complete => cursor = Some(next),
+            _ => break,
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for key in keys.iter().filter(|key| key.name.starts_with("introspectioncache::")) {
-        kv.delete(&key.name).await.unwrap_or(());
+    #[test]
+    fn negative_keys_do_not_collide_with_positive_keys() {
+        assert_ne!(prefixed_key("token"), negative_key("token"));
+        assert!(!negative_key("token").starts_with(CACHE_PREFIX));
+        assert!(!prefixed_key("token").starts_with(NEGATIVE_CACHE_PREFIX));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn subject_index_keys_share_the_subject_prefix() {
+        let key = subject_index_key("alice", "token");
+
+        assert!(key.starts_with(&subject_index_prefix("alice")));
+        assert!(!key.starts_with(&subject_index_prefix("bob")));
+        assert!(key.ends_with(&token_key("token")));
+        assert!(!key.starts_with(CACHE_PREFIX));
+    }
+
+    #[test]
+    fn kv_ttl_is_at_least_the_kv_minimum() {
+        assert_eq!(kv_ttl(1_000 + 3_600, 1_000), 3_600);
+        assert_eq!(kv_ttl(1_000 + 10, 1_000), MIN_KV_TTL_SECONDS);
+        assert_eq!(kv_ttl(1_000 - 10, 1_000), MIN_KV_TTL_SECONDS);
+        assert_eq!(kv_ttl(i64::MAX, i64::MIN), i64::MAX.unsigned_abs());
+    }
+
+    #[test]
+    fn keys_do_not_contain_the_token() {
+        assert!(!prefixed_key("secret-token").contains("secret-token"));
+        assert!(!negative_key("secret-token").contains("secret-token"));
+    }
+
+    #[test]
+    fn key_length_stays_within_kv_limit() {
+        let long_token = "a".repeat(4096);
+
+        assert!(prefixed_key(&long_token).len() <= 512);
+        assert!(negative_key(&long_token).len() <= 512);
+        assert!(subject_index_key(&long_token, &long_token).len() <= 512);
+        assert_eq!(prefixed_key(&long_token).len(), prefixed_key("short").len());
+    }
+}
@@ -1,23 +1,179 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use openidconnect::TokenIntrospectionResponse;
 use time::Duration;
 
+use super::{inactive_response, token_key, CacheEntry, DEFAULT_NEGATIVE_TTL};
+
 type Response = super::super::ZitadelIntrospectionResponse;
 
+/// A cached value with its expiry and the tick it was last used at.
+#[derive(Debug)]
+struct Slot<T> {
+    value: T,
+    expires_at: i64,
+    last_used: AtomicU64,
+}
+
+impl<T: Clone> Slot<T> {
+    fn new(value: T, expires_at: i64, tick: u64) -> Self {
+        Self {
+            value,
+            expires_at,
+            last_used: AtomicU64::new(tick),
+        }
+    }
+
+    /// Returns the value and its expiry unless it has expired, marking it as recently used.
+    fn read(&self, now: i64, tick: u64) -> Option<(T, i64)> {
+        if self.expires_at < now {
+            return None;
+        }
+        self.last_used.store(tick, Ordering::Relaxed);
+        Some((self.value.clone(), self.expires_at))
+    }
+}
+
+/// Makes room for inserting `key` into a full map. Expired entries go first; if that is not
+/// enough, the least recently used tenth of the map is evicted at once, so a full cache
+/// is not scanned again on every insert.
+fn make_room<T>(
+    map: &mut HashMap<String, Slot<T>>,
+    key: &str,
+    max_entries: Option<usize>,
+    now: i64,
+) {
+    let Some(max_entries) = max_entries else {
+        return;
+    };
+    if map.len() < max_entries || map.contains_key(key) {
+        return;
+    }
+    map.retain(|_, slot| slot.expires_at >= now);
+    if map.len() < max_entries {
+        return;
+    }
+
+    let count = (map.len() + 1 - max_entries).max(max_entries / 10);
+    let mut last_used: Vec<u64> = map
+        .values()
+        .map(|slot| slot.last_used.load(Ordering::Relaxed))
+        .collect();
+    let cutoff = *last_used.select_nth_unstable(count - 1).1;
+    map.retain(|_, slot| slot.last_used.load(Ordering::Relaxed) > cutoff);
+}
+
 #[derive(Debug, Clone)]
 pub struct InMemoryIntrospectionCache {
-    cache: Arc<RwLock<HashMap<String, (Response, i64)>>>,
+    cache: Arc<RwLock<HashMap<String, Slot<Response>>>>,
+    negative: Arc<RwLock<HashMap<String, Slot<()>>>>,
+    negative_ttl: Duration,
+    max_entries: Option<usize>,
+    max_ttl: Option<Duration>,
+    // orders uses for the LRU eviction
+    clock: Arc<AtomicU64>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
 }
 
 impl InMemoryIntrospectionCache {
     /// Creates a new in memory cache backed by a HashMap.
-    /// No max capacity limit is enforced, but entries are cleared based on expiry.
+    /// No max capacity limit is enforced unless set with [`Self::with_max_entries`],
+    /// but entries are cleared based on expiry.
     pub fn new() -> Self {
         Self {
             cache: Arc::new(RwLock::new(HashMap::new())),
+            negative: Arc::new(RwLock::new(HashMap::new())),
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            max_entries: None,
+            max_ttl: None,
+            clock: Arc::new(AtomicU64::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sets how long inactive introspection results are remembered.
+    /// A zero (or negative) duration disables negative caching.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Bounds the number of active and of inactive results held. Once full, expired entries
+    /// and then the least recently used ones are evicted.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries.max(1));
+        self
+    }
+
+    /// Keeps active results at most this long, even if their token expires later. In front
+    /// of a shared cache, this bounds how long an eviction elsewhere goes unnoticed here.
+    pub fn with_max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = Some(max_ttl);
+        self
+    }
+
+    /// How many lookups were answered from the cache, active and inactive results alike.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How many lookups found nothing, or only an expired entry.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    async fn lookup(&self, key: &str, now: i64) -> Option<CacheEntry> {
+        // Lookups only need the read lock; the write lock is taken to evict expired entries.
+        let cached = self
+            .cache
+            .read()
+            .await
+            .get(key)
+            .map(|slot| slot.read(now, self.tick()));
+        match cached {
+            Some(Some((response, cached_until))) => {
+                return Some(CacheEntry {
+                    response,
+                    cached_until,
+                })
+            }
+            Some(None) => {
+                let mut cache = self.cache.write().await;
+                if matches!(cache.get(key), Some(slot) if slot.expires_at < now) {
+                    cache.remove(key);
+                }
+            }
+            None => {}
+        }
+
+        let negative = self
+            .negative
+            .read()
+            .await
+            .get(key)
+            .map(|slot| slot.read(now, self.tick()));
+        match negative {
+            Some(Some(((), cached_until))) => Some(CacheEntry {
+                response: inactive_response(),
+                cached_until,
+            }),
+            Some(None) => {
+                let mut negative = self.negative.write().await;
+                if matches!(negative.get(key), Some(slot) if slot.expires_at < now) {
+                    negative.remove(key);
+                }
+                None
+            }
+            None => None,
         }
     }
 }
@@ -30,29 +186,67 @@ impl Default for InMemoryIntrospectionCache {
 
 #[async_trait::async_trait]
 impl super::IntrospectionCache for InMemoryIntrospectionCache {
-    async fn get(&self, token: &str) -> Option<Response> {
-        let mut cache = self.cache.write().await;
-        match cache.get(token) {
-            Some((response, expires_at))
-            if *expires_at < chrono::Utc::now().timestamp() => {
-                cache.remove(token);
-                None
-            }
-            Some((response, _)) => Some(response.clone()),
-            None => None,
-        }
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry> {
+        let result = self
+            .lookup(&token_key(token), chrono::Utc::now().timestamp())
+            .await;
+
+        let counter = if result.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        result
     }
 
-    async fn set(&self, token: &str, response: Response) {
-        if !response.active() || response.exp().is_none() {
+    async fn set_entry(&self, token: &str, entry: CacheEntry) {
+        let CacheEntry {
+            response,
+            cached_until,
+        } = entry;
+        let key = token_key(token);
+        let now = chrono::Utc::now().timestamp();
+        if !response.active() {
+            if self.negative_ttl.is_positive() {
+                let expires_at = cached_until.min(now + self.negative_ttl.whole_seconds());
+                self.cache.write().await.remove(&key);
+                let mut negative = self.negative.write().await;
+                make_room(&mut negative, &key, self.max_entries, now);
+                negative.insert(key, Slot::new((), expires_at, self.tick()));
+            }
             return;
         }
-        let expires_at = response.exp().unwrap().timestamp();
-        self.cache.write().await.insert(token.to_string(), (response, expires_at));
+        if response.exp().is_none() {
+            return;
+        }
+        let mut expires_at = cached_until.min(response.exp().unwrap().timestamp());
+        if let Some(max_ttl) = self.max_ttl {
+            expires_at = expires_at.min(now + max_ttl.whole_seconds());
+        }
+        self.negative.write().await.remove(&key);
+        let mut cache = self.cache.write().await;
+        make_room(&mut cache, &key, self.max_entries, now);
+        cache.insert(key, Slot::new(response, expires_at, self.tick()));
+    }
+
+    async fn remove(&self, token: &str) {
+        let key = token_key(token);
+        self.cache.write().await.remove(&key);
+        self.negative.write().await.remove(&key);
+    }
+
+    async fn remove_by_subject(&self, sub: &str) {
+        self.cache
+            .write()
+            .await
+            .retain(|_, slot| slot.value.sub() != Some(sub));
     }
 
     async fn clear(&self) {
         self.cache.write().await.clear();
+        self.negative.write().await.clear();
     }
 }
 
@@ -129,4 +323,238 @@ mod tests {
         assert!(t.get("token1").await.is_none());
         assert!(t.get("token2").await.is_none());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_negative_caching() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", Response::new(false, Default::default())).await;
+
+        let cached = t.get("token1").await;
+        assert!(cached.is_some());
+        assert!(!cached.unwrap().active());
+        assert!(t.get("token2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_negative_caching_disabled() {
+        let c = InMemoryIntrospectionCache::new().with_negative_ttl(Duration::ZERO);
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", Response::new(false, Default::default())).await;
+
+        assert!(t.get("token1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_negative_entry_expires() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        c.negative.write().await.insert(
+            token_key("token1"),
+            Slot::new((), Utc::now().timestamp() - 10, 0),
+        );
+
+        assert!(t.get("token1").await.is_none());
+        assert!(c.negative.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_active_response_replaces_negative_entry() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", Response::new(false, Default::default())).await;
+
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now() + TimeDelta::try_seconds(10).unwrap()));
+        t.set("token1", response).await;
+
+        assert!(t.get("token1").await.unwrap().active());
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_hashed() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now()));
+        t.set("token1", response).await;
+
+        let cache = c.cache.read().await;
+        assert!(!cache.contains_key("token1"));
+        assert!(cache.keys().all(|key| key.len() == 64));
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now()));
+
+        t.set("token1", response.clone()).await;
+        t.set("token2", response.clone()).await;
+        t.set("token3", Response::new(false, Default::default())).await;
+
+        t.remove("token1").await;
+        t.remove("token3").await;
+
+        assert!(t.get("token1").await.is_none());
+        assert!(t.get("token2").await.is_some());
+        assert!(t.get("token3").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remove_by_subject() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now()));
+        response.set_sub(Some("alice".to_string()));
+        t.set("token1", response.clone()).await;
+        t.set("token2", response.clone()).await;
+        response.set_sub(Some("bob".to_string()));
+        t.set("token3", response).await;
+
+        t.remove_by_subject("alice").await;
+
+        assert!(t.get("token1").await.is_none());
+        assert!(t.get("token2").await.is_none());
+        assert!(t.get("token3").await.is_some());
+    }
+
+    fn active_response() -> Response {
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now() + TimeDelta::try_seconds(60).unwrap()));
+        response
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used_at_capacity() {
+        let c = InMemoryIntrospectionCache::new().with_max_entries(3);
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", active_response()).await;
+        t.set("token2", active_response()).await;
+        t.set("token3", active_response()).await;
+        let _ = t.get("token1").await;
+
+        t.set("token4", active_response()).await;
+
+        assert_eq!(c.cache.read().await.len(), 3);
+        assert!(t.get("token1").await.is_some());
+        assert!(t.get("token2").await.is_none());
+        assert!(t.get("token3").await.is_some());
+        assert!(t.get("token4").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_capacity_is_bounded() {
+        let c = InMemoryIntrospectionCache::new().with_max_entries(20);
+        let t = &c as &dyn IntrospectionCache;
+
+        for i in 0..100 {
+            t.set(&format!("token{}", i), active_response()).await;
+            let inactive = Response::new(false, Default::default());
+            t.set(&format!("revoked{}", i), inactive).await;
+        }
+
+        assert!(c.cache.read().await.len() <= 20);
+        assert!(c.negative.read().await.len() <= 20);
+        assert!(t.get("token99").await.is_some());
+        assert!(t.get("revoked99").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_max_ttl_caps_expiry() {
+        let c = InMemoryIntrospectionCache::new().with_max_ttl(Duration::seconds(30));
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", active_response()).await;
+        assert!(t.get("token1").await.is_some());
+
+        let expires_at = c.cache.read().await[&token_key("token1")].expires_at;
+        assert!(expires_at <= Utc::now().timestamp() + 30);
+
+        let c = InMemoryIntrospectionCache::new().with_max_ttl(Duration::seconds(-1));
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", active_response()).await;
+        assert!(t.get("token1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_entry_expires_at_cached_until() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        let response = active_response();
+        let cached_until = Utc::now().timestamp() - 1;
+        t.set_entry(
+            "token1",
+            CacheEntry {
+                response: response.clone(),
+                cached_until,
+            },
+        )
+        .await;
+        assert!(t.get("token1").await.is_none());
+
+        let cached_until = Utc::now().timestamp() + 10;
+        t.set_entry(
+            "token1",
+            CacheEntry {
+                response: response.clone(),
+                cached_until,
+            },
+        )
+        .await;
+        let entry = t.get_entry("token1").await.unwrap();
+        assert_eq!(entry.cached_until, cached_until);
+        assert_eq!(entry.response.exp(), response.exp());
+    }
+
+    #[tokio::test]
+    async fn test_counts_hits_and_misses() {
+        let c = InMemoryIntrospectionCache::new();
+        let t = &c as &dyn IntrospectionCache;
+
+        t.set("token1", active_response()).await;
+        let inactive = Response::new(false, Default::default());
+        t.set("token2", inactive).await;
+        let mut expired = Response::new(true, Default::default());
+        expired.set_exp(Some(Utc::now() - TimeDelta::try_seconds(10).unwrap()));
+        t.set("token3", expired).await;
+
+        let _ = t.get("token1").await;
+        let _ = t.get("token1").await;
+        let _ = t.get("token2").await;
+        let _ = t.get("token3").await;
+        let _ = t.get("token4").await;
+
+        assert_eq!(c.hits(), 3);
+        assert_eq!(c.misses(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_entries_are_evicted_first() {
+        let c = InMemoryIntrospectionCache::new().with_max_entries(2);
+        let t = &c as &dyn IntrospectionCache;
+
+        let mut expired = Response::new(true, Default::default());
+        expired.set_exp(Some(Utc::now() - TimeDelta::try_seconds(10).unwrap()));
+        t.set("token1", active_response()).await;
+        t.set("token2", expired).await;
+
+        t.set("token3", active_response()).await;
+
+        assert!(t.get("token1").await.is_some());
+        assert!(t.get("token3").await.is_some());
+    }
+}
@@ -1,18 +1,118 @@
 use async_trait::async_trait;
+use openidconnect::TokenIntrospectionResponse;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::ops::Deref;
 
 pub mod in_memory;
 pub mod cloudflare;
+pub mod tiered;
+pub mod cache_api;
 
 pub type Response = super::ZitadelIntrospectionResponse;
 
+/// How long inactive introspection results are remembered unless configured otherwise.
+/// Cloudflare KV does not accept expiration TTLs below 60 seconds.
+pub const DEFAULT_NEGATIVE_TTL: time::Duration = time::Duration::seconds(60);
+
+/// Derives the cache key for a token: the hex encoded SHA-256 digest of the token, so raw
+/// bearer tokens never show up in storage and keys stay a fixed 64 characters long.
+pub(crate) fn token_key(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The response handed out for a negatively cached token.
+pub(crate) fn inactive_response() -> Response {
+    Response::new(false, Default::default())
+}
+
+
+/// Bounds how long cached introspection results are trusted, independently of the cache used.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CachePolicy {
+    /// Cached results are re-introspected after this long even if the token expires later.
+    pub max_ttl: Option<time::Duration>,
+    /// Cached active results are considered expired this long before their `exp`,
+    /// to make up for clock differences between the edge and the IdP.
+    pub leeway: time::Duration,
+}
+
+impl CachePolicy {
+    /// Prepares a response for caching. The entry is kept until the token expires but at most
+    /// `max_ttl`; the response itself, and so its `exp`, is cached as the IdP answered.
+    pub(crate) fn entry(&self, response: Response) -> CacheEntry {
+        let mut entry = CacheEntry::new(response);
+        if let Some(max_ttl) = self.max_ttl {
+            let cap = chrono::Utc::now().timestamp() + max_ttl.whole_seconds();
+            entry.cached_until = entry.cached_until.min(cap);
+        }
+        entry
+    }
+
+    /// Whether a cached result may still be used.
+    pub(crate) fn is_fresh(&self, response: &Response) -> bool {
+        if !response.active() {
+            return true;
+        }
+        match response.exp() {
+            Some(exp) => {
+                exp.timestamp() - self.leeway.whole_seconds() > chrono::Utc::now().timestamp()
+            }
+            None => false,
+        }
+    }
+}
+
+/// A cached introspection result together with the Unix timestamp it may be served until.
+/// `cached_until` can be earlier than the token's `exp`, which is left as the IdP answered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub response: Response,
+    pub cached_until: i64,
+}
+
+impl CacheEntry {
+    /// Caches the response until the token expires. Inactive results carry no `exp`,
+    /// they are kept for the negative TTL of the cache.
+    pub fn new(response: Response) -> Self {
+        let cached_until = response
+            .exp()
+            .map(|exp| exp.timestamp())
+            .unwrap_or(i64::MAX);
+        Self {
+            response,
+            cached_until,
+        }
+    }
+}
 
 #[async_trait]
 pub trait IntrospectionCache: Send + Sync + std::fmt::Debug {
-    async fn get(&self, token: &str) -> Option<Response>;
+    async fn get(&self, token: &str) -> Option<Response> {
+        self.get_entry(token).await.map(|entry| entry.response)
+    }
 
-    async fn set(&self, token: &str, response: Response);
+    async fn set(&self, token: &str, response: Response) {
+        self.set_entry(token, CacheEntry::new(response)).await
+    }
+
+    /// The cached entry for the token, unless it is past its `cached_until`.
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry>;
+
+    /// Caches the entry until its `cached_until` at the latest. Active results without `exp`
+    /// are not cached, inactive ones are kept no longer than the negative TTL of the cache.
+    async fn set_entry(&self, token: &str, entry: CacheEntry);
+
+    /// Evicts the cached result for a single token, e.g. on logout.
+    async fn remove(&self, token: &str);
+
+    /// Evicts every cached result belonging to the subject (`sub`), e.g. on back-channel logout
+    /// or when an administrator revokes a user.
+    async fn remove_by_subject(&self, sub: &str);
 
     async fn clear(&self);
 }
@@ -23,15 +123,96 @@ where
     T: Deref<Target = V> + Send + Sync + Debug,
     V: IntrospectionCache,
 {
-    async fn get(&self, token: &str) -> Option<Response> {
-        self.deref().get(token).await
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry> {
+        self.deref().get_entry(token).await
     }
 
-    async fn set(&self, token: &str, response: Response) {
-        self.deref().set(token, response).await
+    async fn set_entry(&self, token: &str, entry: CacheEntry) {
+        self.deref().set_entry(token, entry).await
+    }
+
+    async fn remove(&self, token: &str) {
+        self.deref().remove(token).await
+    }
+
+    async fn remove_by_subject(&self, sub: &str) {
+        self.deref().remove_by_subject(sub).await
     }
 
     async fn clear(&self) {
         self.deref().clear().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_key_is_hex_sha256() {
+        assert_eq!(
+            token_key("token"),
+            "3c469e9d6c5875d37a43f353d4f88e61fcf812c66eee3457465a40b0da4153e0"
+        );
+    }
+
+    fn active_response(expires_in: i64) -> Response {
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(chrono::Utc::now() + chrono::TimeDelta::seconds(expires_in)));
+        response
+    }
+
+    #[test]
+    fn policy_caps_cached_until_to_max_ttl() {
+        let policy = CachePolicy {
+            max_ttl: Some(time::Duration::seconds(60)),
+            ..Default::default()
+        };
+
+        let response = active_response(3600);
+        let entry = policy.entry(response.clone());
+        let ttl = entry.cached_until - chrono::Utc::now().timestamp();
+        assert!(ttl <= 60 && ttl > 50);
+        assert_eq!(entry.response.exp(), response.exp());
+
+        let entry = policy.entry(active_response(30));
+        let ttl = entry.cached_until - chrono::Utc::now().timestamp();
+        assert!(ttl <= 30);
+
+        let entry = policy.entry(inactive_response());
+        let ttl = entry.cached_until - chrono::Utc::now().timestamp();
+        assert!(ttl <= 60);
+    }
+
+    #[test]
+    fn policy_without_max_ttl_caches_until_exp() {
+        let response = active_response(3600);
+
+        let entry = CachePolicy::default().entry(response.clone());
+        assert_eq!(entry.response.exp(), response.exp());
+        assert_eq!(entry.cached_until, response.exp().unwrap().timestamp());
+        let entry = CachePolicy::default().entry(inactive_response());
+        assert_eq!(entry.cached_until, i64::MAX);
+    }
+
+    #[test]
+    fn policy_applies_leeway() {
+        let policy = CachePolicy {
+            leeway: time::Duration::seconds(30),
+            ..Default::default()
+        };
+
+        assert!(policy.is_fresh(&active_response(60)));
+        assert!(!policy.is_fresh(&active_response(20)));
+        assert!(CachePolicy::default().is_fresh(&active_response(20)));
+        assert!(policy.is_fresh(&inactive_response()));
+    }
+
+    #[test]
+    fn token_key_length_is_bounded() {
+        let long_token = "a".repeat(10_000);
+
+        assert_eq!(token_key(&long_token).len(), 64);
+        assert_eq!(token_key("").len(), 64);
+    }
+}
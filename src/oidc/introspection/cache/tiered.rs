@@ -0,0 +1,179 @@
+use async_trait::async_trait;
+
+use super::{CacheEntry, IntrospectionCache};
+
+/// Composes two caches: reads go through `L1` first and fall back to `L2`, refilling `L1`
+/// on an `L2` hit; writes go to both.
+///
+/// Typically a per-isolate [`InMemoryIntrospectionCache`](super::in_memory::InMemoryIntrospectionCache)
+/// in front of the shared [`CloudflareIntrospectionCache`](super::cloudflare::CloudflareIntrospectionCache).
+#[derive(Debug, Clone)]
+pub struct TieredIntrospectionCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+}
+
+impl<L1, L2> TieredIntrospectionCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        Self { l1, l2 }
+    }
+}
+
+#[async_trait]
+impl<L1, L2> IntrospectionCache for TieredIntrospectionCache<L1, L2>
+where
+    L1: IntrospectionCache,
+    L2: IntrospectionCache,
+{
+    async fn get_entry(&self, token: &str) -> Option<CacheEntry> {
+        if let Some(entry) = self.l1.get_entry(token).await {
+            return Some(entry);
+        }
+
+        let entry = self.l2.get_entry(token).await?;
+        self.l1.set_entry(token, entry.clone()).await;
+        Some(entry)
+    }
+
+    async fn set_entry(&self, token: &str, entry: CacheEntry) {
+        self.l1.set_entry(token, entry.clone()).await;
+        self.l2.set_entry(token, entry).await;
+    }
+
+    async fn remove(&self, token: &str) {
+        self.l1.remove(token).await;
+        self.l2.remove(token).await;
+    }
+
+    async fn remove_by_subject(&self, sub: &str) {
+        self.l1.remove_by_subject(sub).await;
+        self.l2.remove_by_subject(sub).await;
+    }
+
+    async fn clear(&self) {
+        self.l1.clear().await;
+        self.l2.clear().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use crate::oidc::introspection::cache::in_memory::InMemoryIntrospectionCache;
+    use crate::oidc::introspection::cache::Response;
+    use chrono::{TimeDelta, Utc};
+    use openidconnect::TokenIntrospectionResponse;
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn active_response() -> Response {
+        let mut response = Response::new(true, Default::default());
+        response.set_exp(Some(Utc::now() + TimeDelta::try_seconds(60).unwrap()));
+        response
+    }
+
+    fn tiered() -> (
+        Arc<InMemoryIntrospectionCache>,
+        Arc<InMemoryIntrospectionCache>,
+        TieredIntrospectionCache<Arc<InMemoryIntrospectionCache>, Arc<InMemoryIntrospectionCache>>,
+    ) {
+        let l1 = Arc::new(InMemoryIntrospectionCache::new());
+        let l2 = Arc::new(InMemoryIntrospectionCache::new());
+        let cache = TieredIntrospectionCache::new(l1.clone(), l2.clone());
+        (l1, l2, cache)
+    }
+
+    #[tokio::test]
+    async fn set_writes_through_both_tiers() {
+        let (l1, l2, cache) = tiered();
+
+        cache.set("token1", active_response()).await;
+
+        assert!(l1.get("token1").await.is_some());
+        assert!(l2.get("token1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn get_fills_l1_on_l2_hit() {
+        let (l1, l2, cache) = tiered();
+
+        l2.set("token1", active_response()).await;
+        assert!(l1.get("token1").await.is_none());
+
+        assert!(cache.get("token1").await.is_some());
+        assert!(l1.get("token1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn l1_keeps_the_cached_until_of_l2() {
+        let (l1, l2, cache) = tiered();
+
+        let cached_until = Utc::now().timestamp() + 10;
+        l2.set_entry(
+            "token1",
+            CacheEntry {
+                response: active_response(),
+                cached_until,
+            },
+        )
+        .await;
+
+        assert!(cache.get("token1").await.is_some());
+        assert_eq!(
+            l1.get_entry("token1").await.unwrap().cached_until,
+            cached_until
+        );
+    }
+
+    #[tokio::test]
+    async fn get_prefers_l1() {
+        let (l1, l2, cache) = tiered();
+
+        let mut response = active_response();
+        response.set_sub(Some("l1".to_string()));
+        l1.set("token1", response).await;
+        let mut response = active_response();
+        response.set_sub(Some("l2".to_string()));
+        l2.set("token1", response).await;
+
+        assert_eq!(cache.get("token1").await.unwrap().sub(), Some("l1"));
+    }
+
+    #[tokio::test]
+    async fn get_misses_when_both_tiers_miss() {
+        let (_, _, cache) = tiered();
+
+        assert!(cache.get("token1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_evicts_from_both_tiers() {
+        let (l1, l2, cache) = tiered();
+
+        let mut response = active_response();
+        response.set_sub(Some("alice".to_string()));
+        cache.set("token1", response.clone()).await;
+        cache.set("token2", response).await;
+
+        cache.remove("token1").await;
+        assert!(l1.get("token1").await.is_none());
+        assert!(l2.get("token1").await.is_none());
+
+        cache.remove_by_subject("alice").await;
+        assert!(l1.get("token2").await.is_none());
+        assert!(l2.get("token2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clear_clears_both_tiers() {
+        let (l1, l2, cache) = tiered();
+
+        cache.set("token1", active_response()).await;
+        cache.clear().await;
+
+        assert!(l1.get("token1").await.is_none());
+        assert!(l2.get("token1").await.is_none());
+    }
+}
@@ -1,6 +1,5 @@
 use custom_error::custom_error;
-use openidconnect::http::Method;
-use openidconnect::reqwest::async_http_client;
+use openidconnect::http::{Method, StatusCode};
 use openidconnect::url::{ParseError, Url};
 use openidconnect::HttpResponse;
 use openidconnect::{
@@ -14,12 +13,13 @@ use std::error::Error;
 use std::fmt::{Debug, Display};
 use base64::Engine;
 use crate::credentials::{Application, ApplicationError};
+use crate::http_client::{HttpClient, HttpClientError};
 
 pub mod cache;
 
 custom_error! {
     pub IntrospectionError
-        RequestFailed{source: openidconnect::reqwest::Error<reqwest::Error>} = "the introspection request did fail: {source}",
+        RequestFailed{source: HttpClientError} = "the introspection request did fail: {source}",
         PayloadSerialization = "could not correctly serialize introspection payload",
         JWTProfile{source: ApplicationError} = "could not create signed jwt key: {source}",
         ParseUrl{source: ParseError} = "could not parse url: {source}",
@@ -28,6 +28,18 @@ custom_error! {
         ResponseError{source: ZitadelResponseError} = "received error response from Zitadel: {source}",
 }
 
+impl IntrospectionError {
+    /// Whether the IdP could not be reached or failed on its side (transport errors, timeouts
+    /// and 5xx responses), as opposed to rejecting the request.
+    pub fn is_idp_unavailable(&self) -> bool {
+        match self {
+            IntrospectionError::RequestFailed { .. } => true,
+            IntrospectionError::ResponseError { source } => source.status_code.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ZitadelIntrospectionExtraTokenFields {
     pub name: Option<String>,
@@ -123,15 +135,33 @@ pub async fn introspect(
     authentication: &AuthorityAuthentication,
     token: &str,
 ) -> Result<ZitadelIntrospectionResponse, IntrospectionError> {
-    let response = async_http_client(HttpRequest {
-        url: Url::parse(introspection_uri)
-            .map_err(|source| IntrospectionError::ParseUrl { source })?,
-        method: Method::POST,
-        headers: headers(authentication),
-        body: payload(authority, authentication, token)?.into_bytes(),
-    })
+    introspect_with(
+        &HttpClient::default(),
+        introspection_uri,
+        authority,
+        authentication,
+        token,
+    )
     .await
-    .map_err(|source| IntrospectionError::RequestFailed { source })?;
+}
+
+pub async fn introspect_with(
+    http_client: &HttpClient,
+    introspection_uri: &str,
+    authority: &str,
+    authentication: &AuthorityAuthentication,
+    token: &str,
+) -> Result<ZitadelIntrospectionResponse, IntrospectionError> {
+    let response = http_client
+        .execute_safe(HttpRequest {
+            url: Url::parse(introspection_uri)
+                .map_err(|source| IntrospectionError::ParseUrl { source })?,
+            method: Method::POST,
+            headers: headers(authentication),
+            body: payload(authority, authentication, token)?.into_bytes(),
+        })
+        .await
+        .map_err(|source| IntrospectionError::RequestFailed { source })?;
 
     if !response.status_code.is_success() {
         return Err(IntrospectionError::ResponseError {
@@ -148,13 +178,13 @@ pub async fn introspect(
 
 #[derive(Debug)]
 struct ZitadelResponseError {
-    status_code: String,
+    status_code: StatusCode,
     body: String,
 }
 impl ZitadelResponseError {
     fn from_response(response: &HttpResponse) -> Self {
         Self {
-            status_code: response.status_code.to_string(),
+            status_code: response.status_code,
             body: String::from_utf8_lossy(response.body.as_slice()).to_string(),
         }
     }
@@ -188,14 +218,97 @@ fn decode_metadata(response: &mut ZitadelIntrospectionResponse) -> Result<(), In
 mod tests {
     #![allow(clippy::all)]
 
-    use crate::oidc::discovery::discover;
+    use crate::http_client::scripted::ScriptedTransport;
+    use crate::oidc::discovery::discover_with;
+    use crate::test_support::{MockOidcProvider, MockUser};
     use openidconnect::TokenIntrospectionResponse;
 
     use super::*;
 
-    const ZITADEL_URL: &str = "https://zitadel-libraries-l8boqa.zitadel.cloud";
-    const PERSONAL_ACCESS_TOKEN: &str =
-        "dEnGhIFs3VnqcQU5D2zRSeiarB1nwH6goIKY0J8MWZbsnWcTuu1C59lW9DgCq1y096GYdXA";
+    fn response_error(status_code: StatusCode) -> IntrospectionError {
+        IntrospectionError::ResponseError {
+            source: ZitadelResponseError {
+                status_code,
+                body: String::new(),
+            },
+        }
+    }
+
+    fn basic_auth() -> AuthorityAuthentication {
+        AuthorityAuthentication::Basic {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn introspect_parses_response_from_transport() {
+        let transport = ScriptedTransport::new()
+            .respond(200, r#"{"active":true,"sub":"user1","username":"alice"}"#);
+        let http_client = HttpClient::new().with_transport(transport);
+
+        let result = introspect_with(
+            &http_client,
+            "https://idp.invalid/oauth/v2/introspect",
+            "https://idp.invalid",
+            &basic_auth(),
+            "token",
+        )
+        .await
+        .unwrap();
+
+        assert!(result.active());
+        assert_eq!(result.sub(), Some("user1"));
+        assert_eq!(result.username(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn introspect_reports_unavailable_idp() {
+        let transport = ScriptedTransport::new().respond(503, "unavailable");
+        let http_client = HttpClient::new()
+            .with_transport(transport)
+            .with_max_retries(0);
+
+        let result = introspect_with(
+            &http_client,
+            "https://idp.invalid/oauth/v2/introspect",
+            "https://idp.invalid",
+            &basic_auth(),
+            "token",
+        )
+        .await;
+
+        assert!(result.unwrap_err().is_idp_unavailable());
+    }
+
+    #[tokio::test]
+    async fn introspect_decodes_metadata() {
+        let provider = MockOidcProvider::new();
+        let token = provider.issue_token(MockUser::new("user1").with_metadata("tier", "gold"));
+
+        let result = introspect_with(
+            &provider.http_client(),
+            &provider.endpoint("/oauth/v2/introspect"),
+            provider.issuer(),
+            &basic_auth(),
+            &token,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            result.extra_fields().metadata.as_ref().unwrap()["tier"],
+            "gold"
+        );
+    }
+
+    #[test]
+    fn only_server_errors_count_as_idp_unavailable() {
+        assert!(response_error(StatusCode::SERVICE_UNAVAILABLE).is_idp_unavailable());
+        assert!(response_error(StatusCode::BAD_GATEWAY).is_idp_unavailable());
+        assert!(!response_error(StatusCode::UNAUTHORIZED).is_idp_unavailable());
+        assert!(!IntrospectionError::PayloadSerialization.is_idp_unavailable());
+    }
 
     #[tokio::test]
     async fn introspect_fails_with_invalid_url() {
@@ -219,10 +332,13 @@ mod tests {
 
     #[tokio::test]
     async fn introspect_fails_with_invalid_endpoint() {
-        let meta = discover(ZITADEL_URL).await.unwrap();
-        let result = introspect(
+        let provider = MockOidcProvider::new();
+        let http_client = provider.http_client();
+        let meta = discover_with(&http_client, provider.issuer()).await.unwrap();
+        let result = introspect_with(
+            &http_client,
             &meta.token_endpoint().unwrap().to_string(),
-            ZITADEL_URL,
+            provider.issuer(),
             &AuthorityAuthentication::Basic {
                 client_id: "".to_string(),
                 client_secret: "".to_string(),
@@ -236,25 +352,30 @@ mod tests {
 
     #[tokio::test]
     async fn introspect_succeeds() {
-        let meta = discover(ZITADEL_URL).await.unwrap();
-        let result = introspect(
+        let provider = MockOidcProvider::new();
+        let http_client = provider.http_client();
+        let token = provider.issue_token(MockUser::new("user1"));
+        let meta = discover_with(&http_client, provider.issuer()).await.unwrap();
+        let result = introspect_with(
+            &http_client,
             &meta
                 .additional_metadata()
                 .introspection_endpoint
                 .as_ref()
                 .unwrap()
                 .to_string(),
-            ZITADEL_URL,
+            provider.issuer(),
             &AuthorityAuthentication::Basic {
                 client_id: "194339055499018497@zitadel_rust_test".to_string(),
                 client_secret: "Ip56oGzxKL1rJ8JaleUVKL7qUlpZ1tqHQYRSd6JE1mTlTJ3pDkDzoObHdZsOg88B"
                     .to_string(),
             },
-            PERSONAL_ACCESS_TOKEN,
+            &token,
         )
         .await
         .unwrap();
 
         assert!(result.active());
+        assert_eq!(result.sub(), Some("user1"));
     }
 }
@@ -0,0 +1,46 @@
+use async_trait::async_trait;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+
+use super::cloudflare::CloudflareKvStore;
+use super::cookie::CookieStore;
+
+/// The session store `SESSION_STORE` picks at runtime.
+#[derive(Clone, Debug)]
+pub enum SessionBackend {
+    Kv(CloudflareKvStore),
+    Cookie(CookieStore),
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        match self {
+            SessionBackend::Kv(store) => store.create(record).await,
+            SessionBackend::Cookie(store) => store.create(record).await,
+        }
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        match self {
+            SessionBackend::Kv(store) => store.save(record).await,
+            SessionBackend::Cookie(store) => store.save(record).await,
+        }
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        match self {
+            SessionBackend::Kv(store) => store.load(session_id).await,
+            SessionBackend::Cookie(store) => store.load(session_id).await,
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        match self {
+            SessionBackend::Kv(store) => store.delete(session_id).await,
+            SessionBackend::Cookie(store) => store.delete(session_id).await,
+        }
+    }
+}
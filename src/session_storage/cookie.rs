@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+use std::iter::once;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::header::{COOKIE, SET_COOKIE};
+use http::HeaderValue;
+use time::OffsetDateTime;
+use tower_cookies::cookie::{Cookie, CookieJar, SameSite};
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+
+use crate::keyring::Keyring;
+
+const DATA_COOKIE: &str = "session_data";
+/// Value bytes per cookie, leaving room for the name and attributes in the ~4 KB browsers keep.
+const CHUNK_SIZE: usize = 3800;
+const DEFAULT_MAX_CHUNKS: usize = 4;
+/// Records a browser may hold at once, e.g. its session and a pending login. Together they take
+/// up no more cookies than a single record may.
+const MAX_RECORDS: usize = 4;
+
+/// The sealed records a request came with, and what the store wrote for the response.
+#[derive(Debug, Default)]
+struct SessionData {
+    /// The sealed record in each slot, and how many cookies it took.
+    incoming: HashMap<usize, (String, usize)>,
+    /// The slot of every record the store read or wrote.
+    slots: HashMap<Id, usize>,
+    /// `None` once the record was deleted.
+    outgoing: HashMap<Id, Option<String>>,
+}
+
+tokio::task_local! {
+    static SESSION_DATA: Arc<Mutex<SessionData>>;
+}
+
+/// A session store without server storage: the whole record, expiry included, is sealed with the
+/// active session key into `session_data` cookies, split over `session_data.1`, `session_data.2`
+/// and so on when it does not fit into one. Further records, like a pending login next to the
+/// session, get cookies of their own: `session_data_1`, `session_data_1.1` and so on.
+///
+/// Works with the [`carry_session_data`] middleware around the session layer, which hands the
+/// cookies to the store and back.
+#[derive(Clone)]
+pub struct CookieStore {
+    keyring: Keyring,
+    max_chunks: usize,
+}
+
+impl CookieStore {
+    pub fn new(keyring: Keyring) -> Self {
+        Self {
+            keyring,
+            max_chunks: DEFAULT_MAX_CHUNKS,
+        }
+    }
+
+    /// How many cookies the sessions of a browser may take up together, 4 by default.
+    pub fn with_max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks;
+        self
+    }
+
+    fn seal(&self, record: &Record) -> session_store::Result<String> {
+        let json = serde_json::to_string(record)
+            .map_err(|err| session_store::Error::Encode(err.to_string()))?;
+
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.keyring.active().encryption)
+            .add(Cookie::new(DATA_COOKIE, json));
+        let sealed = jar
+            .get(DATA_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .unwrap_or_default();
+
+        if sealed.len() > self.max_chunks * CHUNK_SIZE {
+            return Err(session_store::Error::Backend(format!(
+                "the session takes {} bytes, more than {} cookies hold",
+                sealed.len(),
+                self.max_chunks
+            )));
+        }
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &str) -> Option<Record> {
+        let jar = CookieJar::new();
+        once(self.keyring.active())
+            .chain(self.keyring.previous())
+            .find_map(|key| {
+                jar.private(&key.encryption)
+                    .decrypt(Cookie::new(DATA_COOKIE, sealed.to_string()))
+            })
+            .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
+    }
+
+    /// The slot holding the record `id`, claiming it for the record.
+    fn find_slot(&self, data: &mut SessionData, id: &Id) -> Option<usize> {
+        if let Some(slot) = data.slots.get(id) {
+            return Some(*slot);
+        }
+        let slot = data
+            .incoming
+            .iter()
+            .filter(|(slot, _)| !data.slots.values().any(|claimed| claimed == *slot))
+            .find(|(_, (sealed, _))| self.open(sealed).is_some_and(|record| record.id == *id))
+            .map(|(slot, _)| *slot)?;
+        data.slots.insert(*id, slot);
+        Some(slot)
+    }
+
+    /// How many cookies the records take up once `sealed` is saved in `slot`. Cookies of records
+    /// that are no longer active are given up to new ones and not counted.
+    fn chunks_with(&self, data: &SessionData, slot: usize, sealed: &str) -> usize {
+        let chunks_in = |other: usize| {
+            if other == slot {
+                return chunk_count(sealed);
+            }
+            let incoming = data.incoming.get(&other);
+            match data.slots.iter().find(|(_, claimed)| **claimed == other) {
+                Some((id, _)) => match data.outgoing.get(id) {
+                    Some(outgoing) => outgoing.as_deref().map_or(0, chunk_count),
+                    None => incoming.map_or(0, |(_, chunks)| *chunks),
+                },
+                None => incoming
+                    .filter(|(sealed, _)| {
+                        self.open(sealed)
+                            .is_some_and(|record| is_active(record.expiry_date))
+                    })
+                    .map_or(0, |(_, chunks)| *chunks),
+            }
+        };
+        (0..MAX_RECORDS).map(chunks_in).sum()
+    }
+
+    /// A slot for a new record: one whose record was deleted, or that holds no active record.
+    fn free_slot(&self, data: &SessionData) -> Option<usize> {
+        (0..MAX_RECORDS).find(|slot| {
+            match data.slots.iter().find(|(_, claimed)| *claimed == slot) {
+                Some((id, _)) => matches!(data.outgoing.get(id), Some(None)),
+                None => data
+                    .incoming
+                    .get(slot)
+                    .and_then(|(sealed, _)| self.open(sealed))
+                    .map_or(true, |record| !is_active(record.expiry_date)),
+            }
+        })
+    }
+}
+
+impl std::fmt::Debug for CookieStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CookieStore")
+            .field("max_chunks", &self.max_chunks)
+            .finish_non_exhaustive()
+    }
+}
+
+fn with_session_data<T>(f: impl FnOnce(&mut SessionData) -> T) -> session_store::Result<T> {
+    SESSION_DATA
+        .try_with(|data| f(&mut data.lock().expect("the session data lock is not poisoned")))
+        .map_err(|_| {
+            session_store::Error::Backend(
+                "the cookie store is used outside of the carry_session_data middleware".to_string(),
+            )
+        })
+}
+
+#[async_trait]
+impl SessionStore for CookieStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        record.id = Id::default();
+        self.save(record).await
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        let sealed = self.seal(record)?;
+        with_session_data(|data| {
+            let claimed = self.find_slot(data, &record.id);
+            let slot = match claimed {
+                Some(slot) => slot,
+                None => self.free_slot(data).ok_or_else(|| {
+                    session_store::Error::Backend(format!(
+                        "more than {} sessions are kept in cookies",
+                        MAX_RECORDS
+                    ))
+                })?,
+            };
+            let chunks = self.chunks_with(data, slot, &sealed);
+            if chunks > self.max_chunks {
+                return Err(session_store::Error::Backend(format!(
+                    "the sessions take {} cookies together, more than {}",
+                    chunks, self.max_chunks
+                )));
+            }
+
+            if claimed.is_none() {
+                // a deleted record gives up its slot
+                let replaced: Vec<Id> = data
+                    .slots
+                    .iter()
+                    .filter(|(_, claimed)| **claimed == slot)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in replaced {
+                    data.slots.remove(&id);
+                    data.outgoing.remove(&id);
+                }
+                data.slots.insert(record.id, slot);
+            }
+            data.outgoing.insert(record.id, Some(sealed));
+            Ok(())
+        })?
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let record = with_session_data(|data| match data.outgoing.get(session_id) {
+            Some(outgoing) => outgoing.as_deref().and_then(|sealed| self.open(sealed)),
+            None => {
+                let slot = self.find_slot(data, session_id)?;
+                let (sealed, _) = data.incoming.get(&slot)?;
+                self.open(sealed)
+            }
+        })?;
+
+        Ok(record.filter(|record| record.id == *session_id && is_active(record.expiry_date)))
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        with_session_data(|data| {
+            if self.find_slot(data, session_id).is_some() {
+                data.outgoing.insert(*session_id, None);
+            }
+        })
+    }
+}
+
+fn is_active(expiry_date: OffsetDateTime) -> bool {
+    expiry_date > OffsetDateTime::now_utc()
+}
+
+fn chunk_name(slot: usize, index: usize) -> String {
+    let name = match slot {
+        0 => DATA_COOKIE.to_string(),
+        _ => format!("{}_{}", DATA_COOKIE, slot),
+    };
+    match index {
+        0 => name,
+        _ => format!("{}.{}", name, index),
+    }
+}
+
+/// Whether `name` is one of the cookies a record is kept in.
+pub(crate) fn is_data_cookie(name: &str) -> bool {
+    let Some(rest) = name.strip_prefix(DATA_COOKIE) else {
+        return false;
+    };
+    let is_number = |value: &str| value.parse::<usize>().is_ok();
+    let (slot, index) = match rest.split_once('.') {
+        Some((slot, index)) => (slot, Some(index)),
+        None => (rest, None),
+    };
+    (slot.is_empty() || slot.strip_prefix('_').is_some_and(is_number))
+        && index.map_or(true, is_number)
+}
+
+fn chunk_count(sealed: &str) -> usize {
+    sealed.len().div_ceil(CHUNK_SIZE)
+}
+
+/// Sealed records are base64, so they split anywhere.
+fn split(sealed: &str) -> Vec<&str> {
+    sealed
+        .as_bytes()
+        .chunks(CHUNK_SIZE)
+        .filter_map(|chunk| std::str::from_utf8(chunk).ok())
+        .collect()
+}
+
+/// Joins the chunks of the sealed record in `slot`, returning how many there were.
+fn join(cookies: &HashMap<String, String>, slot: usize) -> Option<(String, usize)> {
+    let mut sealed = String::new();
+    let mut chunks = 0;
+    while let Some(chunk) = cookies.get(&chunk_name(slot, chunks)) {
+        sealed.push_str(chunk);
+        chunks += 1;
+    }
+    (chunks > 0).then_some((sealed, chunks))
+}
+
+fn request_cookies(request: &Request) -> HashMap<String, String> {
+    request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Attributes of the `session_data` cookies, the same as the session cookie's.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionDataCookies {
+    pub(crate) domain: String,
+    pub(crate) secure: bool,
+}
+
+impl SessionDataCookies {
+    fn cookie(&self, name: String, value: String) -> Cookie<'static> {
+        Cookie::build((name, value))
+            .domain(self.domain.clone())
+            .path("/")
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .build()
+    }
+}
+
+/// Hands the `session_data` cookies to a [`CookieStore`] behind it and sets what the store
+/// wrote. Requests stay untouched with other stores.
+pub(crate) async fn carry_session_data(
+    State(cookies): State<SessionDataCookies>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_cookies = request_cookies(&request);
+    let incoming = (0..MAX_RECORDS)
+        .filter_map(|slot| join(&request_cookies, slot).map(|record| (slot, record)))
+        .collect();
+    let data = Arc::new(Mutex::new(SessionData {
+        incoming,
+        ..Default::default()
+    }));
+
+    let mut response = SESSION_DATA.scope(data.clone(), next.run(request)).await;
+
+    let data = data.lock().expect("the session data lock is not poisoned");
+    for (id, outgoing) in &data.outgoing {
+        let Some(&slot) = data.slots.get(id) else {
+            continue;
+        };
+        let incoming_chunks = data.incoming.get(&slot).map_or(0, |(_, chunks)| *chunks);
+        let chunks = outgoing.as_deref().map(split).unwrap_or_default();
+        let mut set_cookies: Vec<Cookie> = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| cookies.cookie(chunk_name(slot, index), chunk.to_string()))
+            .collect();
+        // chunks the record no longer needs
+        for index in chunks.len()..incoming_chunks {
+            let mut removal = cookies.cookie(chunk_name(slot, index), String::new());
+            removal.make_removal();
+            set_cookies.push(removal);
+        }
+
+        for cookie in set_cookies {
+            if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+                response.headers_mut().append(SET_COOKIE, value);
+            }
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::all)]
+
+    use time::Duration;
+
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            id: Id::default(),
+            data: Default::default(),
+            expiry_date: OffsetDateTime::now_utc() + Duration::minutes(30),
+        }
+    }
+
+    async fn in_request<F: std::future::Future>(
+        data: SessionData,
+        f: F,
+    ) -> (F::Output, SessionData) {
+        let data = Arc::new(Mutex::new(data));
+        let output = SESSION_DATA.scope(data.clone(), f).await;
+        let data = std::mem::take(&mut *data.lock().unwrap());
+        (output, data)
+    }
+
+    async fn seal_record(store: &CookieStore, record: &Record) -> String {
+        let (_, mut data) = in_request(SessionData::default(), store.save(record)).await;
+        data.outgoing.remove(&record.id).unwrap().unwrap()
+    }
+
+    fn incoming(sealed: String) -> SessionData {
+        SessionData {
+            incoming: HashMap::from([(0, (sealed, 1))]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_and_load() {
+        let store = CookieStore::new(Keyring::generate());
+        let mut record = record();
+        let (created, data) = in_request(SessionData::default(), store.create(&mut record)).await;
+        assert!(created.is_ok());
+
+        let sealed = data.outgoing[&record.id].clone().unwrap();
+        assert!(!sealed.contains(&record.id.to_string()));
+        let (loaded, _) = in_request(incoming(sealed), store.load(&record.id)).await;
+        assert_eq!(Some(record), loaded.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_other_ids_and_expired_records() {
+        let store = CookieStore::new(Keyring::generate());
+        let record = record();
+        let sealed = seal_record(&store, &record).await;
+        let (loaded, _) = in_request(incoming(sealed), store.load(&Id::default())).await;
+        assert_eq!(None, loaded.unwrap());
+
+        let mut expired = record();
+        expired.expiry_date = OffsetDateTime::now_utc() - Duration::minutes(1);
+        let sealed = seal_record(&store, &expired).await;
+        let (loaded, _) = in_request(incoming(sealed), store.load(&expired.id)).await;
+        assert_eq!(None, loaded.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_load_opens_records_sealed_with_previous_keys() {
+        let original = Keyring::generate();
+        let record = record();
+        let sealed = seal_record(&CookieStore::new(original.clone()), &record).await;
+
+        let mut next = Keyring::generate().active().clone();
+        next.version = 2;
+        let rotated = Keyring::new(vec![original.active().clone(), next]).unwrap();
+        let (loaded, _) = in_request(
+            incoming(sealed.clone()),
+            CookieStore::new(rotated).load(&record.id),
+        )
+        .await;
+        assert_eq!(Some(record.clone()), loaded.unwrap());
+
+        let (loaded, _) = in_request(
+            incoming(sealed),
+            CookieStore::new(Keyring::generate()).load(&record.id),
+        )
+        .await;
+        assert_eq!(None, loaded.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let store = CookieStore::new(Keyring::generate());
+        let record = record();
+        let sealed = seal_record(&store, &record).await;
+
+        let (_, data) = in_request(incoming(sealed), async {
+            store.delete(&record.id).await.unwrap();
+            assert_eq!(None, store.load(&record.id).await.unwrap());
+        })
+        .await;
+        assert_eq!(data.outgoing.get(&record.id), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn test_records_keep_cookies_of_their_own() {
+        let store = CookieStore::new(Keyring::generate());
+        let session = record();
+        let sealed = seal_record(&store, &session).await;
+
+        // e.g. a pending login saved next to the session
+        let mut pending = record();
+        let (_, data) = in_request(incoming(sealed.clone()), async {
+            store.create(&mut pending).await.unwrap();
+            assert!(store.load(&session.id).await.unwrap().is_some());
+        })
+        .await;
+        assert_eq!(data.slots[&session.id], 0);
+        assert_eq!(data.slots[&pending.id], 1);
+
+        let both = SessionData {
+            incoming: HashMap::from([
+                (0, (sealed, 1)),
+                (1, (data.outgoing[&pending.id].clone().unwrap(), 1)),
+            ]),
+            ..Default::default()
+        };
+        let (loaded, data) = in_request(both, async {
+            store.delete(&pending.id).await.unwrap();
+            (
+                store.load(&session.id).await.unwrap(),
+                store.load(&pending.id).await.unwrap(),
+            )
+        })
+        .await;
+        assert_eq!(loaded, (Some(session), None));
+        assert_eq!(data.outgoing.len(), 1);
+        assert_eq!(data.slots[&pending.id], 1);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_records_give_up_their_slot() {
+        let store = CookieStore::new(Keyring::generate());
+        let session = record();
+        let sealed = seal_record(&store, &session).await;
+
+        let mut cycled = record();
+        let (_, data) = in_request(incoming(sealed), async {
+            store.delete(&session.id).await.unwrap();
+            store.create(&mut cycled).await.unwrap();
+        })
+        .await;
+
+        assert_eq!(data.slots.get(&cycled.id), Some(&0));
+        assert!(!data.outgoing.contains_key(&session.id));
+    }
+
+    #[tokio::test]
+    async fn test_save_rejects_sessions_too_large_for_the_cookies() {
+        let store = CookieStore::new(Keyring::generate()).with_max_chunks(2);
+        let mut record = record();
+        record
+            .data
+            .insert("blob".to_string(), "x".repeat(3 * CHUNK_SIZE).into());
+
+        let (saved, data) = in_request(SessionData::default(), store.save(&record)).await;
+        assert!(matches!(saved, Err(session_store::Error::Backend(_))));
+        assert!(data.outgoing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_records_share_the_cookie_budget() {
+        let store = CookieStore::new(Keyring::generate());
+        let sealed = seal_record(&store, &record()).await;
+        let session_taking = |chunks: usize| SessionData {
+            incoming: HashMap::from([(0, (sealed.clone(), chunks))]),
+            ..Default::default()
+        };
+
+        let mut pending = record();
+        let (created, _) = in_request(session_taking(3), store.create(&mut pending)).await;
+        assert!(created.is_ok());
+
+        let mut pending = record();
+        let (created, data) = in_request(session_taking(4), store.create(&mut pending)).await;
+        assert!(matches!(created, Err(session_store::Error::Backend(_))));
+        assert!(data.outgoing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_needs_the_middleware() {
+        let store = CookieStore::new(Keyring::generate());
+        assert!(store.save(&record()).await.is_err());
+    }
+
+    #[test]
+    fn test_large_records_are_chunked() {
+        let sealed = "a".repeat(CHUNK_SIZE * 2 + 10);
+        let chunks = split(&sealed);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(),
+            vec![CHUNK_SIZE, CHUNK_SIZE, 10]
+        );
+
+        let cookies = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| (chunk_name(1, index), chunk.to_string()))
+            .collect();
+        assert_eq!(join(&cookies, 1), Some((sealed, 3)));
+        assert_eq!(join(&cookies, 0), None);
+        assert_eq!(chunk_name(0, 2), "session_data.2");
+        assert_eq!(chunk_name(1, 2), "session_data_1.2");
+    }
+
+    #[test]
+    fn test_data_cookies_are_recognized() {
+        for name in [
+            "session_data",
+            "session_data.2",
+            "session_data_1",
+            "session_data_1.2",
+        ] {
+            assert!(is_data_cookie(name), "{}", name);
+        }
+        for name in [
+            "session",
+            "session_database",
+            "session_data_x",
+            "session_data.x",
+        ] {
+            assert!(!is_data_cookie(name), "{}", name);
+        }
+    }
+}
@@ -1,2 +1,4 @@
+pub mod backend;
 pub mod cloudflare;
+pub mod cookie;
 pub mod in_memory;
\ No newline at end of file
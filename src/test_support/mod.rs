@@ -0,0 +1,7 @@
+//! Test helpers: an in-process OIDC provider so the auth flow can be tested without a network.
+
+mod oidc_provider;
+mod router_transport;
+
+pub(crate) use oidc_provider::{MockOidcProvider, MockUser};
+pub(crate) use router_transport::RouterTransport;
@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::Router;
+use http_body_util::BodyExt;
+use tower::ServiceExt;
+
+use crate::http_client::{HttpTransport, TransportError, TransportRequest, TransportResponse};
+
+/// Serves requests from an axum router in-process instead of sending them over the network.
+#[derive(Clone, Debug)]
+pub(crate) struct RouterTransport {
+    router: Router,
+}
+
+impl RouterTransport {
+    pub(crate) fn new(router: Router) -> Self {
+        Self { router }
+    }
+}
+
+#[async_trait]
+impl HttpTransport for RouterTransport {
+    async fn send(&self, request: TransportRequest) -> Result<TransportResponse, TransportError> {
+        let response = self
+            .router
+            .clone()
+            .oneshot(request.map(Body::from))
+            .await
+            .map_err(TransportError::failed)?;
+
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(TransportError::failed)?
+            .to_bytes();
+
+        Ok(http::Response::from_parts(parts, body.to_vec()))
+    }
+}
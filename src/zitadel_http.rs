@@ -1,3 +1,5 @@
+use crate::http_client::HttpClient;
+
 #[derive(Debug, serde::Deserialize)]
 pub struct OidcMetadata {
     pub issuer: String,
@@ -30,10 +32,21 @@ pub struct OidcMetadata {
 }
 
 pub async fn fetch_oidc_metadata(issuer_url: &str) -> OidcMetadata {
+    fetch_oidc_metadata_with(&HttpClient::default(), issuer_url).await
+}
+
+pub async fn fetch_oidc_metadata_with(http_client: &HttpClient, issuer_url: &str) -> OidcMetadata {
     let issuer_url = issuer_url.trim_end_matches('/');
     let metadata_url = format!("{}/.well-known/openid-configuration", issuer_url);
 
-    let response = reqwest::get(&metadata_url).await.expect("Failed to fetch metadata");
+    let request = http::Request::get(metadata_url)
+        .header(http::header::ACCEPT, "application/json")
+        .body(vec![])
+        .expect("Failed to build metadata request");
+    let response = http_client
+        .execute_request(request)
+        .await
+        .expect("Failed to fetch metadata");
 
-    response.json::<OidcMetadata>().await.expect("Failed to parse metadata")
+    serde_json::from_slice::<OidcMetadata>(response.body()).expect("Failed to parse metadata")
 }